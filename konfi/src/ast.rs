@@ -1,3 +1,5 @@
+use std::rc::Rc;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum UnOp {
     UnPlus,  // +
@@ -21,65 +23,169 @@ pub enum BinOp {
     NotEq,       // !=
     LogicalAnd,  // &&
     LogicalOr,   // ||
+    Coalesce,    // ??
 }
 
-#[derive(Debug, PartialEq)]
+impl BinOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinOp::Times => "*",
+            BinOp::Div => "/",
+            BinOp::Plus => "+",
+            BinOp::Minus => "-",
+            BinOp::ShiftLeft => "<<",
+            BinOp::ShiftRight => ">>",
+            BinOp::LessThan => "<",
+            BinOp::GreaterThan => ">",
+            BinOp::LessEq => "<=",
+            BinOp::GreaterEq => ">=",
+            BinOp::Eq => "==",
+            BinOp::NotEq => "!=",
+            BinOp::LogicalAnd => "&&",
+            BinOp::LogicalOr => "||",
+            BinOp::Coalesce => "??",
+        }
+    }
+}
+
+/// Location of a syntax element in its source text.
+///
+/// The parser only ever sees the remaining suffix of its input, so it records
+/// the number of bytes that were left when the element started. Use
+/// `Pos::line_col` with the full source text to resolve it.
+///
+/// Positions never take part in structural equality of AST nodes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pos {
+    pub rem: usize,
+}
+
+impl Pos {
+    pub fn at(rest: &str) -> Self {
+        Pos { rem: rest.len() }
+    }
+
+    /// Byte offset of this position in `src`.
+    pub fn offset(&self, src: &str) -> usize {
+        src.len().saturating_sub(self.rem)
+    }
+
+    /// 1-based line and column of this position in `src`.
+    pub fn line_col(&self, src: &str) -> (usize, usize) {
+        let before = &src[..self.offset(src)];
+        let line = before.matches('\n').count() + 1;
+        let col = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+        (line, col)
+    }
+}
+
+impl PartialEq for Pos {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Pos {}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Literal {
     Nil,
     Int(i64),
     Double(f64),
     Str(String),
+    Size(u64),     // In bytes.
+    Duration(i64), // In nanoseconds.
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Var {
     pub name: String,
+    pub pos: Pos,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Literal(Literal),
     Var(Var),
     FieldAcc(Box<Expr>, String),
+    OptFieldAcc(Box<Expr>, String), // e?.f
+    Index(Box<Expr>, Box<Expr>),    // r["key"] or l[0]
     UnExpr(UnOp, Box<Expr>),
     BinExpr(Box<Expr>, BinOp, Box<Expr>),
     Rec(Rec),
+    List(Vec<Expr>),
     Call(Call),
     Fun(Fun),
+    ListComp(Box<Expr>, Comp),           // [e for x in xs]
+    RecComp(Box<Expr>, Box<Expr>, Comp), // {[k]: v for k, v in r} or {"${k}": v for k, v in r}
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Fun {
-    params: Vec<Var>,
-    body: Box<Expr>,
+    pub params: Vec<Var>,
+    // Shared with the functions that the lambda evaluates to.
+    pub body: Rc<Expr>,
 }
 
-#[derive(Debug, PartialEq)]
+// The "for vars in iter if cond" part of a comprehension. `vars` has one or
+// two elements: an element (or key), or an index (or key) and element.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comp {
+    pub vars: Vec<Var>,
+    pub iter: Box<Expr>,
+    pub cond: Option<Box<Expr>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Call {
-    fun: Box<Expr>,
-    args: Vec<Box<Expr>>,
+    pub fun: Box<Expr>,
+    pub args: Vec<Expr>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Rec {
     pub let_vars: Vec<LetBinding>,
     pub fields: Vec<Field>,
+    pub asserts: Vec<Assert>,
 }
 
-#[derive(Debug, PartialEq)]
+// assert cond : message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assert {
+    pub cond: Box<Expr>,
+    pub message: Option<Box<Expr>>,
+    pub pos: Pos,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Field {
     pub name: String,
     pub value: Box<Expr>,
+    // Hidden fields (name:: value) can be referenced, but are not part of the output.
+    // Records cannot be extended, so the declaration alone decides the
+    // visibility. Of several declarations of a field, the first one wins.
+    pub hidden: bool,
+    pub pos: Pos,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct LetBinding {
     pub var: Var,
     pub value: Box<Expr>,
 }
 
+// import "path" as var
+#[derive(Debug, PartialEq)]
+pub struct Import {
+    pub path: String,
+    pub var: Var,
+    pub pos: Pos,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Module {
+    pub imports: Vec<Import>,
     pub let_vars: Vec<LetBinding>,
+    pub asserts: Vec<Assert>,
     pub expr: Box<Expr>,
 }
@@ -0,0 +1,277 @@
+// Native functions and modules that are available in every evaluation.
+
+use crate::engine::{Limits, Settings};
+use crate::eval::{Ctx, EvalError, EvalResult, NativeFn, Rec, Val};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+mod durationlib;
+mod listlib;
+mod netlib;
+mod sizelib;
+mod strlib;
+
+pub type Builtin = fn(&[Val], &Limits) -> EvalResult<Val>;
+
+// All builtin modules and their functions, by name.
+pub const MODULES: &[(&str, &[(&str, Builtin)])] = &[
+    ("str", strlib::FUNCTIONS),
+    ("net", netlib::FUNCTIONS),
+    ("size", sizelib::FUNCTIONS),
+    ("duration", durationlib::FUNCTIONS),
+    ("list", listlib::FUNCTIONS),
+];
+
+// Builtin functions that are not part of a module.
+pub const FUNCTIONS: &[(&str, Builtin)] = &[("range", listlib::range)];
+
+// Methods by receiver type (see Val::typ). `v.f(args)` calls the builtin `f`
+// with `v` as its first argument, e.g. `host.endswith(".com")`.
+pub const METHODS: &[(&str, &[(&str, Builtin)])] = &[
+    ("str", strlib::METHODS),
+    ("list", listlib::METHODS),
+    ("size", sizelib::METHODS),
+    ("duration", durationlib::METHODS),
+];
+
+// The method `name` of `v`, as a function with `v` bound to its first argument.
+pub fn method(v: &Val, name: &str) -> Option<Val> {
+    let (_, fns) = METHODS.iter().find(|(t, _)| *t == v.typ())?;
+    let (_, f) = fns.iter().find(|(n, _)| *n == name)?;
+    let (recv, f) = (v.clone(), *f);
+    let bound = move |vals: &[Val], limits: &Limits| {
+        let mut args = Vec::with_capacity(vals.len() + 1);
+        args.push(recv.clone());
+        args.extend_from_slice(vals);
+        f(&args, limits)
+    };
+    Some(Val::NativeFn(NativeFn::with_limits(
+        &format!("{}.{name}", v.typ()),
+        bound,
+    )))
+}
+
+// The names of all methods of type `typ`, sorted.
+pub fn method_names(typ: &str) -> Vec<&'static str> {
+    let mut names: Vec<&str> = METHODS
+        .iter()
+        .filter(|(t, _)| *t == typ)
+        .flat_map(|(_, fns)| fns.iter().map(|(n, _)| *n))
+        .collect();
+    names.sort();
+    names
+}
+
+// Registers all builtin modules in the (global) context `ctx`.
+pub fn register(ctx: &Ctx) {
+    for (name, fns) in MODULES.iter() {
+        ctx.setvar(name, module(name, fns));
+    }
+    for (name, f) in FUNCTIONS.iter() {
+        ctx.setvar(name, Val::NativeFn(NativeFn::with_limits(name, *f)));
+    }
+}
+
+// Registers ext() and env(), which read the external variables and
+// environment variables that the host allows.
+pub(crate) fn register_externals(ctx: &Ctx, settings: &Rc<Settings>) {
+    let s = Rc::clone(settings);
+    let ext = move |vals: &[Val], limits: &Limits| {
+        let args = Args::new("ext", vals, limits, 1, 2)?;
+        let name = args.str(0)?;
+        match (s.externals.get(name), args.get(1)) {
+            (Some(v), _) | (None, Some(v)) => Ok(v.clone()),
+            (None, None) => args.error(format!("external variable \"{name}\" is not set")),
+        }
+    };
+    ctx.setvar("ext", Val::NativeFn(NativeFn::with_limits("ext", ext)));
+    let s = Rc::clone(settings);
+    let env = move |vals: &[Val], limits: &Limits| {
+        let args = Args::new("env", vals, limits, 1, 2)?;
+        let name = args.str(0)?;
+        if !s.env_vars.contains(name) {
+            return args.error(format!(
+                "access to environment variable \"{name}\" is not allowed"
+            ));
+        }
+        match (std::env::var(name), args.get(1)) {
+            (Ok(v), _) => Ok(Val::Str(v)),
+            (Err(_), Some(v)) => Ok(v.clone()),
+            (Err(_), None) => args.error(format!("environment variable \"{name}\" is not set")),
+        }
+    };
+    ctx.setvar("env", Val::NativeFn(NativeFn::with_limits("env", env)));
+}
+
+// Builds a module, i.e. a record of native functions.
+pub fn module(name: &str, fns: &[(&str, Builtin)]) -> Val {
+    let mut r = Rec::new();
+    for (f, b) in fns.iter() {
+        r.setattr(
+            f,
+            Val::NativeFn(NativeFn::with_limits(&format!("{name}.{f}"), *b)),
+        );
+    }
+    Val::Rec(Rc::new(RefCell::new(r)))
+}
+
+/// Type-checked access to the arguments of a native function, and to the
+/// limits that its result must keep.
+pub struct Args<'v> {
+    name: &'v str,
+    vals: &'v [Val],
+    limits: &'v Limits,
+}
+
+impl<'v> Args<'v> {
+    // Wraps `vals`, which must have between `min` and `max` elements.
+    pub fn new(
+        name: &'v str,
+        vals: &'v [Val],
+        limits: &'v Limits,
+        min: usize,
+        max: usize,
+    ) -> EvalResult<Self> {
+        let n = vals.len();
+        if n < min || n > max {
+            let expected = if min == max {
+                format!("{min}")
+            } else if max == usize::MAX {
+                format!("at least {min}")
+            } else {
+                format!("{min} to {max}")
+            };
+            return Err(EvalError {
+                message: format!("{name}: expected {expected} argument(s), got {n}"),
+                pos: None,
+                failed_asserts: 0,
+            });
+        }
+        Ok(Args { name, vals, limits })
+    }
+
+    pub fn len(&self) -> usize {
+        self.vals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vals.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> Option<&'v Val> {
+        self.vals.get(i)
+    }
+
+    // Calls the function `f` with the limits of this call.
+    pub fn call(&self, f: &NativeFn, args: &[Val]) -> EvalResult<Val> {
+        f.call(args, self.limits)
+    }
+
+    // Checks the length of a string of `n` bytes that the function is about
+    // to build, so that it fails before allocating too much memory.
+    pub fn check_str_len(&self, n: usize) -> EvalResult<()> {
+        self.limits.check_str_len(n)
+    }
+
+    // Like `check_str_len`, for a list of `n` elements.
+    pub fn check_list_len(&self, n: usize) -> EvalResult<()> {
+        self.limits.check_list_len(n)
+    }
+
+    // An empty list with room for the `n` elements that the function is
+    // about to add. Fails, rather than aborts, if `n` exceeds the maximum
+    // size or the memory cannot be allocated.
+    pub fn new_list(&self, n: u128) -> EvalResult<Vec<Val>> {
+        let n = usize::try_from(n).unwrap_or(usize::MAX);
+        self.check_list_len(n)?;
+        let mut l = Vec::new();
+        match l.try_reserve_exact(n) {
+            Ok(()) => Ok(l),
+            Err(_) => self.error(format!("cannot allocate a list of {n} elements")),
+        }
+    }
+
+    // An error about this function call.
+    pub fn error<T>(&self, message: String) -> EvalResult<T> {
+        Err(EvalError {
+            message: format!("{}: {}", self.name, message),
+            pos: None,
+            failed_asserts: 0,
+        })
+    }
+
+    fn type_error<T>(&self, i: usize, expected: &str) -> EvalResult<T> {
+        self.error(format!(
+            "argument {} must be {}, got {}",
+            i + 1,
+            expected,
+            self.vals[i].typ()
+        ))
+    }
+
+    pub fn str(&self, i: usize) -> EvalResult<&'v str> {
+        match &self.vals[i] {
+            Val::Str(s) => Ok(s),
+            _ => self.type_error(i, "str"),
+        }
+    }
+
+    pub fn int(&self, i: usize) -> EvalResult<i64> {
+        match &self.vals[i] {
+            Val::Int(n) => Ok(*n),
+            _ => self.type_error(i, "int"),
+        }
+    }
+
+    pub fn fun(&self, i: usize) -> EvalResult<&'v NativeFn> {
+        match &self.vals[i] {
+            Val::NativeFn(f) => Ok(f),
+            _ => self.type_error(i, "fn"),
+        }
+    }
+
+    pub fn list(&self, i: usize) -> EvalResult<&'v [Val]> {
+        match &self.vals[i] {
+            Val::List(l) => Ok(l),
+            _ => self.type_error(i, "list"),
+        }
+    }
+
+    // Like `int`, but the value must not be negative.
+    pub fn index(&self, i: usize) -> EvalResult<usize> {
+        match self.int(i)? {
+            n if n >= 0 => Ok(n as usize),
+            n => self.error(format!("argument {} must not be negative, got {n}", i + 1)),
+        }
+    }
+}
+
+// Test fixtures shared by the tests of all builtin modules.
+#[cfg(test)]
+mod h {
+    use crate::eval::{eval, Ctx, EvalError, EvalResult, Val};
+    use crate::parser;
+
+    // Evaluates the expression `s` in the global context.
+    pub fn e(s: &str) -> EvalResult<Val> {
+        let expr = parser::expr_opt(s).unwrap_or_else(|| panic!("Cannot parse: {}", s));
+        eval(&expr, Ctx::global())
+    }
+
+    // Like `e`, but displays the value, which must exist.
+    pub fn d(s: &str) -> String {
+        e(s).unwrap().to_string()
+    }
+
+    pub fn s(s: &str) -> EvalResult<Val> {
+        Ok(Val::Str(s.to_string()))
+    }
+
+    pub fn err(message: &str) -> EvalResult<Val> {
+        Err(EvalError {
+            message: message.to_string(),
+            pos: None,
+            failed_asserts: 0,
+        })
+    }
+}
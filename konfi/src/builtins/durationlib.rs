@@ -0,0 +1,82 @@
+// The `duration` module: converting and parsing durations.
+
+use super::{Args, Builtin};
+use crate::engine::Limits;
+use crate::eval::{nanos, EvalResult, Val};
+use crate::units;
+use chrono::Duration;
+
+pub const FUNCTIONS: &[(&str, Builtin)] = &[
+    ("as_nanos", as_nanos),
+    ("as_millis", as_millis),
+    ("as_seconds", as_seconds),
+    ("as_minutes", as_minutes),
+    ("as_hours", as_hours),
+    ("parse", parse),
+];
+
+// All functions that take a duration as their first argument.
+pub const METHODS: &[(&str, Builtin)] = &[
+    ("as_nanos", as_nanos),
+    ("as_millis", as_millis),
+    ("as_seconds", as_seconds),
+    ("as_minutes", as_minutes),
+    ("as_hours", as_hours),
+];
+
+// The duration argument in units of `unit` nanoseconds, rounded towards zero.
+fn as_unit(name: &str, vals: &[Val], limits: &Limits, unit: i64) -> EvalResult<Val> {
+    let a = Args::new(name, vals, limits, 1, 1)?;
+    match a.get(0) {
+        Some(Val::Duration(d)) => Ok(Val::Int(nanos(d) / unit)),
+        Some(v) => a.error(format!("argument 1 must be duration, got {}", v.typ())),
+        None => a.error("missing argument 1".to_string()),
+    }
+}
+
+fn as_nanos(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    as_unit("duration.as_nanos", vals, limits, 1)
+}
+
+fn as_millis(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    as_unit("duration.as_millis", vals, limits, 1_000_000)
+}
+
+fn as_seconds(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    as_unit("duration.as_seconds", vals, limits, 1_000_000_000)
+}
+
+fn as_minutes(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    as_unit("duration.as_minutes", vals, limits, 60 * 1_000_000_000)
+}
+
+fn as_hours(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    as_unit("duration.as_hours", vals, limits, 3600 * 1_000_000_000)
+}
+
+// parse(s): parses a duration like "1h30m" or "250ms".
+fn parse(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("duration.parse", vals, limits, 1, 1)?;
+    match units::parse_duration(a.str(0)?) {
+        Ok(d) => Ok(Val::Duration(Duration::nanoseconds(d))),
+        Err(e) => a.error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builtins::h::{e, err};
+    use crate::eval::Val;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(e("duration.as_millis(1m30s)"), Ok(Val::Int(90_000)));
+        assert_eq!(e("duration.as_seconds(1999ms)"), Ok(Val::Int(1)));
+        assert_eq!(e("duration.as_hours(-(90m))"), Ok(Val::Int(-1)));
+        assert_eq!(
+            e("duration.as_nanos(1)"),
+            err("duration.as_nanos: argument 1 must be duration, got int")
+        );
+        assert_eq!(e(r#"duration.parse("1h30m") == 90m"#), Ok(Val::Bool(true)));
+    }
+}
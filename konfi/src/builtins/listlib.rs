@@ -0,0 +1,262 @@
+// The `list` module: generating, transforming and sorting lists.
+
+use super::{Args, Builtin};
+use crate::engine::Limits;
+use crate::eval::{compare, equal, EvalResult, Val};
+use std::cmp::Ordering;
+
+pub const FUNCTIONS: &[(&str, Builtin)] = &[
+    ("len", len),
+    ("map", map),
+    ("filter", filter),
+    ("fold", fold),
+    ("sort", sort),
+    ("unique", unique),
+    ("flatten", flatten),
+];
+
+// All functions that take a list as their first argument.
+pub const METHODS: &[(&str, Builtin)] = &[
+    ("len", len),
+    ("map", map),
+    ("filter", filter),
+    ("fold", fold),
+    ("sort", sort),
+    ("unique", unique),
+    ("flatten", flatten),
+    ("join", super::strlib::join),
+];
+
+// range([start, ]end[, step]): the ints from start (default 0) up to, but
+// excluding, end.
+pub fn range(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("range", vals, limits, 1, 3)?;
+    let (start, end) = match a.len() {
+        1 => (0, a.int(0)?),
+        _ => (a.int(0)?, a.int(1)?),
+    };
+    let step = if a.len() == 3 { a.int(2)? } else { 1 };
+    if step == 0 {
+        return a.error("step must not be 0".to_string());
+    }
+    let n = if (step > 0 && start < end) || (step < 0 && start > end) {
+        (end.abs_diff(start) - 1) / step.unsigned_abs() + 1
+    } else {
+        0
+    };
+    let mut elems = a.new_list(n.into())?;
+    elems.extend((0..n as i64).map(|i| Val::Int(start + i * step)));
+    Ok(Val::List(elems.into()))
+}
+
+// len(l): the number of elements of l.
+fn len(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("list.len", vals, limits, 1, 1)?;
+    Ok(Val::Int(a.list(0)?.len() as i64))
+}
+
+// map(l, f): the results of f(x) for each element x of l.
+fn map(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("list.map", vals, limits, 2, 2)?;
+    let f = a.fun(1)?;
+    let mut res = Vec::new();
+    for v in a.list(0)?.iter() {
+        res.push(a.call(f, std::slice::from_ref(v))?);
+    }
+    Ok(Val::List(res.into()))
+}
+
+// filter(l, f): the elements x of l for which f(x) is true.
+fn filter(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("list.filter", vals, limits, 2, 2)?;
+    let f = a.fun(1)?;
+    let mut res = Vec::new();
+    for v in a.list(0)?.iter() {
+        if a.call(f, std::slice::from_ref(v))?.to_bool() {
+            res.push(v.clone());
+        }
+    }
+    Ok(Val::List(res.into()))
+}
+
+// fold(l, init, f): combines all elements from left to right, starting with
+// init, as in f(f(init, l[0]), l[1]).
+fn fold(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("list.fold", vals, limits, 3, 3)?;
+    let f = a.fun(2)?;
+    let mut acc = vals[1].clone();
+    for v in a.list(0)?.iter() {
+        acc = a.call(f, &[acc, v.clone()])?;
+    }
+    Ok(acc)
+}
+
+// sort(l[, key]): the elements of l in ascending order, or in the order of
+// key(x) if a key function is given. The sort is stable.
+fn sort(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("list.sort", vals, limits, 1, 2)?;
+    let l = a.list(0)?;
+    let keys = match a.len() {
+        1 => l.to_vec(),
+        _ => {
+            let f = a.fun(1)?;
+            let mut keys = Vec::with_capacity(l.len());
+            for v in l.iter() {
+                keys.push(a.call(f, std::slice::from_ref(v))?);
+            }
+            keys
+        }
+    };
+    // sort_by needs a total order, so check that all keys can be compared
+    // with each other before sorting.
+    if let Err((x, y)) = comparable(&keys.iter().collect::<Vec<_>>()) {
+        return a.error(format!("cannot compare {x} and {y}"));
+    }
+    let mut order: Vec<usize> = (0..l.len()).collect();
+    order.sort_by(|&i, &j| compare(&keys[i], &keys[j]).unwrap_or(Ordering::Equal));
+    Ok(Val::List(order.into_iter().map(|i| l[i].clone()).collect()))
+}
+
+// Checks that `compare` orders every pair of `vals`. Otherwise, returns the
+// types of a pair that it does not order. Lists are compared element by
+// element, so the elements at each index must be comparable in turn.
+fn comparable<'a>(vals: &[&'a Val]) -> Result<(), (&'a str, &'a str)> {
+    let Some(first) = vals.first() else {
+        return Ok(());
+    };
+    let number = |v: &Val| matches!(v, Val::Int(_) | Val::Double(_));
+    for v in vals.iter() {
+        let same = match (first, v) {
+            (Val::Double(d), _) | (_, Val::Double(d)) if d.is_nan() => false,
+            (x, y) if number(x) && number(y) => true,
+            (Val::Str(_), Val::Str(_))
+            | (Val::Bool(_), Val::Bool(_))
+            | (Val::Size(_), Val::Size(_))
+            | (Val::Duration(_), Val::Duration(_))
+            | (Val::Timestamp(_), Val::Timestamp(_))
+            | (Val::List(_), Val::List(_)) => true,
+            _ => false,
+        };
+        if !same {
+            return Err((first.typ(), v.typ()));
+        }
+    }
+    let lists: Vec<&[Val]> = vals
+        .iter()
+        .filter_map(|v| match v {
+            Val::List(l) => Some(&l[..]),
+            _ => None,
+        })
+        .collect();
+    let len = lists.iter().map(|l| l.len()).max().unwrap_or(0);
+    for i in 0..len {
+        comparable(&lists.iter().filter_map(|l| l.get(i)).collect::<Vec<_>>())?;
+    }
+    Ok(())
+}
+
+// unique(l): the elements of l without duplicates, in the order of their
+// first occurrence.
+fn unique(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("list.unique", vals, limits, 1, 1)?;
+    let mut res: Vec<Val> = Vec::new();
+    for v in a.list(0)?.iter() {
+        if !res.iter().any(|r| equal(r, v)) {
+            res.push(v.clone());
+        }
+    }
+    Ok(Val::List(res.into()))
+}
+
+// flatten(l): the elements of l, with elements that are lists replaced by
+// their elements. Only flattens one level.
+fn flatten(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("list.flatten", vals, limits, 1, 1)?;
+    let l = a.list(0)?;
+    let n = l.iter().fold(0u128, |n, v| match v {
+        Val::List(l) => n + l.len() as u128,
+        _ => n + 1,
+    });
+    let mut res = a.new_list(n)?;
+    for v in l.iter() {
+        match v {
+            Val::List(l) => res.extend(l.iter().cloned()),
+            _ => res.push(v.clone()),
+        }
+    }
+    Ok(Val::List(res.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builtins::h::{d, e, err};
+
+    #[test]
+    fn ranges() {
+        assert_eq!(d("range(3)"), "[0, 1, 2]");
+        assert_eq!(d("range(2, 5)"), "[2, 3, 4]");
+        assert_eq!(d("range(5, 0, -2)"), "[5, 3, 1]");
+        assert_eq!(d("range(0, 7, 3)"), "[0, 3, 6]");
+        assert_eq!(d("range(3, 3)"), "[]");
+        assert_eq!(e("range(0, 1, 0)"), err("range: step must not be 0"));
+        assert_eq!(
+            e("range(0, 1 << 62)"),
+            err("range: cannot allocate a list of 4611686018427387904 elements")
+        );
+    }
+
+    #[test]
+    fn higher_order() {
+        assert_eq!(d("list.map([1, 2], x => x * 10)"), "[10, 20]");
+        assert_eq!(d("[1, 2, 3, 4].filter(x => x > 2)"), "[3, 4]");
+        assert_eq!(d("range(5).fold(0, (acc, x) => acc + x)"), "10");
+        assert_eq!(
+            e("list.map([1], 1)"),
+            err("list.map: argument 2 must be fn, got int")
+        );
+        assert_eq!(
+            e("list.map([1], (a, b) => a)"),
+            err("lambda(a, b): expected 2 argument(s), got 1")
+        );
+    }
+
+    #[test]
+    fn sort_unique_flatten() {
+        assert_eq!(d("[3, 1, 2].sort()"), "[1, 2, 3]");
+        assert_eq!(
+            d(r#"["bb", "a", "ccc"].sort(s => s.len())"#),
+            r#"["a", "bb", "ccc"]"#
+        );
+        assert_eq!(d("[2s, 1m, 1ms].sort()"), "[1ms, 2s, 1m]");
+        assert_eq!(
+            e(r#"[1, "a"].sort()"#),
+            err("list.sort: cannot compare int and str")
+        );
+        // The keys are checked before sorting, also in long lists, where
+        // sorting with an inconsistent order would fail.
+        let mixed = "[[i - (i/7)*7, \"a\", i - (i/3)*3, 5 - i][i - (i/4)*4] for i in range(50)]";
+        assert_eq!(
+            e(&format!("{mixed}.sort()")),
+            err("list.sort: cannot compare int and str")
+        );
+        assert_eq!(
+            e("[[1, 2], [1, \"a\"], [0]].sort()"),
+            err("list.sort: cannot compare int and str")
+        );
+        assert_eq!(
+            e("[{a: 1}, {a: 2}].sort()"),
+            err("list.sort: cannot compare rec and rec")
+        );
+        assert_eq!(d("range(60).map(i => 59 - i).sort() == range(60)"), "true");
+        assert_eq!(d("[].sort()"), "[]");
+        assert_eq!(d("[[2, 1], [1, 3], [1]].sort()"), "[[1], [1, 3], [2, 1]]");
+        assert_eq!(d("[1, 2, 1, {a: 1}, {a: 1}].unique()"), "[1, 2, {a: 1}]");
+        assert_eq!(
+            d("[[1], {a: [1]}, [1], {a: [1], h:: 2}].unique()"),
+            "[[1], {a: [1]}]"
+        );
+        assert_eq!(d("[[1, 2], 3, [[4]]].flatten()"), "[1, 2, 3, [4]]");
+        assert_eq!(d(r#"["a", "b"].join("-")"#), r#""a-b""#);
+        assert_eq!(d("[1, 2].len()"), "2");
+    }
+}
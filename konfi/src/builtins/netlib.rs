@@ -0,0 +1,488 @@
+// The `net` module: URLs, IP addresses and CIDR math.
+
+use super::{Args, Builtin};
+use crate::engine::Limits;
+use crate::eval::{EvalResult, Rec, Val};
+use std::cell::RefCell;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::rc::Rc;
+
+pub const FUNCTIONS: &[(&str, Builtin)] = &[
+    ("parse_url", parse_url),
+    ("scheme", scheme),
+    ("host", host),
+    ("port", port),
+    ("path", path),
+    ("query", query),
+    ("is_ip", is_ip),
+    ("is_ipv4", is_ipv4),
+    ("is_ipv6", is_ipv6),
+    ("network", network),
+    ("broadcast", broadcast),
+    ("netmask", netmask),
+    ("prefix_len", prefix_len),
+    ("contains", contains),
+    ("nth_host", nth_host),
+    ("subnet", subnet),
+    ("subnets", subnets),
+];
+
+// The components of a URL of the form
+// scheme://[userinfo@]host[:port][/path][?query][#fragment].
+#[derive(Debug, PartialEq)]
+struct Url<'a> {
+    scheme: &'a str,
+    host: &'a str,
+    port: Option<u16>,
+    path: &'a str,
+    query: &'a str,
+}
+
+fn split_url(url: &str) -> Result<Url<'_>, String> {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return Err(format!("missing scheme in URL \"{url}\""));
+    };
+    if scheme.is_empty()
+        || !scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    {
+        return Err(format!("invalid scheme in URL \"{url}\""));
+    }
+    let rest = rest.split_once('#').map_or(rest, |(r, _)| r);
+    let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, a)| a);
+    let (host, port) = if let Some(a) = authority.strip_prefix('[') {
+        // IPv6 address literal, e.g. [::1]:8080
+        let Some((h, p)) = a.split_once(']') else {
+            return Err(format!("unterminated '[' in URL \"{url}\""));
+        };
+        if h.parse::<Ipv6Addr>().is_err() {
+            return Err(format!("invalid IPv6 address \"{h}\" in URL \"{url}\""));
+        }
+        match p {
+            "" => (h, None),
+            _ => match p.strip_prefix(':') {
+                Some(p) => (h, Some(p)),
+                None => return Err(format!("invalid authority in URL \"{url}\"")),
+            },
+        }
+    } else {
+        match authority.split_once(':') {
+            Some((h, p)) => (h, Some(p)),
+            None => (authority, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(format!("missing host in URL \"{url}\""));
+    }
+    let port = match port {
+        None => None,
+        Some(p) => match p.parse::<u16>() {
+            Ok(p) => Some(p),
+            Err(_) => return Err(format!("invalid port \"{p}\" in URL \"{url}\"")),
+        },
+    };
+    Ok(Url {
+        scheme,
+        host,
+        port,
+        path,
+        query,
+    })
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        "ftp" => Some(21),
+        "ssh" => Some(22),
+        _ => None,
+    }
+}
+
+fn url_arg<'v>(a: &Args<'v>, i: usize) -> EvalResult<Url<'v>> {
+    split_url(a.str(i)?).or_else(|e| a.error(e))
+}
+
+fn url_port(u: &Url) -> Val {
+    match u.port.or_else(|| default_port(u.scheme)) {
+        Some(p) => Val::Int(p as i64),
+        None => Val::Nil,
+    }
+}
+
+// parse_url(url): a record with fields scheme, host, port, path and query.
+// If the URL has no explicit port, port is the scheme's default port, or nil.
+fn parse_url(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.parse_url", vals, limits, 1, 1)?;
+    let u = url_arg(&a, 0)?;
+    let mut r = Rec::new();
+    r.setattr("scheme", Val::Str(u.scheme.to_string()));
+    r.setattr("host", Val::Str(u.host.to_string()));
+    r.setattr("port", url_port(&u));
+    r.setattr("path", Val::Str(u.path.to_string()));
+    r.setattr("query", Val::Str(u.query.to_string()));
+    Ok(Val::Rec(Rc::new(RefCell::new(r))))
+}
+
+fn scheme(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.scheme", vals, limits, 1, 1)?;
+    Ok(Val::Str(url_arg(&a, 0)?.scheme.to_string()))
+}
+
+fn host(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.host", vals, limits, 1, 1)?;
+    Ok(Val::Str(url_arg(&a, 0)?.host.to_string()))
+}
+
+fn port(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.port", vals, limits, 1, 1)?;
+    Ok(url_port(&url_arg(&a, 0)?))
+}
+
+fn path(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.path", vals, limits, 1, 1)?;
+    Ok(Val::Str(url_arg(&a, 0)?.path.to_string()))
+}
+
+fn query(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.query", vals, limits, 1, 1)?;
+    Ok(Val::Str(url_arg(&a, 0)?.query.to_string()))
+}
+
+fn is_ip(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.is_ip", vals, limits, 1, 1)?;
+    Ok(Val::Bool(a.str(0)?.parse::<IpAddr>().is_ok()))
+}
+
+fn is_ipv4(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.is_ipv4", vals, limits, 1, 1)?;
+    Ok(Val::Bool(a.str(0)?.parse::<Ipv4Addr>().is_ok()))
+}
+
+fn is_ipv6(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.is_ipv6", vals, limits, 1, 1)?;
+    Ok(Val::Bool(a.str(0)?.parse::<Ipv6Addr>().is_ok()))
+}
+
+// An IPv4 or IPv6 network in CIDR notation. Addresses of both families are
+// stored as u128 so that the arithmetic can be shared.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Cidr {
+    addr: u128,
+    prefix: u32,
+    // Address width in bits: 32 or 128.
+    bits: u32,
+}
+
+impl Cidr {
+    // Parses "addr/prefix". A plain address is a network with a single address.
+    fn parse(s: &str) -> Result<Cidr, String> {
+        let (a, p) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let (addr, bits) = match a.parse::<IpAddr>() {
+            Ok(IpAddr::V4(a)) => (u32::from(a) as u128, 32),
+            Ok(IpAddr::V6(a)) => (u128::from(a), 128),
+            Err(_) => return Err(format!("invalid IP address \"{a}\"")),
+        };
+        let prefix = match p {
+            None => bits,
+            Some(p) => match p.parse::<u32>() {
+                Ok(p) if p <= bits => p,
+                _ => return Err(format!("invalid prefix length \"{p}\" in \"{s}\"")),
+            },
+        };
+        Ok(Cidr { addr, prefix, bits })
+    }
+
+    fn mask(&self) -> u128 {
+        let all = u128::MAX >> (128 - self.bits);
+        all & !all.checked_shr(self.prefix).unwrap_or(0)
+    }
+
+    fn network(&self) -> u128 {
+        self.addr & self.mask()
+    }
+
+    fn last(&self) -> u128 {
+        self.network() | (!self.mask() & (u128::MAX >> (128 - self.bits)))
+    }
+
+    // Number of addresses in this network, minus one (it may be 2^128).
+    fn size_minus_one(&self) -> u128 {
+        self.last() - self.network()
+    }
+
+    fn contains(&self, other: &Cidr) -> bool {
+        self.bits == other.bits
+            && other.prefix >= self.prefix
+            && other.network() & self.mask() == self.network()
+    }
+
+    fn fmt_addr(&self, a: u128) -> String {
+        if self.bits == 32 {
+            Ipv4Addr::from(a as u32).to_string()
+        } else {
+            Ipv6Addr::from(a).to_string()
+        }
+    }
+
+    fn fmt_net(&self, a: u128, prefix: u32) -> String {
+        format!("{}/{}", self.fmt_addr(a), prefix)
+    }
+}
+
+fn cidr_arg(a: &Args, i: usize) -> EvalResult<Cidr> {
+    Cidr::parse(a.str(i)?).or_else(|e| a.error(e))
+}
+
+fn addr_val(c: &Cidr, a: u128) -> EvalResult<Val> {
+    Ok(Val::Str(c.fmt_addr(a)))
+}
+
+// network(cidr): the network address.
+fn network(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.network", vals, limits, 1, 1)?;
+    let c = cidr_arg(&a, 0)?;
+    addr_val(&c, c.network())
+}
+
+// broadcast(cidr): the broadcast address of an IPv4 network.
+fn broadcast(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.broadcast", vals, limits, 1, 1)?;
+    let c = cidr_arg(&a, 0)?;
+    if c.bits != 32 {
+        return a.error("IPv6 networks have no broadcast address".to_string());
+    }
+    addr_val(&c, c.last())
+}
+
+fn netmask(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.netmask", vals, limits, 1, 1)?;
+    let c = cidr_arg(&a, 0)?;
+    addr_val(&c, c.mask())
+}
+
+fn prefix_len(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.prefix_len", vals, limits, 1, 1)?;
+    Ok(Val::Int(cidr_arg(&a, 0)?.prefix as i64))
+}
+
+// contains(cidr, addr_or_cidr): whether the network contains the given
+// address or network.
+fn contains(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.contains", vals, limits, 2, 2)?;
+    let c = cidr_arg(&a, 0)?;
+    Ok(Val::Bool(c.contains(&cidr_arg(&a, 1)?)))
+}
+
+// nth_host(cidr, n): the address at offset n from the network address.
+// Negative n count backwards from the last address, i.e. -1 is the
+// broadcast address of an IPv4 network.
+fn nth_host(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.nth_host", vals, limits, 2, 2)?;
+    let c = cidr_arg(&a, 0)?;
+    let n = a.int(1)?;
+    let off = n.unsigned_abs() as u128;
+    let size_minus_one = c.size_minus_one();
+    let addr = if n >= 0 && off <= size_minus_one {
+        c.network() + off
+    } else if n < 0 && off - 1 <= size_minus_one {
+        c.last() - (off - 1)
+    } else {
+        return a.error(format!(
+            "host number {n} out of range for {}",
+            c.fmt_net(c.network(), c.prefix)
+        ));
+    };
+    addr_val(&c, addr)
+}
+
+// Checks the new prefix length for splitting `c` and returns the number of
+// subnets, minus one.
+fn check_subnet_prefix(a: &Args, c: &Cidr, prefix: i64) -> EvalResult<u128> {
+    if prefix < c.prefix as i64 || prefix > c.bits as i64 {
+        return a.error(format!(
+            "prefix length {prefix} must be between {} and {}",
+            c.prefix, c.bits
+        ));
+    }
+    let newbits = prefix as u32 - c.prefix;
+    Ok(if newbits == 128 {
+        u128::MAX
+    } else {
+        (1u128 << newbits) - 1
+    })
+}
+
+// subnet(cidr, prefix_len, n): the n-th subnet with the given prefix length.
+fn subnet(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.subnet", vals, limits, 3, 3)?;
+    let c = cidr_arg(&a, 0)?;
+    let prefix = a.int(1)?;
+    let max = check_subnet_prefix(&a, &c, prefix)?;
+    let n = a.index(2)? as u128;
+    if n > max {
+        return a.error(format!(
+            "subnet number {n} out of range for {} split into /{prefix}",
+            c.fmt_net(c.network(), c.prefix)
+        ));
+    }
+    let prefix = prefix as u32;
+    let step = 1u128.checked_shl(c.bits - prefix).unwrap_or(0);
+    Ok(Val::Str(c.fmt_net(c.network() + n * step, prefix)))
+}
+
+// subnets(cidr, prefix_len): all subnets with the given prefix length.
+fn subnets(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("net.subnets", vals, limits, 2, 2)?;
+    let c = cidr_arg(&a, 0)?;
+    let prefix = a.int(1)?;
+    let max = check_subnet_prefix(&a, &c, prefix)?;
+    let mut nets = a.new_list(max.saturating_add(1))?;
+    let prefix = prefix as u32;
+    let step = 1u128.checked_shl(c.bits - prefix).unwrap_or(0);
+    nets.extend((0..=max).map(|i| Val::Str(c.fmt_net(c.network() + i * step, prefix))));
+    Ok(Val::List(nets.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtins::h::{e, err, s};
+
+    #[test]
+    fn split_url_works() {
+        assert_eq!(
+            split_url("https://user@Example.com:8443/a/b?x=1&y=2#frag"),
+            Ok(Url {
+                scheme: "https",
+                host: "Example.com",
+                port: Some(8443),
+                path: "/a/b",
+                query: "x=1&y=2",
+            })
+        );
+        assert_eq!(
+            split_url("http://[::1]:80"),
+            Ok(Url {
+                scheme: "http",
+                host: "::1",
+                port: Some(80),
+                path: "",
+                query: "",
+            })
+        );
+        assert!(split_url("example.com/foo").is_err());
+        assert!(split_url("http://:80/").is_err());
+        assert!(split_url("http://foo:http/").is_err());
+        assert!(split_url("http://[::1/").is_err());
+    }
+
+    #[test]
+    fn url_functions() {
+        assert_eq!(
+            e(r#"net.path("https://foosen.com:8080/path/to/glory")"#),
+            s("/path/to/glory")
+        );
+        assert_eq!(e(r#"net.port("https://foosen.com/")"#), Ok(Val::Int(443)));
+        assert_eq!(e(r#"net.port("foo://foosen.com/")"#), Ok(Val::Nil));
+        assert_eq!(
+            e(r#"net.parse_url("http://localhost:8080/x?a=b").query"#),
+            s("a=b")
+        );
+        assert_eq!(
+            e(r#"net.host("localhost")"#),
+            err("net.host: missing scheme in URL \"localhost\"")
+        );
+    }
+
+    #[test]
+    fn ip_predicates() {
+        assert_eq!(e(r#"net.is_ipv4("10.0.0.1")"#), Ok(Val::Bool(true)));
+        assert_eq!(e(r#"net.is_ipv4("10.0.0.256")"#), Ok(Val::Bool(false)));
+        assert_eq!(e(r#"net.is_ipv6("fe80::1")"#), Ok(Val::Bool(true)));
+        assert_eq!(e(r#"net.is_ip("fe80::1")"#), Ok(Val::Bool(true)));
+        assert_eq!(e(r#"net.is_ip("example.com")"#), Ok(Val::Bool(false)));
+    }
+
+    #[test]
+    fn cidr_functions() {
+        assert_eq!(e(r#"net.network("10.1.2.3/16")"#), s("10.1.0.0"));
+        assert_eq!(e(r#"net.broadcast("10.1.2.3/16")"#), s("10.1.255.255"));
+        assert_eq!(e(r#"net.netmask("10.1.2.3/20")"#), s("255.255.240.0"));
+        assert_eq!(e(r#"net.netmask("0.0.0.0/0")"#), s("0.0.0.0"));
+        assert_eq!(e(r#"net.prefix_len("10.0.0.0/8")"#), Ok(Val::Int(8)));
+        assert_eq!(e(r#"net.network("2001:db8::1/32")"#), s("2001:db8::"));
+        assert_eq!(
+            e(r#"net.broadcast("2001:db8::/32")"#),
+            err("net.broadcast: IPv6 networks have no broadcast address")
+        );
+        assert_eq!(
+            e(r#"net.network("10.0.0.0/33")"#),
+            err("net.network: invalid prefix length \"33\" in \"10.0.0.0/33\"")
+        );
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let c = |a, b| e(&format!("net.contains(\"{a}\", \"{b}\")"));
+        assert_eq!(c("10.0.0.0/8", "10.255.0.1"), Ok(Val::Bool(true)));
+        assert_eq!(c("10.0.0.0/8", "11.0.0.1"), Ok(Val::Bool(false)));
+        assert_eq!(c("10.0.0.0/8", "10.1.0.0/16"), Ok(Val::Bool(true)));
+        assert_eq!(c("10.1.0.0/16", "10.0.0.0/8"), Ok(Val::Bool(false)));
+        assert_eq!(c("10.0.0.0/8", "::1"), Ok(Val::Bool(false)));
+        assert_eq!(c("::/0", "::1"), Ok(Val::Bool(true)));
+    }
+
+    #[test]
+    fn cidr_hosts() {
+        assert_eq!(e(r#"net.nth_host("10.0.0.0/24", 5)"#), s("10.0.0.5"));
+        assert_eq!(e(r#"net.nth_host("10.0.0.0/24", -2)"#), s("10.0.0.254"));
+        assert_eq!(e(r#"net.nth_host("fd00::/64", 16)"#), s("fd00::10"));
+        assert_eq!(
+            e(r#"net.nth_host("10.0.0.0/24", 256)"#),
+            err("net.nth_host: host number 256 out of range for 10.0.0.0/24")
+        );
+        assert_eq!(
+            e(r#"net.nth_host("10.0.0.0/24", -257)"#),
+            err("net.nth_host: host number -257 out of range for 10.0.0.0/24")
+        );
+    }
+
+    #[test]
+    fn cidr_subnets() {
+        assert_eq!(
+            e(r#"net.subnets("10.0.0.0/16", 18)"#),
+            Ok(Val::from(vec![
+                "10.0.0.0/18",
+                "10.0.64.0/18",
+                "10.0.128.0/18",
+                "10.0.192.0/18",
+            ]))
+        );
+        assert_eq!(e(r#"net.subnet("10.0.0.0/8", 24, 258)"#), s("10.1.2.0/24"));
+        assert_eq!(e(r#"net.subnet("fd00::/48", 64, 1)"#), s("fd00:0:0:1::/64"));
+        assert_eq!(
+            e(r#"net.subnets("10.0.0.0/16", 8)"#),
+            err("net.subnets: prefix length 8 must be between 16 and 32")
+        );
+        assert_eq!(
+            e(r#"net.subnets("fd00::/48", 128)"#),
+            err("net.subnets: cannot allocate a list of 18446744073709551615 elements")
+        );
+        assert_eq!(
+            e(r#"net.subnet("10.0.0.0/16", 18, 4)"#),
+            err("net.subnet: subnet number 4 out of range for 10.0.0.0/16 split into /18")
+        );
+    }
+}
@@ -0,0 +1,157 @@
+// The `size` module: constructing, converting and formatting byte sizes.
+
+use super::{Args, Builtin};
+use crate::engine::Limits;
+use crate::eval::{EvalResult, Val};
+use crate::units;
+
+pub const FUNCTIONS: &[(&str, Builtin)] = &[
+    ("bytes", bytes),
+    ("kilobytes", kilobytes),
+    ("megabytes", megabytes),
+    ("gigabytes", gigabytes),
+    ("terabytes", terabytes),
+    ("kibibytes", kibibytes),
+    ("mebibytes", mebibytes),
+    ("gibibytes", gibibytes),
+    ("tebibytes", tebibytes),
+    ("as_bytes", as_bytes),
+    ("human", human),
+    ("parse", parse),
+];
+
+// All functions that take a size as their first argument.
+pub const METHODS: &[(&str, Builtin)] = &[("as_bytes", as_bytes), ("human", human)];
+
+// Turns the int argument into a size of `n * m` bytes.
+fn from_unit(name: &str, vals: &[Val], limits: &Limits, m: u64) -> EvalResult<Val> {
+    let a = Args::new(name, vals, limits, 1, 1)?;
+    let n = a.index(0)? as u64;
+    match n.checked_mul(m) {
+        Some(s) => Ok(Val::Size(s)),
+        None => a.error(format!("size overflow for {n} * {m} bytes")),
+    }
+}
+
+fn bytes(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    from_unit("size.bytes", vals, limits, 1)
+}
+
+fn kilobytes(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    from_unit("size.kilobytes", vals, limits, 1000)
+}
+
+fn megabytes(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    from_unit("size.megabytes", vals, limits, 1000 * 1000)
+}
+
+fn gigabytes(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    from_unit("size.gigabytes", vals, limits, 1000 * 1000 * 1000)
+}
+
+fn terabytes(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    from_unit("size.terabytes", vals, limits, 1000 * 1000 * 1000 * 1000)
+}
+
+fn kibibytes(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    from_unit("size.kibibytes", vals, limits, 1 << 10)
+}
+
+fn mebibytes(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    from_unit("size.mebibytes", vals, limits, 1 << 20)
+}
+
+fn gibibytes(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    from_unit("size.gibibytes", vals, limits, 1 << 30)
+}
+
+fn tebibytes(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    from_unit("size.tebibytes", vals, limits, 1 << 40)
+}
+
+fn size_arg(a: &Args, i: usize) -> EvalResult<u64> {
+    match a.get(i) {
+        Some(Val::Size(s)) => Ok(*s),
+        Some(v) => a.error(format!("argument {} must be size, got {}", i + 1, v.typ())),
+        None => a.error(format!("missing argument {}", i + 1)),
+    }
+}
+
+// as_bytes(s): the number of bytes in s, as an int.
+fn as_bytes(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("size.as_bytes", vals, limits, 1, 1)?;
+    let s = size_arg(&a, 0)?;
+    match i64::try_from(s) {
+        Ok(n) => Ok(Val::Int(n)),
+        Err(_) => a.error(format!("{} bytes do not fit into an int", s)),
+    }
+}
+
+// human(s[, system]): an approximate, human-readable representation of s,
+// e.g. "1.5 GiB". `system` is "iec" (the default) or "si".
+fn human(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("size.human", vals, limits, 1, 2)?;
+    let s = size_arg(&a, 0)?;
+    let iec = match if a.len() == 2 { a.str(1)? } else { "iec" } {
+        "iec" => true,
+        "si" => false,
+        sys => {
+            return a.error(format!(
+                "unit system must be \"iec\" or \"si\", got \"{sys}\""
+            ))
+        }
+    };
+    Ok(Val::Str(units::format_human(s, iec)))
+}
+
+// parse(s): parses a size like "512MiB" or "2g". The "B" is optional here.
+fn parse(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("size.parse", vals, limits, 1, 1)?;
+    match units::parse_size(a.str(0)?) {
+        Ok(s) => Ok(Val::Size(s)),
+        Err(e) => a.error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builtins::h::{e, err};
+    use crate::eval::Val;
+
+    #[test]
+    fn constructors() {
+        assert_eq!(e("size.megabytes(100)"), Ok(Val::Size(100_000_000)));
+        assert_eq!(e("size.mebibytes(100) == 100MiB"), Ok(Val::Bool(true)));
+        assert_eq!(e("size.bytes(7)"), Ok(Val::Size(7)));
+        assert_eq!(
+            e("size.kilobytes(-1)"),
+            err("size.kilobytes: argument 1 must not be negative, got -1")
+        );
+        assert_eq!(
+            e("size.tebibytes(20000000)"),
+            err("size.tebibytes: size overflow for 20000000 * 1099511627776 bytes")
+        );
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(e("size.as_bytes(2KiB)"), Ok(Val::Int(2048)));
+        assert_eq!(
+            e("size.as_bytes(2)"),
+            err("size.as_bytes: argument 1 must be size, got int")
+        );
+        assert_eq!(
+            e("size.human(1536MiB)"),
+            Ok(Val::Str("1.5 GiB".to_string()))
+        );
+        assert_eq!(
+            e(r#"size.human(1536MiB, "si")"#),
+            Ok(Val::Str("1.6 GB".to_string()))
+        );
+        assert_eq!(e(r#"size.parse("2g")"#), Ok(Val::Size(2_000_000_000)));
+        assert_eq!(
+            e(r#"size.parse("2x")"#),
+            err("size.parse: Invalid size unit \"x\" in \"2x\"")
+        );
+    }
+}
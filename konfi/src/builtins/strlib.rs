@@ -0,0 +1,274 @@
+// The `str` module: string manipulation.
+
+use super::{Args, Builtin};
+use crate::engine::Limits;
+use crate::eval::{EvalResult, Val};
+use std::borrow::Cow;
+
+pub const FUNCTIONS: &[(&str, Builtin)] = &[
+    ("startswith", startswith),
+    ("endswith", endswith),
+    ("contains", contains),
+    ("split", split),
+    ("join", join),
+    ("replace", replace),
+    ("upper", upper),
+    ("lower", lower),
+    ("trim", trim),
+    ("substr", substr),
+    ("len", len),
+    ("format", format),
+];
+
+// All functions that take a str as their first argument.
+pub const METHODS: &[(&str, Builtin)] = &[
+    ("startswith", startswith),
+    ("endswith", endswith),
+    ("contains", contains),
+    ("split", split),
+    ("replace", replace),
+    ("upper", upper),
+    ("lower", lower),
+    ("trim", trim),
+    ("substr", substr),
+    ("len", len),
+    ("format", format),
+];
+
+fn str_val(s: &str) -> EvalResult<Val> {
+    Ok(Val::Str(s.to_string()))
+}
+
+// startswith(s, prefix)
+fn startswith(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("str.startswith", vals, limits, 2, 2)?;
+    Ok(Val::Bool(a.str(0)?.starts_with(a.str(1)?)))
+}
+
+// endswith(s, suffix)
+fn endswith(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("str.endswith", vals, limits, 2, 2)?;
+    Ok(Val::Bool(a.str(0)?.ends_with(a.str(1)?)))
+}
+
+// contains(s, substring)
+fn contains(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("str.contains", vals, limits, 2, 2)?;
+    Ok(Val::Bool(a.str(0)?.contains(a.str(1)?)))
+}
+
+// split(s[, sep]): splits at whitespace if no separator is given.
+fn split(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("str.split", vals, limits, 1, 2)?;
+    let s = a.str(0)?;
+    let parts: Vec<Val> = if a.len() == 1 {
+        s.split_whitespace()
+            .map(|p| Val::Str(p.to_string()))
+            .collect()
+    } else {
+        let sep = a.str(1)?;
+        if sep.is_empty() {
+            return a.error("separator must not be empty".to_string());
+        }
+        s.split(sep).map(|p| Val::Str(p.to_string())).collect()
+    };
+    Ok(Val::List(parts.into()))
+}
+
+// join(list[, sep])
+pub(super) fn join(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("str.join", vals, limits, 1, 2)?;
+    let sep = if a.len() == 2 { a.str(1)? } else { "" };
+    let mut parts = Vec::new();
+    for (i, v) in a.list(0)?.iter().enumerate() {
+        match v {
+            Val::Str(s) => parts.push(s.as_str()),
+            _ => return a.error(format!("list element {i} must be str, got {}", v.typ())),
+        }
+    }
+    let seps = parts.len().saturating_sub(1).saturating_mul(sep.len());
+    a.check_str_len(parts.iter().fold(seps, |n, p| n.saturating_add(p.len())))?;
+    str_val(&parts.join(sep))
+}
+
+// replace(s, from, to): replaces all occurrences of `from`.
+fn replace(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("str.replace", vals, limits, 3, 3)?;
+    let (s, from, to) = (a.str(0)?, a.str(1)?, a.str(2)?);
+    if from.is_empty() {
+        return a.error("pattern must not be empty".to_string());
+    }
+    if to.len() > from.len() {
+        let growth = s
+            .matches(from)
+            .count()
+            .saturating_mul(to.len() - from.len());
+        a.check_str_len(s.len().saturating_add(growth))?;
+    }
+    str_val(&s.replace(from, to))
+}
+
+fn upper(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("str.upper", vals, limits, 1, 1)?;
+    str_val(&a.str(0)?.to_uppercase())
+}
+
+fn lower(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("str.lower", vals, limits, 1, 1)?;
+    str_val(&a.str(0)?.to_lowercase())
+}
+
+fn trim(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("str.trim", vals, limits, 1, 1)?;
+    str_val(a.str(0)?.trim())
+}
+
+// substr(s, start[, len]): indices count characters, not bytes.
+fn substr(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("str.substr", vals, limits, 2, 3)?;
+    let s = a.str(0)?;
+    let start = a.index(1)?;
+    let n = s.chars().count();
+    if start > n {
+        return a.error(format!("start index {start} out of range for length {n}"));
+    }
+    let len = if a.len() == 3 { a.index(2)? } else { n - start };
+    str_val(&s.chars().skip(start).take(len).collect::<String>())
+}
+
+// len(s): the number of characters in s.
+fn len(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("str.len", vals, limits, 1, 1)?;
+    Ok(Val::Int(a.str(0)?.chars().count() as i64))
+}
+
+// format(fmt, args...): replaces each {} in fmt by the next argument.
+// Use {{ and }} for literal braces.
+fn format(vals: &[Val], limits: &Limits) -> EvalResult<Val> {
+    let a = Args::new("str.format", vals, limits, 1, usize::MAX)?;
+    let fmt = a.str(0)?;
+    let mut out = String::with_capacity(fmt.len());
+    let mut next = 1;
+    let mut cs = fmt.chars().peekable();
+    while let Some(c) = cs.next() {
+        match (c, cs.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                cs.next();
+                out.push(c);
+            }
+            ('{', Some('}')) => {
+                cs.next();
+                let arg = match a.get(next) {
+                    // Strings are inserted verbatim, without quotes.
+                    Some(Val::Str(s)) => Cow::Borrowed(s.as_str()),
+                    Some(v) => Cow::Owned(v.to_string()),
+                    None => {
+                        return a.error(format!("not enough arguments for format string \"{fmt}\""))
+                    }
+                };
+                a.check_str_len(out.len().saturating_add(arg.len()))?;
+                out.push_str(&arg);
+                next += 1;
+            }
+            ('{', _) | ('}', _) => {
+                return a.error(format!("unmatched '{c}' in format string \"{fmt}\""))
+            }
+            _ => out.push(c),
+        }
+    }
+    if next < a.len() {
+        return a.error(format!("too many arguments for format string \"{fmt}\""));
+    }
+    str_val(&out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builtins::h::{e, err, s};
+    use crate::eval::Val;
+
+    #[test]
+    fn predicates() {
+        assert_eq!(
+            e(r#"str.startswith("foo.com", "foo")"#),
+            Ok(Val::Bool(true))
+        );
+        assert_eq!(e(r#"str.endswith("foo.com", ".com")"#), Ok(Val::Bool(true)));
+        assert_eq!(e(r#"str.endswith("foo.de", ".com")"#), Ok(Val::Bool(false)));
+        assert_eq!(e(r#"str.contains("foo.com", "o.c")"#), Ok(Val::Bool(true)));
+    }
+
+    #[test]
+    fn split_join() {
+        assert_eq!(
+            e(r#"str.split("a,b,,c", ",")"#),
+            Ok(Val::from(vec!["a", "b", "", "c"]))
+        );
+        assert_eq!(e(r#"str.join(str.split("  a b\n c "), "-")"#), s("a-b-c"));
+        assert_eq!(e(r#"str.join(["a", "b"])"#), s("ab"));
+        assert_eq!(
+            e(r#"str.join(["a", 1], "-")"#),
+            err("str.join: list element 1 must be str, got int")
+        );
+    }
+
+    #[test]
+    fn transform() {
+        assert_eq!(e(r#"str.replace("a.b.c", ".", "::")"#), s("a::b::c"));
+        assert_eq!(e(r#"str.upper("Straße")"#), s("STRASSE"));
+        assert_eq!(e(r#"str.lower("ABC")"#), s("abc"));
+        assert_eq!(e(r#"str.trim("  abc \n")"#), s("abc"));
+    }
+
+    #[test]
+    fn substr_len() {
+        assert_eq!(e(r#"str.substr("Sögestraße", 1, 3)"#), s("öge"));
+        assert_eq!(e(r#"str.substr("abc", 1)"#), s("bc"));
+        assert_eq!(e(r#"str.substr("abc", 1, 10)"#), s("bc"));
+        assert_eq!(
+            e(r#"str.substr("abc", 4)"#),
+            err("str.substr: start index 4 out of range for length 3")
+        );
+        assert_eq!(
+            e(r#"str.substr("abc", -1)"#),
+            err("str.substr: argument 2 must not be negative, got -1")
+        );
+        assert_eq!(e(r#"str.len("Sögestraße")"#), Ok(Val::Int(10)));
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(
+            e(r#"str.format("{}:{} {{{}}}", "localhost", 8080, [1])"#),
+            s("localhost:8080 {[1]}")
+        );
+        assert_eq!(
+            e(r#"str.format("{}:{}", "localhost")"#),
+            err("str.format: not enough arguments for format string \"{}:{}\"")
+        );
+        assert_eq!(
+            e(r#"str.format("{}", 1, 2)"#),
+            err("str.format: too many arguments for format string \"{}\"")
+        );
+        assert_eq!(
+            e(r#"str.format("{x}", 1)"#),
+            err("str.format: unmatched '{' in format string \"{x}\"")
+        );
+    }
+
+    #[test]
+    fn bad_args() {
+        assert_eq!(
+            e(r#"str.upper(1)"#),
+            err("str.upper: argument 1 must be str, got int")
+        );
+        assert_eq!(
+            e(r#"str.endswith("a")"#),
+            err("str.endswith: expected 2 argument(s), got 1")
+        );
+        assert_eq!(
+            e(r#"str.split("a", ",", 1)"#),
+            err("str.split: expected 1 to 2 argument(s), got 3")
+        );
+    }
+}
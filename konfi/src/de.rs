@@ -0,0 +1,476 @@
+// Deserialization of evaluated konfi values into Rust types, using serde.
+//
+//     #[derive(Deserialize)]
+//     struct Config { port: u16, timeout: std::time::Duration }
+//     let c: Config = konfi::from_str("{\n port: 8080\n timeout: 30s\n}")?;
+
+use crate::ast;
+use crate::engine::Engine;
+use crate::eval::{self, Val};
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+use std::fmt::{self, Display};
+use std::path::Path;
+
+/// What went wrong while loading a konfi file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Io,
+    Parse,
+    Eval,
+    Deserialize,
+}
+
+/// An error while loading a konfi file or deserializing its value.
+#[derive(Debug, PartialEq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// Path of the value that could not be deserialized, e.g. ".servers[0].port".
+    pub path: Option<String>,
+    /// The file that was loaded, if any.
+    pub file: Option<String>,
+    /// 1-based line and column in the source, if known. For deserialization
+    /// errors, this is the location of the innermost field involved.
+    pub line_col: Option<(usize, usize)>,
+}
+
+impl Error {
+    pub(crate) fn new(message: String) -> Self {
+        Error {
+            kind: ErrorKind::Deserialize,
+            message,
+            path: None,
+            file: None,
+            line_col: None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.file, self.line_col) {
+            (Some(file), Some((line, col))) => write!(f, "{file}:{line}:{col}: ")?,
+            (Some(file), None) => write!(f, "{file}: ")?,
+            (None, Some((line, col))) => write!(f, "{line}:{col}: ")?,
+            (None, None) => {}
+        }
+        match &self.path {
+            Some(path) if !path.is_empty() => write!(f, "{path}: {}", self.message),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::new(msg.to_string())
+    }
+}
+
+/// Deserializes an evaluated value.
+pub fn from_val<T: DeserializeOwned>(val: &Val) -> Result<T, Error> {
+    T::deserialize(Deserializer::new(val, None))
+}
+
+/// Parses, evaluates and deserializes konfi source code.
+pub fn from_str<T: DeserializeOwned>(src: &str) -> Result<T, Error> {
+    Engine::new().deserialize_str(src)
+}
+
+/// Loads the konfi file at `path` and deserializes its value.
+pub fn from_path<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T, Error> {
+    Engine::new().deserialize_file(path)
+}
+
+// Where a value lives: its path and the position of the innermost field containing it.
+#[derive(Clone)]
+struct Loc<'a> {
+    path: String,
+    pos: Option<ast::Pos>,
+    src: Option<&'a str>,
+}
+
+impl<'a> Loc<'a> {
+    fn field(&self, name: &str, pos: Option<ast::Pos>) -> Self {
+        Loc {
+            path: format!("{}.{}", self.path, name),
+            pos: pos.or(self.pos),
+            src: self.src,
+        }
+    }
+
+    fn index(&self, i: usize) -> Self {
+        Loc {
+            path: format!("{}[{}]", self.path, i),
+            pos: self.pos,
+            src: self.src,
+        }
+    }
+
+    // Attaches this location to `e`, unless it already has a more specific one.
+    fn apply(&self, mut e: Error) -> Error {
+        if e.path.is_none() {
+            e.path = Some(self.path.clone());
+        }
+        if e.line_col.is_none() {
+            if let (Some(pos), Some(src)) = (self.pos, self.src) {
+                e.line_col = Some(pos.line_col(src));
+            }
+        }
+        e
+    }
+}
+
+/// A serde deserializer for evaluated values.
+pub struct Deserializer<'a> {
+    val: &'a Val,
+    loc: Loc<'a>,
+}
+
+impl<'a> Deserializer<'a> {
+    /// Creates a deserializer for `val`. If `src` is the source text `val`
+    /// was evaluated from, errors include line and column numbers.
+    pub fn new(val: &'a Val, src: Option<&'a str>) -> Self {
+        Deserializer {
+            val,
+            loc: Loc {
+                path: String::new(),
+                pos: None,
+                src,
+            },
+        }
+    }
+
+    fn visit<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.val {
+            Val::Nil => visitor.visit_unit(),
+            Val::Bool(b) => visitor.visit_bool(*b),
+            Val::Int(i) => visitor.visit_i64(*i),
+            Val::Double(d) => visitor.visit_f64(*d),
+            Val::Str(s) => visitor.visit_str(s),
+            Val::Size(s) => visitor.visit_u64(*s),
+            // Durations look like std::time::Duration to serde.
+            Val::Duration(d) => {
+                let n = eval::nanos(d);
+                if n < 0 {
+                    return Err(Error::new(format!(
+                        "negative duration {} cannot be deserialized",
+                        self.val
+                    )));
+                }
+                let n = n as u64;
+                visitor.visit_map(de::value::MapDeserializer::new(
+                    [("secs", n / 1_000_000_000), ("nanos", n % 1_000_000_000)].into_iter(),
+                ))
+            }
+            Val::Rec(r) => {
+                let r = r.borrow();
+                let mut fields: Vec<_> = r.visible().collect();
+                fields.sort_by_key(|(name, _)| *name);
+                visitor.visit_map(RecAccess {
+                    rec: &r,
+                    fields: fields.into_iter(),
+                    value: None,
+                    loc: &self.loc,
+                })
+            }
+            Val::List(l) => visitor.visit_seq(ListAccess {
+                vals: l.iter().enumerate(),
+                loc: &self.loc,
+            }),
+            Val::Timestamp(_) => Err(Error::new(
+                "timestamps cannot be deserialized yet".to_string(),
+            )),
+            Val::NativeFn(nf) => Err(Error::new(format!(
+                "cannot deserialize function {}",
+                nf.name
+            ))),
+        }
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Deserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let loc = self.loc.clone();
+        self.visit(visitor).map_err(|e| loc.apply(e))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.val {
+            Val::Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    // Sizes and durations can also be read as strings, e.g. "512MiB" or "1h30m".
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.val {
+            Val::Size(_) | Val::Duration(_) => visitor
+                .visit_string(self.val.to_string())
+                .map_err(|e| self.loc.apply(e)),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // Unit variants are strings, all other variants records with a single field,
+    // e.g. {tcp: {port: 80}}.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let loc = self.loc.clone();
+        let r = match self.val {
+            Val::Str(s) => visitor.visit_enum(s.as_str().into_deserializer()),
+            Val::Rec(r) if r.borrow().fields.len() == 1 => {
+                let r = r.borrow();
+                let (name, val) = r.fields.iter().next().expect("one field");
+                visitor.visit_enum(VariantRec {
+                    name,
+                    de: Deserializer {
+                        val,
+                        loc: self.loc.field(name, r.getloc(name)),
+                    },
+                })
+            }
+            v => Err(Error::new(format!(
+                "expected a str or a rec with a single field for enum, got {}",
+                v.typ()
+            ))),
+        };
+        r.map_err(|e| loc.apply(e))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct RecAccess<'a, 'r> {
+    rec: &'r eval::Rec,
+    fields: std::vec::IntoIter<(&'r String, &'r Val)>,
+    value: Option<(&'r String, &'r Val)>,
+    loc: &'r Loc<'a>,
+}
+
+impl<'de, 'a, 'r> MapAccess<'de> for RecAccess<'a, 'r> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.fields.next() {
+            Some((name, val)) => {
+                self.value = Some((name, val));
+                seed.deserialize(name.as_str().into_deserializer())
+                    .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (name, val) = self
+            .value
+            .take()
+            .ok_or_else(|| Error::new("value requested before key".to_string()))?;
+        seed.deserialize(Deserializer {
+            val,
+            loc: self.loc.field(name, self.rec.getloc(name)),
+        })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.fields.len())
+    }
+}
+
+struct ListAccess<'a, 'r> {
+    vals: std::iter::Enumerate<std::slice::Iter<'r, Val>>,
+    loc: &'r Loc<'a>,
+}
+
+impl<'de, 'a, 'r> SeqAccess<'de> for ListAccess<'a, 'r> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.vals.next() {
+            Some((i, val)) => seed
+                .deserialize(Deserializer {
+                    val,
+                    loc: self.loc.index(i),
+                })
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.vals.len())
+    }
+}
+
+// An enum variant given as a record with a single field.
+struct VariantRec<'a> {
+    name: &'a str,
+    de: Deserializer<'a>,
+}
+
+impl<'de, 'a> EnumAccess<'de> for VariantRec<'a> {
+    type Error = Error;
+    type Variant = Deserializer<'a>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self::Variant), Error> {
+        let v = seed.deserialize(self.name.into_deserializer())?;
+        Ok((v, self.de))
+    }
+}
+
+impl<'de, 'a> VariantAccess<'de> for Deserializer<'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Proto {
+        Http,
+        Tcp { port: u16 },
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Server {
+        name: String,
+        port: u16,
+        weight: Option<f64>,
+        timeout: Duration,
+        max_body: u64,
+        tags: Vec<String>,
+        proto: Proto,
+    }
+
+    #[test]
+    fn deserialize_struct() {
+        let src = r#"
+let base = 8000
+{
+  name: "web"
+  port: base + 80
+  timeout: 1m30s
+  max_body: 2MiB
+  tags: ["a", "b"]
+  proto: {tcp: {port: 9000}}
+}
+"#;
+        assert_eq!(
+            from_str::<Server>(src),
+            Ok(Server {
+                name: "web".to_string(),
+                port: 8080,
+                weight: None,
+                timeout: Duration::from_secs(90),
+                max_body: 2 << 20,
+                tags: vec!["a".to_string(), "b".to_string()],
+                proto: Proto::Tcp { port: 9000 },
+            })
+        );
+        let m: HashMap<String, String> = from_str("{\n a: \"x\"\n t: 250ms\n s: 1KiB\n}").unwrap();
+        assert_eq!(m["t"], "250ms");
+        assert_eq!(m["s"], "1KiB");
+        assert_eq!(from_str::<Proto>(r#""http""#), Ok(Proto::Http));
+        assert_eq!(from_str::<Option<i32>>("7"), Ok(Some(7)));
+    }
+
+    #[test]
+    fn errors() {
+        let src = "{\n  servers: [\n    {\n      port: 70000\n    }\n  ]\n}";
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct S {
+            port: u16,
+        }
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct C {
+            servers: Vec<S>,
+        }
+        let e = from_str::<C>(src).unwrap_err();
+        assert_eq!(e.path.as_deref(), Some(".servers[0].port"));
+        assert_eq!(e.line_col, Some((4, 7)));
+        assert_eq!(
+            e.to_string(),
+            "4:7: .servers[0].port: invalid value: integer `70000`, expected u16"
+        );
+        let e = from_str::<C>("{\n  servers: [{}]\n}").unwrap_err();
+        assert_eq!(e.to_string(), "2:3: .servers[0]: missing field `port`");
+        let e = from_str::<Duration>("1s - 2s").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "negative duration -1s cannot be deserialized"
+        );
+        let e = from_str::<C>("{\n  servers: 1 + \"a\"\n}").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "2:3: Invalid types for arithmetic operation '+': int and str"
+        );
+        let e = from_path::<C>("/nonexistent/x.konfi").unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("/nonexistent/x.konfi: Cannot read file"));
+    }
+}
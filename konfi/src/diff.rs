@@ -0,0 +1,148 @@
+// Structural diff of evaluated values.
+//
+// Records are compared field by field, regardless of field order, and lists
+// element by element. The result lists every added, removed and changed
+// value by its path, e.g. "servers[1].port".
+
+use crate::eval::{PathElem, Rec, Val};
+use std::fmt::{self, Display};
+
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Added(Val),
+    Removed(Val),
+    Changed(Val, Val),
+}
+
+/// A difference between two values.
+#[derive(Debug, PartialEq)]
+pub struct Diff {
+    // Path of the value that differs; empty for the values themselves.
+    pub path: String,
+    pub change: Change,
+}
+
+impl Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() {
+            "<root>"
+        } else {
+            &self.path
+        };
+        match &self.change {
+            Change::Added(v) => write!(f, "+ {path}: {} {v}", v.typ()),
+            Change::Removed(v) => write!(f, "- {path}: {} {v}", v.typ()),
+            Change::Changed(old, new) => {
+                write!(f, "~ {path}: {} {old} -> {} {new}", old.typ(), new.typ())
+            }
+        }
+    }
+}
+
+/// Returns the differences between `old` and `new`, sorted by path.
+pub fn diff(old: &Val, new: &Val) -> Vec<Diff> {
+    let mut ds = vec![];
+    diff_at(old, new, "", &mut ds);
+    ds
+}
+
+fn diff_at(old: &Val, new: &Val, path: &str, ds: &mut Vec<Diff>) {
+    let push = |ds: &mut Vec<Diff>, path: String, change| ds.push(Diff { path, change });
+    match (old, new) {
+        (Val::Rec(o), Val::Rec(n)) => {
+            let (o, n) = (o.borrow(), n.borrow());
+            let mut names: Vec<&String> = o.visible().chain(n.visible()).map(|(f, _)| f).collect();
+            names.sort();
+            names.dedup();
+            for name in names {
+                let field_path = format!("{path}{}", PathElem::Field(name.to_string()));
+                let field_path = field_path
+                    .strip_prefix('.')
+                    .unwrap_or(&field_path)
+                    .to_string();
+                let visible = |r: &Rec| r.getattr(name).filter(|_| !r.is_hidden(name));
+                match (visible(&o), visible(&n)) {
+                    (Some(ov), Some(nv)) => diff_at(&ov, &nv, &field_path, ds),
+                    (Some(ov), None) => push(ds, field_path, Change::Removed(ov)),
+                    (None, Some(nv)) => push(ds, field_path, Change::Added(nv)),
+                    (None, None) => unreachable!("{name} is a field of either record"),
+                }
+            }
+        }
+        (Val::List(o), Val::List(n)) => {
+            for i in 0..o.len().max(n.len()) {
+                let elem_path = format!("{path}[{i}]");
+                match (o.get(i), n.get(i)) {
+                    (Some(ov), Some(nv)) => diff_at(ov, nv, &elem_path, ds),
+                    (Some(ov), None) => push(ds, elem_path, Change::Removed(ov.clone())),
+                    (None, Some(nv)) => push(ds, elem_path, Change::Added(nv.clone())),
+                    (None, None) => unreachable!("{i} is an index of either list"),
+                }
+            }
+        }
+        (o, n) if o != n => push(ds, path.to_string(), Change::Changed(o.clone(), n.clone())),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+
+    fn eval(src: &str) -> Val {
+        Engine::new().eval_str(src).unwrap()
+    }
+
+    fn diff_lines(old: &str, new: &str) -> Vec<String> {
+        diff(&eval(old), &eval(new))
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn field_order_is_ignored() {
+        assert!(diff_lines("{\n  a: 1\n  b: 2\n}", "{\n  b: 2\n  a: 1\n}").is_empty());
+    }
+
+    #[test]
+    fn changes() {
+        let old = r#"{
+            name: "web"
+            port: 80
+            pool: { replicas: 1 }
+            hosts: ["a", "b"]
+            timeout: 30s
+        }"#;
+        let new = r#"{
+            name: "web"
+            port: "80"
+            pool: {
+                replicas: 2
+                cert: "c.pem"
+            }
+            hosts: ["a"]
+            timeout: 1m
+        }"#;
+        assert_eq!(
+            diff_lines(old, new),
+            vec![
+                "- hosts[1]: str \"b\"",
+                "+ pool.cert: str \"c.pem\"",
+                "~ pool.replicas: int 1 -> int 2",
+                "~ port: int 80 -> str \"80\"",
+                "~ timeout: duration 30s -> duration 1m",
+            ]
+        );
+        assert_eq!(diff_lines("1", "[1]"), vec!["~ <root>: int 1 -> list [1]"]);
+        assert_eq!(
+            diff_lines("{a:: 1, b: {c:: 1}}", "{a: 1, b: {c:: 2}}"),
+            vec!["+ a: int 1"]
+        );
+        assert_eq!(
+            diff_lines("{\"a.b\": {c: 1}}", "{\"a.b\": {c: 2}}"),
+            vec!["~ [\"a.b\"].c: int 1 -> int 2"]
+        );
+    }
+}
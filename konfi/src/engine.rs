@@ -0,0 +1,676 @@
+// Embedding konfi: an Engine evaluates konfi sources with host-defined
+// functions, modules and values.
+//
+//     let engine = Engine::builder()
+//         .function("double", |args| match args {
+//             [Val::Int(n)] => Ok(Val::Int(2 * n)),
+//             _ => Err(EvalError {
+//                 message: "double: expected an int".into(),
+//                 pos: None,
+//                 failed_asserts: 0,
+//             }),
+//         })
+//         .global("region", "eu-west")
+//         .build();
+//     let port: u16 = engine.deserialize_str("double(4000)")?;
+
+use crate::de::{self, Error, ErrorKind};
+use crate::eval::{self, Ctx, Env, EvalError, EvalResult, NativeFn, Rec, Val};
+use crate::parser;
+use serde::de::DeserializeOwned;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Limits that keep the evaluation of untrusted sources in check.
+/// Exceeding any of them makes the evaluation fail with an EvalError.
+/// Only the depth is limited by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum nesting depth of expressions during evaluation, and of the
+    /// lists and records in values.
+    pub max_depth: usize,
+    /// Maximum number of expressions evaluated, including those of imported modules.
+    pub max_steps: Option<u64>,
+    /// Maximum length of strings (in bytes) and lists (in elements).
+    pub max_size: Option<usize>,
+    /// Maximum wall-clock time of an evaluation, including its imports.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            // Deep enough for any sane config, shallow enough for a 2MB thread stack.
+            max_depth: 200,
+            max_steps: None,
+            max_size: None,
+            timeout: None,
+        }
+    }
+}
+
+impl Limits {
+    // Checks the length of a string of `n` bytes against the maximum size.
+    pub(crate) fn check_str_len(&self, n: usize) -> EvalResult<()> {
+        self.check_size("String", n, "bytes")
+    }
+
+    // Checks the length of a list of `n` elements against the maximum size.
+    pub(crate) fn check_list_len(&self, n: usize) -> EvalResult<()> {
+        self.check_size("List", n, "elements")
+    }
+
+    fn check_size(&self, what: &str, n: usize, unit: &str) -> EvalResult<()> {
+        match self.max_size {
+            Some(max) if n > max => Err(EvalError {
+                message: format!("{what} of {n} {unit} exceeds the maximum size of {max} {unit}"),
+                pos: None,
+                failed_asserts: 0,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Finds the sources of imported modules.
+pub trait ImportResolver {
+    /// Resolves `path`, imported by the module named `from` (None for the
+    /// main module if it has no name). Returns the module's name, which
+    /// identifies it in error messages and for cycle detection, and its source.
+    fn resolve(&self, path: &str, from: Option<&str>) -> Result<(String, String), String>;
+}
+
+type ReadFn = dyn Fn(&Path) -> Result<String, String>;
+
+/// Resolves imports to files, relative to the directory of the importing file.
+/// Only files below the base directory can be imported.
+pub struct FileResolver {
+    base_dir: PathBuf,
+    read: Box<ReadFn>,
+}
+
+impl FileResolver {
+    /// Imports of modules without a name are resolved relative to `base_dir`.
+    pub fn new(base_dir: impl Into<PathBuf>) -> Self {
+        Self::with_reader(base_dir, |p| {
+            std::fs::read_to_string(p).map_err(|e| e.to_string())
+        })
+    }
+
+    /// Like `new`, but reads files with `read`, e.g. from a version control system.
+    pub fn with_reader(
+        base_dir: impl Into<PathBuf>,
+        read: impl Fn(&Path) -> Result<String, String> + 'static,
+    ) -> Self {
+        FileResolver {
+            base_dir: base_dir.into(),
+            read: Box::new(read),
+        }
+    }
+}
+
+impl FileResolver {
+    // Whether `file` is below the base directory. Files that exist on disk
+    // must also be below it after resolving symbolic links.
+    fn contains(&self, file: &Path) -> bool {
+        let absolute = |p: &Path| std::path::absolute(p).map(|p| normalize(&p));
+        let below = match (absolute(file), absolute(&self.base_dir)) {
+            (Ok(f), Ok(base)) => f.starts_with(base),
+            _ => false,
+        };
+        below
+            && match (file.canonicalize(), self.base_dir.canonicalize()) {
+                (Ok(f), Ok(base)) => f.starts_with(base),
+                _ => true,
+            }
+    }
+}
+
+impl ImportResolver for FileResolver {
+    fn resolve(&self, path: &str, from: Option<&str>) -> Result<(String, String), String> {
+        let dir = match from.and_then(|f| Path::new(f).parent()) {
+            Some(d) => d,
+            None => &self.base_dir,
+        };
+        let file = normalize(&dir.join(path));
+        if !self.contains(&file) {
+            return Err(format!(
+                "{} is outside of the base directory {}",
+                file.display(),
+                self.base_dir.display()
+            ));
+        }
+        let src = (self.read)(&file)?;
+        Ok((file.display().to_string(), src))
+    }
+}
+
+// Removes . and resolvable .. components, so that every file has one name.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(out.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// A module of native functions, available as a record in konfi.
+#[derive(Default)]
+pub struct Module {
+    functions: Vec<(String, NativeFn)>,
+}
+
+impl Module {
+    pub fn new() -> Self {
+        Module::default()
+    }
+
+    pub fn function(mut self, name: &str, f: impl Fn(&[Val]) -> EvalResult<Val> + 'static) -> Self {
+        self.functions
+            .push((name.to_string(), NativeFn::new(name, f)));
+        self
+    }
+
+    fn into_val(self, module_name: &str) -> Val {
+        let mut r = Rec::new();
+        for (name, mut f) in self.functions {
+            f.name = format!("{module_name}.{name}");
+            r.setattr(&name, Val::NativeFn(f));
+        }
+        Val::Rec(Rc::new(RefCell::new(r)))
+    }
+}
+
+// Everything an Engine was built with, shared by all of its evaluations.
+#[derive(Default)]
+pub(crate) struct Settings {
+    // Host-defined values, available in every module.
+    pub globals: Vec<(String, Val)>,
+    pub resolver: Option<Rc<dyn ImportResolver>>,
+    pub limits: Limits,
+    // Values of external variables, read by ext().
+    pub externals: HashMap<String, Val>,
+    // Environment variables that env() may read.
+    pub env_vars: HashSet<String>,
+}
+
+/// Builds an Engine.
+#[derive(Default)]
+pub struct EngineBuilder {
+    settings: Settings,
+}
+
+impl EngineBuilder {
+    /// Defines a global native function.
+    pub fn function(self, name: &str, f: impl Fn(&[Val]) -> EvalResult<Val> + 'static) -> Self {
+        self.global(name, Val::NativeFn(NativeFn::new(name, f)))
+    }
+
+    /// Defines a global module of native functions, like the builtin `str`.
+    pub fn module(self, name: &str, module: Module) -> Self {
+        let v = module.into_val(name);
+        self.global(name, v)
+    }
+
+    /// Defines a global value. Globals shadow builtins of the same name.
+    pub fn global(mut self, name: &str, val: impl Into<Val>) -> Self {
+        self.settings.globals.push((name.to_string(), val.into()));
+        self
+    }
+
+    /// Enables imports, resolved by `resolver`. Without one, imports are errors.
+    pub fn import_resolver(mut self, resolver: impl ImportResolver + 'static) -> Self {
+        self.settings.resolver = Some(Rc::new(resolver));
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.settings.limits = limits;
+        self
+    }
+
+    /// Sets the external variable `name`, which konfi code reads with `ext("name")`.
+    pub fn ext(mut self, name: &str, val: impl Into<Val>) -> Self {
+        self.settings.externals.insert(name.to_string(), val.into());
+        self
+    }
+
+    /// Allows `env("name")` to read the environment variable `name`.
+    /// No environment variables can be read by default.
+    pub fn allow_env(mut self, name: &str) -> Self {
+        self.settings.env_vars.insert(name.to_string());
+        self
+    }
+
+    pub fn build(self) -> Engine {
+        Engine {
+            settings: Rc::new(self.settings),
+        }
+    }
+}
+
+/// Evaluates konfi sources. An Engine can be used for any number of evaluations,
+/// each of which starts from the same builtins and globals.
+#[derive(Default)]
+pub struct Engine {
+    settings: Rc<Settings>,
+}
+
+impl Engine {
+    /// An engine with only the builtins, no imports and default limits.
+    pub fn new() -> Self {
+        Engine::default()
+    }
+
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder::default()
+    }
+
+    /// The global context for evaluating the module `name` (used to resolve
+    /// its relative imports), for use with the functions of the eval module.
+    pub fn context(&self, name: Option<&str>) -> Rc<Ctx<'static>> {
+        Ctx::root(Rc::new(Env::new(Rc::clone(&self.settings), name)))
+    }
+
+    /// Parses and evaluates a module.
+    pub fn eval_str(&self, src: &str) -> Result<Val, Error> {
+        self.eval_source(src, None)
+    }
+
+    /// Parses and evaluates the module `name`. Errors refer to the module by its name.
+    pub fn eval_source(&self, src: &str, name: Option<&str>) -> Result<Val, Error> {
+        let with_file = |e: Error| Error {
+            file: name.map(str::to_string),
+            ..e
+        };
+        let module = parser::parse_module(src).map_err(|e| {
+            with_file(Error {
+                kind: ErrorKind::Parse,
+                line_col: Some(e.pos.line_col(src)),
+                ..Error::new(format!("Cannot parse:\n{}", e.message))
+            })
+        })?;
+        eval::eval_module(&module, src, self.context(name)).map_err(|e| {
+            with_file(Error {
+                kind: ErrorKind::Eval,
+                line_col: e.pos.map(|p| p.line_col(src)),
+                ..Error::new(e.message)
+            })
+        })
+    }
+
+    /// Loads and evaluates the konfi file at `path`.
+    pub fn eval_file(&self, path: impl AsRef<Path>) -> Result<Val, Error> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path).map_err(|e| Error {
+            kind: ErrorKind::Io,
+            file: Some(path.display().to_string()),
+            ..Error::new(format!("Cannot read file: {e}"))
+        })?;
+        let name = normalize(path).display().to_string();
+        self.eval_source(&src, Some(&name)).map_err(|e| Error {
+            file: Some(path.display().to_string()),
+            ..e
+        })
+    }
+
+    /// Parses, evaluates and deserializes a module.
+    pub fn deserialize_str<T: DeserializeOwned>(&self, src: &str) -> Result<T, Error> {
+        let val = self.eval_str(src)?;
+        T::deserialize(de::Deserializer::new(&val, Some(src)))
+    }
+
+    /// Loads, evaluates and deserializes the konfi file at `path`.
+    pub fn deserialize_file<T: DeserializeOwned>(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<T, Error> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path).map_err(|e| Error {
+            kind: ErrorKind::Io,
+            file: Some(path.display().to_string()),
+            ..Error::new(format!("Cannot read file: {e}"))
+        })?;
+        let name = normalize(path).display().to_string();
+        let val = self.eval_source(&src, Some(&name))?;
+        T::deserialize(de::Deserializer::new(&val, Some(&src))).map_err(|e| Error {
+            file: Some(path.display().to_string()),
+            ..e
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::EvalError;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    // Resolves imports from an in-memory map of sources.
+    struct MapResolver(HashMap<&'static str, &'static str>);
+
+    impl ImportResolver for MapResolver {
+        fn resolve(&self, path: &str, _from: Option<&str>) -> Result<(String, String), String> {
+            match self.0.get(path) {
+                Some(src) => Ok((path.to_string(), src.to_string())),
+                None => Err("not found".to_string()),
+            }
+        }
+    }
+
+    fn err(message: &str) -> EvalError {
+        EvalError {
+            message: message.to_string(),
+            pos: None,
+            failed_asserts: 0,
+        }
+    }
+
+    #[test]
+    fn host_functions_and_globals() {
+        let engine = Engine::builder()
+            .function("double", |args| match args {
+                [Val::Int(n)] => Ok(Val::Int(2 * n)),
+                _ => Err(err("double: expected an int")),
+            })
+            .module(
+                "geo",
+                Module::new().function("zone", |args| match args {
+                    [Val::Str(r)] => Ok(Val::from(format!("{r}-a"))),
+                    _ => Err(err("geo.zone: expected a str")),
+                }),
+            )
+            .global("region", "eu-west")
+            .global("replicas", 3)
+            .build();
+        assert_eq!(engine.eval_str("double(replicas)"), Ok(Val::Int(6)));
+        assert_eq!(
+            engine.eval_str("geo.zone(region)"),
+            Ok(Val::from("eu-west-a"))
+        );
+        // Builtins are still available.
+        assert_eq!(
+            engine.eval_str("str.upper(region)"),
+            Ok(Val::from("EU-WEST"))
+        );
+        let e = engine.eval_str("{\n  x: double(\"a\")\n}").unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Eval);
+        assert_eq!(e.to_string(), "2:3: double: expected an int");
+    }
+
+    #[test]
+    fn typed_results() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Config {
+            region: String,
+            replicas: u32,
+        }
+        let engine = Engine::builder()
+            .global("default_region", "eu-west")
+            .build();
+        let c: Config = engine
+            .deserialize_str("{\n  region: default_region\n  replicas: 2 + 1\n}")
+            .unwrap();
+        assert_eq!(
+            c,
+            Config {
+                region: "eu-west".to_string(),
+                replicas: 3
+            }
+        );
+        let e = engine
+            .deserialize_str::<Config>("{\n  region: 1")
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Parse);
+    }
+
+    #[test]
+    fn imports() {
+        let resolver = MapResolver(HashMap::from([
+            ("base", "{\n  port: 8080\n}"),
+            (
+                "app",
+                "import \"base\" as base\n{\n  port: base.port + 1\n}",
+            ),
+            ("cycle_a", "import \"cycle_b\" as b\nb"),
+            ("cycle_b", "import \"cycle_a\" as a\na"),
+            ("broken", "{\n  x: 1 + true\n}"),
+            (
+                "checks",
+                "let mk_port = n => {\n  p: n\n  assert p > 0\n}\n{port: mk_port}",
+            ),
+        ]));
+        let engine = Engine::builder().import_resolver(resolver).build();
+        assert_eq!(
+            engine.eval_str("import \"app\" as app\napp.port").unwrap(),
+            Val::Int(8081)
+        );
+        assert_eq!(
+            engine
+                .eval_source("import \"cycle_a\" as a\na", Some("main"))
+                .unwrap_err()
+                .message,
+            "cycle_a:1:1: cycle_b:1:1: Import cycle: main -> cycle_a -> cycle_b -> cycle_a"
+        );
+        let e = engine.eval_str("import \"broken\" as b\nb").unwrap_err();
+        assert_eq!(e.line_col, Some((1, 1)));
+        assert!(e.message.starts_with("broken:2:"), "{}", e.message);
+        // Failed assertions are located in the module that defines them.
+        assert_eq!(
+            engine
+                .eval_str("import \"checks\" as c\nc.port(0)")
+                .unwrap_err()
+                .message,
+            "1 assertion(s) failed:\n  3:3: <root>: assertion failed"
+        );
+        let e = engine.eval_str("import \"missing\" as m\nm").unwrap_err();
+        assert_eq!(e.message, "Cannot import \"missing\": not found");
+        // Imports are disabled unless there is a resolver.
+        let e = Engine::new()
+            .eval_str("import \"base\" as b\nb")
+            .unwrap_err();
+        assert_eq!(e.message, "Cannot import \"base\": imports are not enabled");
+    }
+
+    #[test]
+    fn externals() {
+        let engine = Engine::builder()
+            .ext("stage", "prod")
+            .allow_env("KONFI_TEST_VAR")
+            .build();
+        assert_eq!(engine.eval_str("ext(\"stage\")"), Ok(Val::from("prod")));
+        assert_eq!(engine.eval_str("ext(\"replicas\", 2)"), Ok(Val::Int(2)));
+        assert_eq!(
+            engine.eval_str("ext(\"replicas\")").unwrap_err().message,
+            "ext: external variable \"replicas\" is not set"
+        );
+        assert_eq!(
+            engine.eval_str("env(\"KONFI_TEST_VAR\", \"x\")"),
+            Ok(Val::from("x"))
+        );
+        assert_eq!(
+            engine.eval_str("env(\"HOME\")").unwrap_err().message,
+            "env: access to environment variable \"HOME\" is not allowed"
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let limits = Limits {
+            max_depth: 10,
+            ..Default::default()
+        };
+        let engine = Engine::builder().limits(limits).build();
+        assert_eq!(
+            engine.eval_str("[[[1]]]"),
+            Ok(Val::from(vec![Val::from(vec![Val::from(vec![1])])]))
+        );
+        let deep = format!("{}1{}", "[".repeat(20), "]".repeat(20));
+        assert_eq!(
+            engine.eval_str(&deep).unwrap_err().message,
+            "Maximum evaluation depth of 10 exceeded"
+        );
+        // Values built in loops are limited to the same depth.
+        let nested = |n| format!("list.fold(range({n}), 1, (acc, x) => [acc])");
+        assert!(engine.eval_str(&nested(10)).is_ok());
+        assert_eq!(
+            engine.eval_str(&nested(11)).unwrap_err().message,
+            "Values must not be nested more than 10 levels deep"
+        );
+        assert_eq!(
+            engine
+                .eval_str("list.fold(range(11), 1, (acc, x) => {a: acc})")
+                .unwrap_err()
+                .message,
+            "Values must not be nested more than 10 levels deep"
+        );
+        assert_eq!(
+            engine
+                .eval_str("list.fold(range(10), [1], (acc, x) => acc.map(y => [y]))")
+                .unwrap_err()
+                .message,
+            "Values must not be nested more than 10 levels deep"
+        );
+        // Chains of operators and suffixes do not count against the depth.
+        let sum = vec!["1"; 100].join(" + ");
+        assert_eq!(engine.eval_str(&sum), Ok(Val::Int(100)));
+        let fields = format!("nil{}", "?.a".repeat(100));
+        assert_eq!(engine.eval_str(&fields), Ok(Val::Nil));
+        let engine = |limits| Engine::builder().limits(limits).build();
+        let e = engine(Limits {
+            max_steps: Some(3),
+            ..Default::default()
+        });
+        assert_eq!(e.eval_str("1 + 2"), Ok(Val::Int(3)));
+        assert_eq!(
+            e.eval_str("1 + 2 + 3").unwrap_err().message,
+            "Maximum number of 3 evaluation steps exceeded"
+        );
+        let e = engine(Limits {
+            max_size: Some(5),
+            ..Default::default()
+        });
+        assert_eq!(
+            e.eval_str("str.join([\"abc\", \"def\"])")
+                .unwrap_err()
+                .message,
+            "String of 6 bytes exceeds the maximum size of 5 bytes"
+        );
+        assert_eq!(
+            e.eval_str("str.split(\",,,,,\", \",\")")
+                .unwrap_err()
+                .message,
+            "List of 6 elements exceeds the maximum size of 5 elements"
+        );
+        // Builtins check the size of their results before building them.
+        let err = |s: &str| e.eval_str(s).unwrap_err().message;
+        assert_eq!(
+            err("range(6)"),
+            "List of 6 elements exceeds the maximum size of 5 elements"
+        );
+        assert_eq!(
+            err("[[1, 2], [3, 4], 5, 6].flatten()"),
+            "List of 6 elements exceeds the maximum size of 5 elements"
+        );
+        assert_eq!(
+            err("str.format(\"{}{}\", \"abc\", \"def\")"),
+            "String of 6 bytes exceeds the maximum size of 5 bytes"
+        );
+        let e = engine(Limits {
+            max_size: Some(100_000),
+            ..Default::default()
+        });
+        let src = format!(
+            "let a = \"{}\"\nstr.replace(a, \"x\", a)",
+            "x".repeat(100_000)
+        );
+        assert_eq!(
+            e.eval_str(&src).unwrap_err().message,
+            "String of 10000000000 bytes exceeds the maximum size of 100000 bytes"
+        );
+        assert_eq!(
+            e.eval_str("str.replace(\"abc\", \"b\", \"xyz\")"),
+            Ok(Val::from("axyzc"))
+        );
+        assert_eq!(
+            e.eval_str("net.subnets(\"10.0.0.0/8\", 30)")
+                .unwrap_err()
+                .message,
+            "List of 4194304 elements exceeds the maximum size of 100000 elements"
+        );
+        let e = engine(Limits {
+            timeout: Some(Duration::ZERO),
+            ..Default::default()
+        });
+        let many = format!("[{}]", vec!["1 + 1"; 1000].join(", "));
+        assert!(e
+            .eval_str(&many)
+            .unwrap_err()
+            .message
+            .starts_with("Evaluation timed out"));
+    }
+
+    #[test]
+    fn file_imports_stay_below_base_dir() {
+        let resolver = FileResolver::with_reader("/project", |p| Ok(p.display().to_string()));
+        let resolve = |path, from| resolver.resolve(path, from).map(|(name, _)| name);
+        assert_eq!(
+            resolve("lib/a.konfi", Some("/project/main.konfi")),
+            Ok("/project/lib/a.konfi".to_string())
+        );
+        assert_eq!(
+            resolve("../b.konfi", Some("/project/lib/a.konfi")),
+            Ok("/project/b.konfi".to_string())
+        );
+        assert_eq!(
+            resolve("../etc/passwd", Some("/project/main.konfi")),
+            Err("/etc/passwd is outside of the base directory /project".to_string())
+        );
+        assert_eq!(
+            resolve("/etc/passwd", None),
+            Err("/etc/passwd is outside of the base directory /project".to_string())
+        );
+        assert!(resolve("../../x.konfi", None).is_err());
+        assert!(resolve("/projectx/a.konfi", None).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_imports_resolve_symlinks() {
+        let dir = std::env::temp_dir().join(format!("konfi-imports-{}", std::process::id()));
+        let base = dir.join("base");
+        std::fs::create_dir_all(&base).unwrap();
+        std::fs::write(dir.join("secret.konfi"), "1").unwrap();
+        std::fs::write(base.join("ok.konfi"), "2").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.konfi"), base.join("link.konfi")).unwrap();
+        let resolver = FileResolver::new(&base);
+        assert_eq!(resolver.resolve("ok.konfi", None).unwrap().1, "2");
+        let e = resolver.resolve("link.konfi", None).unwrap_err();
+        assert!(e.contains("outside of the base directory"), "{}", e);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(
+            normalize(Path::new("./a/../b/./c.konfi")),
+            PathBuf::from("b/c.konfi")
+        );
+        assert_eq!(
+            normalize(Path::new("../a.konfi")),
+            PathBuf::from("../a.konfi")
+        );
+    }
+}
@@ -1,24 +1,157 @@
 // Evaluate ast::Expr and friends and turn them into actual values.
 
 use crate::ast;
+use crate::builtins;
+use crate::engine::{Limits, Settings};
+use crate::parser;
+use crate::units;
 use chrono::Duration;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
+use std::time::Instant;
 
-type UtcTimestamp = chrono::offset::Utc;
+type UtcTimestamp = chrono::DateTime<chrono::Utc>;
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum Val {
     Nil,
     Rec(Rc<RefCell<Rec>>),
+    List(List),
     Bool(bool),
     Int(i64),
     Double(f64),
     Str(String),
+    Size(u64), // In bytes.
     Timestamp(UtcTimestamp),
     Duration(Duration),
+    NativeFn(NativeFn),
+}
+
+/// The elements of a list, shared by all copies of the list.
+#[derive(Debug, Clone)]
+pub struct List {
+    elems: Rc<[Val]>,
+    // How deeply lists and records are nested in the list, see `Val::depth`.
+    depth: usize,
+}
+
+impl From<Vec<Val>> for List {
+    fn from(elems: Vec<Val>) -> Self {
+        let depth = 1 + elems.iter().map(Val::depth).max().unwrap_or(0);
+        List {
+            elems: elems.into(),
+            depth,
+        }
+    }
+}
+
+impl FromIterator<Val> for List {
+    fn from_iter<I: IntoIterator<Item = Val>>(iter: I) -> Self {
+        iter.into_iter().collect::<Vec<_>>().into()
+    }
+}
+
+impl std::ops::Deref for List {
+    type Target = [Val];
+
+    fn deref(&self) -> &[Val] {
+        &self.elems
+    }
+}
+
+impl PartialEq for List {
+    fn eq(&self, other: &Self) -> bool {
+        self.elems == other.elems
+    }
+}
+
+type NativeFnImpl = dyn Fn(&[Val], &Limits) -> EvalResult<Val>;
+
+/// A function implemented in Rust, e.g. one of the builtins. Besides its
+/// arguments, it is called with the limits of the calling evaluation.
+#[derive(Clone)]
+pub struct NativeFn {
+    pub name: String,
+    pub f: Rc<NativeFnImpl>,
+}
+
+impl NativeFn {
+    pub fn new(name: &str, f: impl Fn(&[Val]) -> EvalResult<Val> + 'static) -> Self {
+        Self::with_limits(name, move |vals, _| f(vals))
+    }
+
+    /// Like `new`, for functions that must keep their results within the
+    /// limits, e.g. because they build large strings or lists.
+    pub fn with_limits(
+        name: &str,
+        f: impl Fn(&[Val], &Limits) -> EvalResult<Val> + 'static,
+    ) -> Self {
+        NativeFn {
+            name: name.to_string(),
+            f: Rc::new(f),
+        }
+    }
+
+    pub fn call(&self, args: &[Val], limits: &Limits) -> EvalResult<Val> {
+        (self.f)(args, limits)
+    }
+}
+
+impl From<bool> for Val {
+    fn from(b: bool) -> Self {
+        Val::Bool(b)
+    }
+}
+
+impl From<i64> for Val {
+    fn from(n: i64) -> Self {
+        Val::Int(n)
+    }
+}
+
+impl From<f64> for Val {
+    fn from(d: f64) -> Self {
+        Val::Double(d)
+    }
+}
+
+impl From<&str> for Val {
+    fn from(s: &str) -> Self {
+        Val::Str(s.to_string())
+    }
+}
+
+impl From<String> for Val {
+    fn from(s: String) -> Self {
+        Val::Str(s)
+    }
+}
+
+impl From<Duration> for Val {
+    fn from(d: Duration) -> Self {
+        Val::Duration(d)
+    }
+}
+
+impl<T: Into<Val>> From<Vec<T>> for Val {
+    fn from(l: Vec<T>) -> Self {
+        Val::List(l.into_iter().map(Into::into).collect())
+    }
+}
+
+impl PartialEq for NativeFn {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.f, &other.f)
+    }
+}
+
+impl std::fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NativeFn({})", self.name)
+    }
 }
 
 impl Val {
@@ -26,12 +159,64 @@ impl Val {
         match self {
             Val::Nil => "nil",
             Val::Rec(_) => "rec",
+            Val::List(_) => "list",
             Val::Int(_) => "int",
             Val::Double(_) => "double",
             Val::Str(_) => "str",
+            Val::Size(_) => "size",
             Val::Timestamp(_) => "timestamp",
             Val::Duration(_) => "duration",
             Val::Bool(_) => "bool",
+            Val::NativeFn(_) => "fn",
+        }
+    }
+
+    // Selects a sub-value by its path, e.g. "servers.web.ports[0]".
+    // The empty path selects the value itself.
+    pub fn select(&self, path: &str) -> EvalResult<Val> {
+        let elems = parse_path(path).map_err(|message| EvalError {
+            message,
+            pos: None,
+            failed_asserts: 0,
+        })?;
+        let mut v = self.clone();
+        let mut prefix = String::new();
+        for elem in elems {
+            v = match (&elem, &v) {
+                (PathElem::Field(f), Val::Rec(r)) => r.borrow().getattr(f),
+                (PathElem::Index(i), Val::List(l)) => l.get(*i).cloned(),
+                (PathElem::Field(_), _) | (PathElem::Index(_), _) => {
+                    return Err(EvalError {
+                        message: format!(
+                            "Cannot select {} from value of type '{}' at '{}'",
+                            elem,
+                            v.typ(),
+                            prefix
+                        ),
+                        pos: None,
+                        failed_asserts: 0,
+                    })
+                }
+            }
+            .ok_or_else(|| EvalError {
+                message: format!("Path '{}{}' does not exist", prefix, elem),
+                pos: None,
+                failed_asserts: 0,
+            })?;
+            prefix.push_str(&elem.to_string());
+        }
+        Ok(v)
+    }
+
+    /// How deeply lists and records are nested in the value: 1 plus the
+    /// depth of its deepest element or field for lists and records, and 0
+    /// for all other values. The depth is stored with lists and records when
+    /// they are built, so this does not traverse the value.
+    pub fn depth(&self) -> usize {
+        match self {
+            Val::List(l) => l.depth,
+            Val::Rec(r) => 1 + r.borrow().field_depth,
+            _ => 0,
         }
     }
 
@@ -39,12 +224,15 @@ impl Val {
         match self {
             Val::Nil => false,
             Val::Rec(r) => !r.borrow().is_empty(),
+            Val::List(l) => !l.is_empty(),
             Val::Bool(b) => *b,
             Val::Int(i) => *i != 0,
             Val::Double(d) => *d != 0.0,
             Val::Str(s) => !s.is_empty(),
-            Val::Timestamp(_) => todo!(),
-            Val::Duration(_) => todo!(),
+            Val::Size(s) => *s != 0,
+            Val::Timestamp(_) => true,
+            Val::Duration(d) => !d.is_zero(),
+            Val::NativeFn(_) => true,
         }
     }
 }
@@ -53,78 +241,437 @@ impl Display for Val {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Val::Nil => write!(f, "nil"),
-            Val::Rec(_) => todo!(),
+            Val::Rec(r) => {
+                let r = r.borrow();
+                let mut fields: Vec<_> = r.fields.iter().collect();
+                fields.sort_by_key(|(name, _)| *name);
+                write!(f, "{{")?;
+                for (i, (name, v)) in fields.into_iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    let sep = if r.is_hidden(name) { "::" } else { ":" };
+                    if parser::is_ident(name) {
+                        write!(f, "{name}{sep} {v}")?;
+                    } else {
+                        write!(f, "\"{name}\"{sep} {v}")?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            Val::List(l) => {
+                write!(f, "[")?;
+                for (i, v) in l.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{v}")?;
+                }
+                write!(f, "]")
+            }
             Val::Bool(b) => write!(f, "{b}"),
             Val::Int(i) => write!(f, "{i}"),
             Val::Double(d) => write!(f, "{d}"),
             Val::Str(s) => write!(f, "\"{s}\""),
-            Val::Timestamp(_) => todo!(),
-            Val::Duration(_) => todo!(),
+            Val::Size(s) => write!(f, "{}", units::format_exact(*s)),
+            Val::Timestamp(t) => write!(f, "{}", format_timestamp(t)),
+            Val::Duration(d) => write!(f, "{}", units::format_duration(nanos(d))),
+            Val::NativeFn(nf) => write!(f, "<fn {}>", nf.name),
         }
     }
 }
 
-#[derive(PartialEq, Debug)]
+/// An element of a path to a sub-value, see `Val::select`.
+#[derive(Debug, PartialEq)]
+pub enum PathElem {
+    Field(String),
+    Index(usize),
+}
+
+impl Display for PathElem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathElem::Field(name) if parser::is_ident(name) => write!(f, ".{name}"),
+            PathElem::Field(name) => write!(f, "[\"{name}\"]"),
+            PathElem::Index(i) => write!(f, "[{i}]"),
+        }
+    }
+}
+
+/// Parses a path like "servers.web.ports[0]" into its elements. Field names
+/// that are not identifiers are written in brackets, e.g. `labels["app.kubernetes.io/name"]`.
+pub fn parse_path(path: &str) -> Result<Vec<PathElem>, String> {
+    let mut elems = Vec::new();
+    let mut rest = path.trim();
+    let mut first = true;
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("[\"") {
+            let Some((name, r)) = r.split_once("\"]") else {
+                return Err(format!("Missing '\"]' in path '{path}'"));
+            };
+            elems.push(PathElem::Field(name.to_string()));
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let Some((idx, r)) = r.split_once(']') else {
+                return Err(format!("Missing ']' in path '{path}'"));
+            };
+            match idx.trim().parse::<usize>() {
+                Ok(i) => elems.push(PathElem::Index(i)),
+                Err(_) => return Err(format!("Invalid index '{idx}' in path '{path}'")),
+            }
+            rest = r;
+        } else {
+            let r = match rest.strip_prefix('.') {
+                Some(r) => r,
+                None if first => rest,
+                None => return Err(format!("Expected '.' or '[' in path '{path}'")),
+            };
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            if end == 0 {
+                return Err(format!("Empty field name in path '{path}'"));
+            }
+            elems.push(PathElem::Field(r[..end].to_string()));
+            rest = &r[end..];
+        }
+        first = false;
+    }
+    Ok(elems)
+}
+
+#[derive(Debug, Default)]
 pub struct Rec {
     pub fields: HashMap<String, Val>,
+    // Source positions of the field definitions that produced each value.
+    pub locs: HashMap<String, ast::Pos>,
+    // Names of the fields that are hidden from the output, see `ast::Field`.
+    pub hidden: HashSet<String>,
+    // Where the value of each field came from.
+    pub provenance: HashMap<String, Rc<Provenance>>,
+    // The depth of the deepest field value, see `Val::depth`.
+    field_depth: usize,
+}
+
+/// Where the value of a field was defined and which fields it was computed from.
+#[derive(Debug)]
+pub struct Provenance {
+    pub name: String,
+    /// Name of the module that defines the field, if it has one.
+    pub module: Option<String>,
+    pub pos: ast::Pos,
+    /// The fields that were read to compute the value, in the order of their first use.
+    pub deps: Vec<Dep>,
+}
+
+/// A field that another field depends on. Dependencies only refer to fields
+/// that were evaluated before, so they never form cycles, but they do not
+/// keep the record of the field alive.
+#[derive(Debug, Clone)]
+pub struct Dep {
+    pub rec: Weak<RefCell<Rec>>,
+    pub prov: Rc<Provenance>,
+}
+
+// Records are equal if their fields are equal, regardless of where they were defined.
+impl PartialEq for Rec {
+    fn eq(&self, other: &Self) -> bool {
+        self.fields == other.fields
+    }
 }
 
 impl Rec {
     pub fn new() -> Self {
-        Rec {
-            fields: HashMap::new(),
-        }
+        Rec::default()
     }
     pub fn getattr(&self, f: &str) -> Option<Val> {
-        self.fields.get(f).map(|v| v.clone())
+        self.fields.get(f).cloned()
     }
     pub fn setattr(&mut self, f: &str, val: Val) {
+        self.field_depth = self.field_depth.max(val.depth());
         self.fields.insert(f.to_string(), val);
     }
+    pub fn getloc(&self, f: &str) -> Option<ast::Pos> {
+        self.locs.get(f).copied()
+    }
+    pub fn setloc(&mut self, f: &str, pos: ast::Pos) {
+        self.locs.insert(f.to_string(), pos);
+    }
     pub fn is_empty(&self) -> bool {
         self.fields.is_empty()
     }
+    pub fn is_hidden(&self, f: &str) -> bool {
+        self.hidden.contains(f)
+    }
+    pub fn hide(&mut self, f: &str) {
+        self.hidden.insert(f.to_string());
+    }
+    pub fn provenance(&self, f: &str) -> Option<Rc<Provenance>> {
+        self.provenance.get(f).cloned()
+    }
+    /// The fields that are part of the output, i.e. not hidden, in no particular order.
+    pub fn visible(&self) -> impl Iterator<Item = (&String, &Val)> {
+        self.fields.iter().filter(|(f, _)| !self.is_hidden(f))
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub struct EvalError {
     pub message: String,
+    // Location of the innermost field whose evaluation failed, if known.
+    pub pos: Option<ast::Pos>,
+    // The number of failed assertions, if the evaluation failed because of them.
+    pub failed_asserts: usize,
 }
 
-type EvalResult<T> = Result<T, EvalError>;
+impl EvalError {
+    // Attaches `pos` to the error, unless it already has a (more specific) position.
+    pub fn at(mut self, pos: ast::Pos) -> Self {
+        self.pos.get_or_insert(pos);
+        self
+    }
+}
+
+// Like ast::Pos, error positions do not take part in equality.
+impl PartialEq for EvalError {
+    fn eq(&self, other: &Self) -> bool {
+        self.message == other.message
+    }
+}
+
+pub type EvalResult<T> = Result<T, EvalError>;
+
+/// Settings and state of one evaluation, shared by all of its contexts.
+#[derive(Default)]
+pub struct Env {
+    settings: Rc<Settings>,
+    // Current nesting depth of `eval`.
+    depth: Cell<usize>,
+    // Number of `eval` steps so far.
+    steps: Cell<u64>,
+    // When the evaluation times out, if it has a timeout.
+    deadline: Option<Instant>,
+    // Names of the modules currently being evaluated, innermost last.
+    modules: RefCell<Vec<String>>,
+    // Values of all modules imported so far, by name.
+    imported: RefCell<HashMap<String, Val>>,
+    // Failed assertions that have not been reported yet, see `checked`.
+    failures: RefCell<Vec<AssertFailure>>,
+    // Sources of the modules and functions under evaluation, innermost last,
+    // to resolve the positions of failed assertions.
+    sources: RefCell<Vec<Rc<str>>>,
+    // The fields read so far by each field under evaluation, innermost last.
+    deps: RefCell<Vec<Vec<Dep>>>,
+    // The fields under evaluation and their records, innermost last.
+    fields: RefCell<Vec<(Rc<RefCell<Rec>>, String)>>,
+}
+
+// A failed assertion of a record or module.
+struct AssertFailure {
+    message: String,
+    pos: ast::Pos,
+    // The source that `pos` refers to, if known.
+    src: Option<Rc<str>>,
+    // The record whose assertion failed, to find its path in the result.
+    rec: Option<Rc<RefCell<Rec>>>,
+}
+
+impl Env {
+    /// Creates an environment for evaluating the module called `name`.
+    /// The name is passed to the import resolver to resolve relative imports.
+    pub(crate) fn new(settings: Rc<Settings>, name: Option<&str>) -> Self {
+        Env {
+            deadline: settings.limits.timeout.map(|t| Instant::now() + t),
+            settings,
+            modules: RefCell::new(name.map(|n| vec![n.to_string()]).unwrap_or_default()),
+            ..Default::default()
+        }
+    }
+
+    // Accounts for one evaluation step, checking the depth, step and time limits.
+    fn enter(env: &Rc<Env>) -> EvalResult<DepthGuard> {
+        let limits = &env.settings.limits;
+        let err = |message: String| {
+            Err(EvalError {
+                message,
+                pos: None,
+                failed_asserts: 0,
+            })
+        };
+        let depth = env.depth.get() + 1;
+        if depth > limits.max_depth {
+            return err(format!(
+                "Maximum evaluation depth of {} exceeded",
+                limits.max_depth
+            ));
+        }
+        let steps = env.steps.get() + 1;
+        if let Some(max) = limits.max_steps.filter(|max| steps > *max) {
+            return err(format!("Maximum number of {max} evaluation steps exceeded"));
+        }
+        if let (Some(deadline), Some(timeout)) = (env.deadline, limits.timeout) {
+            if Instant::now() > deadline {
+                return err(format!("Evaluation timed out after {:?}", timeout));
+            }
+        }
+        env.depth.set(depth);
+        env.steps.set(steps);
+        Ok(DepthGuard(Rc::clone(env)))
+    }
+
+    // Runs `f` with `src` as the source of the code that it evaluates.
+    fn with_source<T>(&self, src: Rc<str>, f: impl FnOnce() -> T) -> T {
+        self.sources.borrow_mut().push(src);
+        let r = f();
+        self.sources.borrow_mut().pop();
+        r
+    }
+
+    // Records that the field under evaluation, if any, reads the field `name` of `rec`.
+    fn read_field(&self, rec: &Rc<RefCell<Rec>>, name: &str) {
+        let Some(prov) = rec.borrow().provenance(name) else {
+            // Fields from builtins or comprehensions have no provenance.
+            return;
+        };
+        if let Some(deps) = self.deps.borrow_mut().last_mut() {
+            if !deps.iter().any(|d| Rc::ptr_eq(&d.prov, &prov)) {
+                deps.push(Dep {
+                    rec: Rc::downgrade(rec),
+                    prov,
+                });
+            }
+        }
+    }
+
+    // Checks the value `v` of the expression `e` against the limits.
+    fn check_value(&self, e: &ast::Expr, v: Val) -> EvalResult<Val> {
+        // Only these expressions can nest values more deeply than their operands.
+        if matches!(
+            e,
+            ast::Expr::List(_)
+                | ast::Expr::ListComp(..)
+                | ast::Expr::Rec(_)
+                | ast::Expr::RecComp(..)
+                | ast::Expr::Call(_)
+        ) {
+            self.check_nesting(&v)?;
+        }
+        self.check_size(v)
+    }
+
+    // Checks that the lists and records in `v` are not nested more deeply
+    // than the maximum depth. Dropping, printing or serializing a value
+    // recurses into it, so deeper values could overflow the stack.
+    fn check_nesting(&self, v: &Val) -> EvalResult<()> {
+        let max = self.settings.limits.max_depth;
+        if v.depth() > max {
+            return Err(EvalError {
+                message: format!("Values must not be nested more than {max} levels deep"),
+                pos: None,
+                failed_asserts: 0,
+            });
+        }
+        Ok(())
+    }
+
+    // Checks that `v` does not exceed the maximum size of strings and lists.
+    fn check_size(&self, v: Val) -> EvalResult<Val> {
+        let limits = &self.settings.limits;
+        match &v {
+            Val::Str(s) => limits.check_str_len(s.len())?,
+            Val::List(l) => limits.check_list_len(l.len())?,
+            _ => {}
+        }
+        Ok(v)
+    }
+}
+
+// Decrements the evaluation depth when dropped.
+struct DepthGuard(Rc<Env>);
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        self.0.depth.set(self.0.depth.get() - 1);
+    }
+}
+
+// What a variable name refers to in a context, see `Ctx::binding`.
+enum Binding<'a> {
+    // A let binding or an evaluated field, with the record of the field.
+    Val(Val, Option<Rc<RefCell<Rec>>>),
+    // A field that has not been evaluated yet, with the context of its record.
+    Field(Rc<Ctx<'a>>, &'a ast::Field),
+}
 
 // Evaluation context.
 pub struct Ctx<'a> {
     rec: Rc<RefCell<Rec>>,
-    rec_expr: &'a ast::Rec,
+    // The record expression that `rec` is evaluated from, if any.
+    rec_expr: Option<&'a ast::Rec>,
+    // Variables introduced by let bindings in this scope.
+    vars: RefCell<HashMap<String, Val>>,
     parent: Option<Rc<Ctx<'a>>>,
+    env: Rc<Env>,
 }
 
-static GLOBAL_DUMMY_REC: ast::Rec = ast::Rec {
-    let_vars: vec![],
-    fields: vec![],
-};
-
 impl<'a> Ctx<'a> {
+    // The global context, which holds all builtins, in a default environment.
     pub fn global() -> Rc<Ctx<'a>> {
-        Rc::new(Ctx {
+        Self::root(Rc::new(Env::default()))
+    }
+    // The global context of a module evaluated in `env`: all builtins,
+    // ext() and env(), and the host's globals.
+    pub fn root(env: Rc<Env>) -> Rc<Ctx<'a>> {
+        let ctx = Ctx {
             rec: Rc::new(RefCell::new(Rec::new())),
-            rec_expr: &GLOBAL_DUMMY_REC,
+            rec_expr: None,
+            vars: RefCell::new(HashMap::new()),
             parent: None,
-        })
+            env,
+        };
+        builtins::register(&ctx);
+        builtins::register_externals(&ctx, &ctx.env.settings);
+        for (name, val) in ctx.env.settings.globals.iter() {
+            ctx.setvar(name, val.clone());
+        }
+        Rc::new(ctx)
     }
     pub fn child_of(parent: Rc<Ctx<'a>>, r: Rc<RefCell<Rec>>, re: &'a ast::Rec) -> Rc<Ctx<'a>> {
+        Self::child(parent, r, Some(re))
+    }
+    fn child(parent: Rc<Ctx<'a>>, r: Rc<RefCell<Rec>>, re: Option<&'a ast::Rec>) -> Rc<Ctx<'a>> {
         Rc::new(Ctx {
             rec: r,
             rec_expr: re,
+            vars: RefCell::new(HashMap::new()),
+            env: Rc::clone(&parent.env),
             parent: Some(parent),
         })
     }
+    // A context without a parent that holds just `vars`, see `lambda`.
+    fn closure(env: Rc<Env>, vars: HashMap<String, Val>) -> Rc<Ctx<'a>> {
+        Rc::new(Ctx {
+            rec: Rc::new(RefCell::new(Rec::new())),
+            rec_expr: None,
+            vars: RefCell::new(vars),
+            parent: None,
+            env,
+        })
+    }
+    // A scope without record fields, used to hold let bindings.
+    pub fn scope_of(parent: Rc<Ctx<'a>>) -> Rc<Ctx<'a>> {
+        Self::child(parent, Rc::new(RefCell::new(Rec::new())), None)
+    }
+
+    pub fn setvar(&self, var: &str, val: Val) {
+        self.vars.borrow_mut().insert(var.to_string(), val);
+    }
 
     pub fn getval(&self, var: &str) -> Option<Val> {
         let mut c = self;
         loop {
-            if let Some(v) = self.rec.borrow().getattr(var) {
+            if let Some(v) = c.vars.borrow().get(var) {
+                return Some(v.clone());
+            }
+            if let Some(v) = c.rec.borrow().getattr(var) {
                 return Some(v);
             }
             if let Some(p) = &c.parent {
@@ -136,121 +683,765 @@ impl<'a> Ctx<'a> {
     }
 
     fn getfield(&self, field: &str) -> Option<&'a ast::Field> {
-        return self.rec_expr.fields.iter().find(|&fld| fld.name == field);
+        self.rec_expr?.fields.iter().find(|&fld| fld.name == field)
     }
 
-    pub fn for_var(ctx: Rc<Ctx<'a>>, field: &str) -> Option<(Rc<Ctx<'a>>, &'a ast::Field)> {
-        if let Some(f) = ctx.getfield(field) {
-            return Some((ctx, f));
-        }
-        match &ctx.parent {
-            Some(p) => Self::for_var(Rc::clone(p), field),
-            None => None,
+    // Resolves `var` one scope at a time, from the inside out. In each scope,
+    // let bindings come before fields, whether or not those are evaluated yet.
+    fn binding(ctx: Rc<Ctx<'a>>, var: &str) -> Option<Binding<'a>> {
+        let mut c = ctx;
+        loop {
+            if let Some(v) = c.vars.borrow().get(var) {
+                return Some(Binding::Val(v.clone(), None));
+            }
+            if let Some(v) = c.rec.borrow().getattr(var) {
+                return Some(Binding::Val(v, Some(Rc::clone(&c.rec))));
+            }
+            if let Some(f) = c.getfield(var) {
+                return Some(Binding::Field(c, f));
+            }
+            c = Rc::clone(c.parent.as_ref()?);
         }
     }
 }
 
 macro_rules! numeric_binexpr {
-    ($lv:expr, $op:tt, $rv:expr) => {
+    ($binop:expr, $lv:expr, $op:tt, $rv:expr) => {
         match (&$lv, &$rv) {
-            (Val::Int(a), Val::Int(b)) => Ok(Val::Int(a $op b)),
+            (Val::Int(a), Val::Int(b)) => int_binexpr(*a, $binop, *b),
             (Val::Int(a), Val::Double(b)) => Ok(Val::Double((*a as f64) $op b)),
             (Val::Double(a), Val::Int(b)) => Ok(Val::Double(a $op (*b as f64))),
             (Val::Double(a), Val::Double(b)) => Ok(Val::Double(a $op b)),
             (_, _) => Err(EvalError {
                 message: format!("Invalid types for arithmetic operation '{}': {} and {}",
                     stringify!($op), $lv.typ(), $rv.typ()),
+                pos: None, failed_asserts: 0,
             }),
         }
     };
 }
 
-macro_rules! comp_expr {
-    ($lv:expr, $op:tt, $rv:expr) => {
-        match (&$lv, &$rv) {
-            (Val::Int(a), Val::Int(b)) => Ok(Val::Bool(*a $op *b)),
-            (Val::Int(a), Val::Double(b)) => Ok(Val::Bool((*a as f64) $op *b)),
-            (Val::Double(a), Val::Int(b)) => Ok(Val::Bool(*a $op (*b as f64))),
-            (Val::Double(a), Val::Double(b)) => Ok(Val::Bool(*a $op *b)),
-            (Val::Str(a), Val::Str(b)) => Ok(Val::Bool(a $op b)),
-            (Val::Bool(a), Val::Bool(b)) => Ok(Val::Bool(*a $op *b)),
-            (_, _) => Err(EvalError {
-                message: format!("Invalid types for arithmetic operation '{}': {} and {}",
-                    stringify!($op), $lv.typ(), $rv.typ()),
-            }),
+// Arithmetic on ints, which fails rather than overflows.
+fn int_binexpr(a: i64, op: ast::BinOp, b: i64) -> EvalResult<Val> {
+    use ast::BinOp::*;
+    let shift = |f: fn(i64, u32) -> Option<i64>| u32::try_from(b).ok().and_then(|b| f(a, b));
+    let r = match op {
+        Plus => a.checked_add(b),
+        Minus => a.checked_sub(b),
+        Times => a.checked_mul(b),
+        Div => a.checked_div(b),
+        ShiftLeft => shift(i64::checked_shl),
+        ShiftRight => shift(i64::checked_shr),
+        _ => unreachable!("{} is not an arithmetic operator", op.symbol()),
+    };
+    r.map(Val::Int).ok_or_else(|| {
+        let message = match op {
+            Div if b == 0 => "Division by zero".to_string(),
+            ShiftLeft | ShiftRight => format!(
+                "Shift amount must be between 0 and 63 in {a} {} {b}",
+                op.symbol()
+            ),
+            _ => format!("Integer overflow in {a} {} {b}", op.symbol()),
+        };
+        EvalError {
+            message,
+            pos: None,
+            failed_asserts: 0,
+        }
+    })
+}
+
+/// Deep equality of values, as in `a == b`. Ints and doubles are equal if
+/// they have the same numeric value, records if their visible fields are
+/// equal, and values of different types are never equal.
+pub fn equal(a: &Val, b: &Val) -> bool {
+    match (a, b) {
+        (Val::Int(x), Val::Double(y)) | (Val::Double(y), Val::Int(x)) => *x as f64 == *y,
+        (Val::List(x), Val::List(y)) => {
+            x.len() == y.len() && x.iter().zip(y.iter()).all(|(a, b)| equal(a, b))
+        }
+        (Val::Rec(x), Val::Rec(y)) => {
+            let (x, y) = (x.borrow(), y.borrow());
+            x.visible().count() == y.visible().count()
+                && x.visible()
+                    .all(|(f, v)| !y.is_hidden(f) && y.fields.get(f).is_some_and(|w| equal(v, w)))
+        }
+        _ => a == b,
+    }
+}
+
+/// The order of two values, as in `a < b`. Lists are ordered
+/// lexicographically. Returns None for values of different types (except
+/// ints and doubles), records, functions and NaN.
+pub fn compare(a: &Val, b: &Val) -> Option<Ordering> {
+    match (a, b) {
+        (Val::Int(x), Val::Int(y)) => Some(x.cmp(y)),
+        (Val::Int(x), Val::Double(y)) => compare_int_double(*x, *y),
+        (Val::Double(x), Val::Int(y)) => compare_int_double(*y, *x).map(Ordering::reverse),
+        (Val::Double(x), Val::Double(y)) => x.partial_cmp(y),
+        (Val::Str(x), Val::Str(y)) => Some(x.cmp(y)),
+        (Val::Bool(x), Val::Bool(y)) => Some(x.cmp(y)),
+        (Val::Size(x), Val::Size(y)) => Some(x.cmp(y)),
+        (Val::Duration(x), Val::Duration(y)) => Some(x.cmp(y)),
+        (Val::Timestamp(x), Val::Timestamp(y)) => Some(x.cmp(y)),
+        (Val::List(x), Val::List(y)) => {
+            for (a, b) in x.iter().zip(y.iter()) {
+                match compare(a, b)? {
+                    Ordering::Equal => {}
+                    o => return Some(o),
+                }
+            }
+            Some(x.len().cmp(&y.len()))
+        }
+        _ => None,
+    }
+}
+
+// Compares an int with a double exactly. Converting the int to a double
+// would round large ints, which makes the order inconsistent.
+fn compare_int_double(i: i64, d: f64) -> Option<Ordering> {
+    // The range of i64 is [-2^63, 2^63).
+    const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+    if d.is_nan() {
+        None
+    } else if d >= LIMIT {
+        Some(Ordering::Less)
+    } else if d < -LIMIT {
+        Some(Ordering::Greater)
+    } else {
+        let t = d.trunc();
+        Some(i.cmp(&(t as i64)).then(0f64.total_cmp(&(d - t))))
+    }
+}
+
+// Evaluates the comparison `lv op rv`, where `test` tells if the order of
+// the operands satisfies `op`.
+fn ordered(op: ast::BinOp, lv: &Val, rv: &Val, test: fn(Ordering) -> bool) -> EvalResult<Val> {
+    let number = |v: &Val| matches!(v, Val::Int(_) | Val::Double(_));
+    match compare(lv, rv) {
+        Some(o) => Ok(Val::Bool(test(o))),
+        // NaN is not ordered, so every comparison with it is false.
+        None if number(lv) && number(rv) => Ok(Val::Bool(false)),
+        None => Err(EvalError {
+            message: format!(
+                "Invalid types for comparison '{}': {} and {}",
+                op.symbol(),
+                lv.typ(),
+                rv.typ()
+            ),
+            pos: None,
+            failed_asserts: 0,
+        }),
+    }
+}
+
+// Arithmetic on sizes. Returns None if neither operand is a size.
+fn size_binexpr(op: ast::BinOp, lv: &Val, rv: &Val) -> Option<EvalResult<Val>> {
+    use ast::BinOp::*;
+    if !matches!(op, Plus | Minus | Times | Div)
+        || !(matches!(lv, Val::Size(_)) || matches!(rv, Val::Size(_)))
+    {
+        return None;
+    }
+    let err = |message: String| {
+        Err(EvalError {
+            message,
+            pos: None,
+            failed_asserts: 0,
+        })
+    };
+    let overflow = || err(format!("Size overflow in {} {} {}", lv, op.symbol(), rv));
+    let r = match (op, lv, rv) {
+        (Plus, Val::Size(a), Val::Size(b)) => a
+            .checked_add(*b)
+            .map_or_else(overflow, |s| Ok(Val::Size(s))),
+        (Minus, Val::Size(a), Val::Size(b)) => match a.checked_sub(*b) {
+            Some(s) => Ok(Val::Size(s)),
+            None => err(format!("Negative size in {} - {}", lv, rv)),
+        },
+        (Times, Val::Size(a), Val::Int(b)) | (Times, Val::Int(b), Val::Size(a)) => {
+            match u64::try_from(*b) {
+                Ok(b) => a.checked_mul(b).map_or_else(overflow, |s| Ok(Val::Size(s))),
+                Err(_) => err(format!("Cannot multiply size by negative int {}", b)),
+            }
+        }
+        (Div, Val::Size(a), Val::Int(b)) => match u64::try_from(*b) {
+            Ok(b) if b > 0 => Ok(Val::Size(a / b)),
+            _ => err(format!("Cannot divide size by non-positive int {}", b)),
+        },
+        // The ratio of two sizes is a plain number.
+        (Div, Val::Size(a), Val::Size(b)) => match b {
+            0 => err("Division by zero size".to_string()),
+            _ => Ok(Val::Double(*a as f64 / *b as f64)),
+        },
+        _ => err(format!(
+            "Invalid types for arithmetic operation '{}': {} and {} \
+            (use size.bytes to turn an int into a size, size.as_bytes for the reverse)",
+            op.symbol(),
+            lv.typ(),
+            rv.typ()
+        )),
+    };
+    Some(r)
+}
+
+// The nanoseconds of a duration. All durations are created from nanoseconds
+// (see duration_binexpr), so this never overflows.
+pub fn nanos(d: &Duration) -> i64 {
+    d.num_nanoseconds().unwrap_or(i64::MAX)
+}
+
+// Timestamps are written in RFC 3339 format, e.g. "2024-05-01T12:00:00Z".
+pub fn format_timestamp(t: &UtcTimestamp) -> String {
+    t.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
+}
+
+// Arithmetic on durations. Returns None if neither operand is a duration.
+fn duration_binexpr(op: ast::BinOp, lv: &Val, rv: &Val) -> Option<EvalResult<Val>> {
+    use ast::BinOp::*;
+    if !matches!(op, Plus | Minus | Times | Div)
+        || !(matches!(lv, Val::Duration(_)) || matches!(rv, Val::Duration(_)))
+    {
+        return None;
+    }
+    let err = |message: String| {
+        Err(EvalError {
+            message,
+            pos: None,
+            failed_asserts: 0,
+        })
+    };
+    let result = |d: Option<i64>| match d {
+        Some(d) => Ok(Val::Duration(Duration::nanoseconds(d))),
+        None => err(format!(
+            "Duration overflow in {} {} {}",
+            lv,
+            op.symbol(),
+            rv
+        )),
+    };
+    let r = match (op, lv, rv) {
+        (Plus, Val::Duration(a), Val::Duration(b)) => result(nanos(a).checked_add(nanos(b))),
+        (Minus, Val::Duration(a), Val::Duration(b)) => result(nanos(a).checked_sub(nanos(b))),
+        (Times, Val::Duration(a), Val::Int(b)) | (Times, Val::Int(b), Val::Duration(a)) => {
+            result(nanos(a).checked_mul(*b))
         }
+        (Div, Val::Duration(a), Val::Int(b)) => match b {
+            0 => err("Division of duration by zero".to_string()),
+            _ => result(nanos(a).checked_div(*b)),
+        },
+        // The ratio of two durations is a plain number.
+        (Div, Val::Duration(a), Val::Duration(b)) => match nanos(b) {
+            0 => err("Division by zero duration".to_string()),
+            b => Ok(Val::Double(nanos(a) as f64 / b as f64)),
+        },
+        _ => err(format!(
+            "Invalid types for arithmetic operation '{}': {} and {}",
+            op.symbol(),
+            lv.typ(),
+            rv.typ()
+        )),
     };
+    Some(r)
+}
+
+// Field access `v.f` on a value other than a record: the method `f` of `v`.
+fn method(v: &Val, f: &str) -> EvalResult<Val> {
+    if let Some(m) = builtins::method(v, f) {
+        return Ok(m);
+    }
+    let names = builtins::method_names(v.typ());
+    let message = if names.is_empty() {
+        format!("Invalid field access on value type '{}'", v.typ())
+    } else {
+        format!(
+            "No method '{}' for type '{}'. Available methods: {}",
+            f,
+            v.typ(),
+            names.join(", ")
+        )
+    };
+    Err(EvalError {
+        message,
+        pos: None,
+        failed_asserts: 0,
+    })
 }
 
 pub fn eval(e: &ast::Expr, ctx: Rc<Ctx>) -> EvalResult<Val> {
+    let env = Rc::clone(&ctx.env);
+    let _depth = Env::enter(&env)?;
+    env.check_value(e, eval_expr(e, ctx)?)
+}
+
+fn eval_expr(e: &ast::Expr, ctx: Rc<Ctx>) -> EvalResult<Val> {
     match e {
         ast::Expr::Literal(i) => match i {
             ast::Literal::Nil => Ok(Val::Nil),
             ast::Literal::Int(i) => Ok(Val::Int(*i)),
             ast::Literal::Double(d) => Ok(Val::Double(*d)),
             ast::Literal::Str(s) => Ok(Val::Str(s.clone())),
+            ast::Literal::Size(s) => Ok(Val::Size(*s)),
+            ast::Literal::Duration(d) => Ok(Val::Duration(Duration::nanoseconds(*d))),
         },
-        ast::Expr::Var(v) => match ctx.getval(&v.name) {
-            Some(r) => Ok(r),
-            None => match Ctx::for_var(ctx, &v.name) {
-                Some((ctx2, fld)) => {
-                    // Evaluate `fld`, store its value, and return it.
-                    eval_field(fld, Rc::clone(&ctx2))
-                }
-                None => Err(EvalError {
-                    message: format!("Unbound variable '{}'", v.name),
-                }),
-            },
-        },
-        ast::Expr::FieldAcc(re, f) => match eval(re, ctx)? {
-            Val::Rec(r) => r.borrow().getattr(f).ok_or_else(|| EvalError {
-                message: format!("Field does not exist '{}'", f),
-            }),
-            v => Err(EvalError {
-                message: format!("Invalid field access on value type '{}'", v.typ()),
-            }),
-        },
+        ast::Expr::Var(v) => lookup(&v.name, ctx),
+        ast::Expr::FieldAcc(..)
+        | ast::Expr::OptFieldAcc(..)
+        | ast::Expr::Index(..)
+        | ast::Expr::Call(_) => eval_suffixes(e, ctx),
         ast::Expr::UnExpr(op, e) => {
             let val = eval(e, Rc::clone(&ctx))?;
             match op {
                 ast::UnOp::UnPlus => Ok(val),
                 ast::UnOp::UnMinus => match &val {
-                    Val::Int(i) => Ok(Val::Int(-i)),
+                    Val::Int(i) => i.checked_neg().map(Val::Int).ok_or_else(|| EvalError {
+                        message: format!("Integer overflow in -({i})"),
+                        pos: None,
+                        failed_asserts: 0,
+                    }),
                     Val::Double(d) => Ok(Val::Double(-d)),
+                    Val::Duration(d) => Ok(Val::Duration(-*d)),
                     _ => Err(EvalError {
                         message: format!("Cannot apply unary minus to type '{}'", val.typ()),
+                        pos: None,
+                        failed_asserts: 0,
                     }),
                 },
                 ast::UnOp::Not => Ok(Val::Bool(!val.to_bool())),
             }
         }
-        ast::Expr::BinExpr(le, op, re) => {
-            let lv = eval(le, Rc::clone(&ctx))?;
-            // Let's make && || lazy later. For now all ops are eager.
-            let rv = eval(re, ctx)?;
-            match op {
-                ast::BinOp::Times => numeric_binexpr!(lv, *, rv),
-                ast::BinOp::Div => numeric_binexpr!(lv, /, rv),
-                ast::BinOp::Plus => numeric_binexpr!(lv, +, rv),
-                ast::BinOp::Minus => numeric_binexpr!(lv, -, rv),
-                ast::BinOp::ShiftLeft => todo!(),
-                ast::BinOp::ShiftRight => todo!(),
-                ast::BinOp::LessThan => comp_expr!(lv, <, rv),
-                ast::BinOp::GreaterThan => comp_expr!(lv, >, rv),
-                ast::BinOp::LessEq => comp_expr!(lv, <=, rv),
-                ast::BinOp::GreaterEq => comp_expr!(lv, >=, rv),
-                ast::BinOp::Eq => comp_expr!(lv, ==, rv),
-                ast::BinOp::NotEq => comp_expr!(lv, !=, rv),
-                ast::BinOp::LogicalAnd => Ok(Val::Bool(lv.to_bool() && rv.to_bool())),
-                ast::BinOp::LogicalOr => Ok(Val::Bool(lv.to_bool() || rv.to_bool())),
-            }
-        }
+        ast::Expr::BinExpr(..) => eval_binexpr(e, ctx),
         ast::Expr::Rec(re) => {
             let r = eval_rec(re, ctx)?;
             Ok(Val::Rec(r))
         }
-        ast::Expr::Call(_) => todo!(),
-        ast::Expr::Fun(_) => todo!(),
+        ast::Expr::List(es) => {
+            let mut vs = Vec::with_capacity(es.len());
+            for e in es.iter() {
+                vs.push(eval(e, Rc::clone(&ctx))?);
+            }
+            Ok(Val::List(vs.into()))
+        }
+        ast::Expr::Fun(f) => lambda(f, &ctx, None),
+        ast::Expr::ListComp(e, c) => {
+            let mut vs = Vec::new();
+            comprehend(c, &ctx, |scope| {
+                vs.push(eval(e, scope)?);
+                Ok(())
+            })?;
+            Ok(Val::List(vs.into()))
+        }
+        ast::Expr::RecComp(k, v, c) => {
+            let mut r = Rec::new();
+            comprehend(c, &ctx, |scope| match eval(k, Rc::clone(&scope))? {
+                Val::Str(name) => {
+                    r.setattr(&name, eval(v, scope)?);
+                    Ok(())
+                }
+                kv => Err(EvalError {
+                    message: format!("Record comprehension keys must be str, got {}", kv.typ()),
+                    pos: None,
+                    failed_asserts: 0,
+                }),
+            })?;
+            Ok(Val::Rec(Rc::new(RefCell::new(r))))
+        }
+    }
+}
+
+// Evaluates a chain of suffixes like a.b[c](d). Each suffix applies to the
+// value of the expression before it, so the chain is evaluated in a loop from
+// the left, rather than recursively, which would count every suffix against
+// the maximum depth.
+fn eval_suffixes(e: &ast::Expr, ctx: Rc<Ctx>) -> EvalResult<Val> {
+    let mut suffixes = vec![e];
+    let mut base = e;
+    loop {
+        base = match base {
+            ast::Expr::FieldAcc(b, _) | ast::Expr::OptFieldAcc(b, _) | ast::Expr::Index(b, _) => b,
+            ast::Expr::Call(c) => &c.fun,
+            _ => break,
+        };
+        suffixes.push(base);
+    }
+    suffixes.pop();
+    let mut v = eval(base, Rc::clone(&ctx))?;
+    while let Some(s) = suffixes.pop() {
+        if suffixes.is_empty() {
+            // `eval` accounts for the whole chain.
+            return suffix(v, s, ctx);
+        }
+        drop(Env::enter(&ctx.env)?);
+        v = ctx.env.check_value(s, suffix(v, s, Rc::clone(&ctx))?)?;
+    }
+    Ok(v)
+}
+
+// Applies the suffix `s` to `v`, the value of the expression before it.
+fn suffix(v: Val, s: &ast::Expr, ctx: Rc<Ctx>) -> EvalResult<Val> {
+    match (s, v) {
+        (ast::Expr::FieldAcc(_, f), Val::Rec(r)) => {
+            ctx.env.read_field(&r, f);
+            r.borrow().getattr(f).ok_or_else(|| EvalError {
+                message: format!("Field does not exist '{}'", f),
+                pos: None,
+                failed_asserts: 0,
+            })
+        }
+        (ast::Expr::OptFieldAcc(_, f), Val::Rec(r)) => {
+            ctx.env.read_field(&r, f);
+            Ok(r.borrow().getattr(f).unwrap_or(Val::Nil))
+        }
+        (ast::Expr::OptFieldAcc(_, _), Val::Nil) => Ok(Val::Nil),
+        (ast::Expr::FieldAcc(_, f) | ast::Expr::OptFieldAcc(_, f), v) => method(&v, f),
+        (ast::Expr::Index(_, k), v) => match (&v, eval(k, Rc::clone(&ctx))?) {
+            (Val::Rec(r), Val::Str(f)) => {
+                ctx.env.read_field(r, &f);
+                r.borrow().getattr(&f).ok_or_else(|| EvalError {
+                    message: format!("Field does not exist '{}'", f),
+                    pos: None,
+                    failed_asserts: 0,
+                })
+            }
+            (Val::List(l), Val::Int(i)) => usize::try_from(i)
+                .ok()
+                .and_then(|i| l.get(i).cloned())
+                .ok_or_else(|| EvalError {
+                    message: format!("Index {} out of range for list of length {}", i, l.len()),
+                    pos: None,
+                    failed_asserts: 0,
+                }),
+            (_, kv) => Err(EvalError {
+                message: format!(
+                    "Cannot index value of type '{}' with '{}'",
+                    v.typ(),
+                    kv.typ()
+                ),
+                pos: None,
+                failed_asserts: 0,
+            }),
+        },
+        (ast::Expr::Call(c), f) => {
+            let mut args = Vec::with_capacity(c.args.len());
+            for a in c.args.iter() {
+                args.push(eval(a, Rc::clone(&ctx))?);
+            }
+            match f {
+                Val::NativeFn(nf) => nf.call(&args, &ctx.env.settings.limits),
+                _ => Err(EvalError {
+                    message: format!("Cannot call value of type '{}'", f.typ()),
+                    pos: None,
+                    failed_asserts: 0,
+                }),
+            }
+        }
+        _ => unreachable!("not a suffix: {:?}", s),
+    }
+}
+
+// Evaluates a chain of binary operators like a + b + c. Operators associate
+// to the right, as in a + (b + c), so the chain is evaluated in a loop: first
+// its operands from the left, then the operators from the right. Evaluating it
+// recursively would count every operator against the maximum depth.
+fn eval_binexpr(e: &ast::Expr, ctx: Rc<Ctx>) -> EvalResult<Val> {
+    let mut left = vec![];
+    let mut e = e;
+    let mut v = loop {
+        let ast::Expr::BinExpr(le, op, re) = e else {
+            break eval(e, Rc::clone(&ctx))?;
+        };
+        if !left.is_empty() {
+            // `eval` accounts for the whole chain.
+            drop(Env::enter(&ctx.env)?);
+        }
+        let lv = eval(le, Rc::clone(&ctx))?;
+        // The default of ?? is only evaluated if needed.
+        if *op == ast::BinOp::Coalesce && !matches!(lv, Val::Nil) {
+            break lv;
+        }
+        left.push((lv, *op));
+        e = re;
+    };
+    while let Some((lv, op)) = left.pop() {
+        v = binexpr(lv, op, v)?;
+        if !left.is_empty() {
+            v = ctx.env.check_size(v)?;
+        }
+    }
+    Ok(v)
+}
+
+// The value of `lv op rv`.
+fn binexpr(lv: Val, op: ast::BinOp, rv: Val) -> EvalResult<Val> {
+    if let Some(r) = size_binexpr(op, &lv, &rv) {
+        return r;
+    }
+    if let Some(r) = duration_binexpr(op, &lv, &rv) {
+        return r;
+    }
+    match op {
+        ast::BinOp::Times => numeric_binexpr!(op, lv, *, rv),
+        ast::BinOp::Div => numeric_binexpr!(op, lv, /, rv),
+        ast::BinOp::Plus => numeric_binexpr!(op, lv, +, rv),
+        ast::BinOp::Minus => numeric_binexpr!(op, lv, -, rv),
+        ast::BinOp::ShiftLeft | ast::BinOp::ShiftRight => match (&lv, &rv) {
+            (Val::Int(a), Val::Int(b)) => int_binexpr(*a, op, *b),
+            _ => Err(EvalError {
+                message: format!(
+                    "Invalid types for arithmetic operation '{}': {} and {}",
+                    op.symbol(),
+                    lv.typ(),
+                    rv.typ()
+                ),
+                pos: None,
+                failed_asserts: 0,
+            }),
+        },
+        ast::BinOp::LessThan => ordered(op, &lv, &rv, Ordering::is_lt),
+        ast::BinOp::GreaterThan => ordered(op, &lv, &rv, Ordering::is_gt),
+        ast::BinOp::LessEq => ordered(op, &lv, &rv, Ordering::is_le),
+        ast::BinOp::GreaterEq => ordered(op, &lv, &rv, Ordering::is_ge),
+        ast::BinOp::Eq => Ok(Val::Bool(equal(&lv, &rv))),
+        ast::BinOp::NotEq => Ok(Val::Bool(!equal(&lv, &rv))),
+        ast::BinOp::LogicalAnd => Ok(Val::Bool(lv.to_bool() && rv.to_bool())),
+        ast::BinOp::LogicalOr => Ok(Val::Bool(lv.to_bool() || rv.to_bool())),
+        // Only reached if the left operand is nil, see `eval_binexpr`.
+        ast::BinOp::Coalesce => Ok(rv),
+    }
+}
+
+// The value of the variable `name` in `ctx`.
+fn lookup(name: &str, ctx: Rc<Ctx>) -> EvalResult<Val> {
+    let env = Rc::clone(&ctx.env);
+    match Ctx::binding(ctx, name) {
+        Some(Binding::Val(v, rec)) => {
+            if let Some(rec) = rec {
+                env.read_field(&rec, name);
+            }
+            Ok(v)
+        }
+        Some(Binding::Field(ctx2, fld)) => {
+            // Evaluate `fld`, store its value, and return it.
+            let v = eval_field(fld, Rc::clone(&ctx2))?;
+            env.read_field(&ctx2.rec, name);
+            Ok(v)
+        }
+        None => Err(EvalError {
+            message: format!("Unbound variable '{}'", name),
+            pos: None,
+            failed_asserts: 0,
+        }),
+    }
+}
+
+// Calls `f` with a new scope for each iteration of the comprehension `c`
+// whose condition holds. A single variable binds the elements of a list or
+// the keys of a record; two variables bind index and element, or key and
+// value. Records are iterated in key order, without their hidden fields.
+fn comprehend<'a>(
+    c: &ast::Comp,
+    ctx: &Rc<Ctx<'a>>,
+    mut f: impl FnMut(Rc<Ctx<'a>>) -> EvalResult<()>,
+) -> EvalResult<()> {
+    let (items, keys): (Vec<(Val, Val)>, bool) = match eval(&c.iter, Rc::clone(ctx))? {
+        Val::List(l) => {
+            let items = l.iter().enumerate();
+            (
+                items
+                    .map(|(i, v)| (Val::Int(i as i64), v.clone()))
+                    .collect(),
+                false,
+            )
+        }
+        Val::Rec(r) => {
+            let r = r.borrow();
+            let mut fields: Vec<_> = r.visible().collect();
+            fields.sort_by_key(|(k, _)| *k);
+            let items = fields.into_iter();
+            (
+                items
+                    .map(|(k, v)| (Val::Str(k.clone()), v.clone()))
+                    .collect(),
+                true,
+            )
+        }
+        v => {
+            return Err(EvalError {
+                message: format!("Cannot iterate over value of type '{}'", v.typ()),
+                pos: None,
+                failed_asserts: 0,
+            })
+        }
+    };
+    for (k, v) in items {
+        let scope = Ctx::scope_of(Rc::clone(ctx));
+        match c.vars.as_slice() {
+            [x] if keys => scope.setvar(&x.name, k),
+            [x] => scope.setvar(&x.name, v),
+            [a, b] => {
+                scope.setvar(&a.name, k);
+                scope.setvar(&b.name, v);
+            }
+            _ => unreachable!("comprehensions have one or two variables"),
+        }
+        if let Some(cond) = &c.cond {
+            if !eval(cond, Rc::clone(&scope))?.to_bool() {
+                continue;
+            }
+        }
+        f(scope)?;
+    }
+    Ok(())
+}
+
+// Evaluates a lambda to a function. Functions must not refer to the context
+// they were created in, so the lambda captures the values of the free
+// variables of its body right away. Variables that are not bound at all are
+// left out and are unbound when the function is called. `name` is the
+// variable or field that the lambda is the value of, which refers to the
+// function itself in its body.
+fn lambda(f: &ast::Fun, ctx: &Rc<Ctx>, name: Option<&str>) -> EvalResult<Val> {
+    let params: Vec<String> = f.params.iter().map(|p| p.name.clone()).collect();
+    let mut bound: HashSet<String> = params.iter().cloned().collect();
+    bound.extend(name.map(String::from));
+    let mut names = HashSet::new();
+    free_vars(&f.body, &bound, &mut names);
+    let mut captured = HashMap::new();
+    // Fields that are being evaluated, which happens for mutually recursive
+    // functions. They are read from their record when the function is called.
+    let mut pending = vec![];
+    for name in names {
+        let ctx2 = match Ctx::binding(Rc::clone(ctx), &name) {
+            Some(Binding::Val(v, _)) => {
+                captured.insert(name, v);
+                continue;
+            }
+            Some(Binding::Field(ctx2, _)) => ctx2,
+            None => continue,
+        };
+        let evaluating = ctx
+            .env
+            .fields
+            .borrow()
+            .iter()
+            .any(|(r, f)| Rc::ptr_eq(r, &ctx2.rec) && *f == name);
+        if evaluating {
+            pending.push((name, Rc::downgrade(&ctx2.rec)));
+        } else {
+            let v = lookup(&name, Rc::clone(ctx))?;
+            captured.insert(name, v);
+        }
+    }
+    let body = Rc::clone(&f.body);
+    let env = Rc::clone(&ctx.env);
+    // Positions in the body refer to the source that defines the lambda.
+    let src = env.sources.borrow().last().cloned();
+    let fun_name = format!("lambda({})", params.join(", "));
+    let this_name = name.map(String::from);
+    // The function itself, for `this_name`. A weak reference, because the
+    // function must not keep itself alive.
+    let this: Rc<OnceCell<Weak<NativeFnImpl>>> = Rc::new(OnceCell::new());
+    let call = {
+        let fun_name = fun_name.clone();
+        let this = Rc::clone(&this);
+        move |args: &[Val], _: &Limits| {
+            if args.len() != params.len() {
+                return Err(EvalError {
+                    message: format!(
+                        "{fun_name}: expected {} argument(s), got {}",
+                        params.len(),
+                        args.len()
+                    ),
+                    pos: None,
+                    failed_asserts: 0,
+                });
+            }
+            let scope = Ctx::closure(Rc::clone(&env), captured.clone());
+            for (n, rec) in pending.iter() {
+                if let Some(v) = rec.upgrade().and_then(|r| r.borrow().getattr(n)) {
+                    scope.setvar(n, v);
+                }
+            }
+            if let (Some(n), Some(f)) = (&this_name, this.get().and_then(Weak::upgrade)) {
+                let name = fun_name.clone();
+                scope.setvar(n, Val::NativeFn(NativeFn { name, f }));
+            }
+            for (p, a) in params.iter().zip(args) {
+                scope.setvar(p, a.clone());
+            }
+            match &src {
+                Some(src) => env.with_source(Rc::clone(src), || eval(&body, scope)),
+                None => eval(&body, scope),
+            }
+        }
+    };
+    let fun = NativeFn::with_limits(&fun_name, call);
+    let _ = this.set(Rc::downgrade(&fun.f));
+    Ok(Val::NativeFn(fun))
+}
+
+// Evaluates the value `e` of the variable or field `name`.
+fn eval_binding(name: &str, e: &ast::Expr, ctx: Rc<Ctx>) -> EvalResult<Val> {
+    match e {
+        ast::Expr::Fun(f) => lambda(f, &ctx, Some(name)),
+        _ => eval(e, ctx),
+    }
+}
+
+// Collects the names of the variables that `e` refers to, except for those
+// in `bound` and those that `e` binds itself.
+fn free_vars(e: &ast::Expr, bound: &HashSet<String>, names: &mut HashSet<String>) {
+    let comp = |c: &ast::Comp, exprs: &[&ast::Expr], names: &mut HashSet<String>| {
+        free_vars(&c.iter, bound, names);
+        let mut bound = bound.clone();
+        bound.extend(c.vars.iter().map(|v| v.name.clone()));
+        for e in exprs.iter().copied().chain(c.cond.as_deref()) {
+            free_vars(e, &bound, names);
+        }
+    };
+    match e {
+        ast::Expr::Literal(_) => {}
+        ast::Expr::Var(v) => {
+            if !bound.contains(&v.name) {
+                names.insert(v.name.clone());
+            }
+        }
+        ast::Expr::FieldAcc(e, _) | ast::Expr::OptFieldAcc(e, _) | ast::Expr::UnExpr(_, e) => {
+            free_vars(e, bound, names)
+        }
+        ast::Expr::BinExpr(l, _, r) | ast::Expr::Index(l, r) => {
+            free_vars(l, bound, names);
+            free_vars(r, bound, names);
+        }
+        ast::Expr::Rec(r) => {
+            let mut bound = bound.clone();
+            bound.extend(r.let_vars.iter().map(|lb| lb.var.name.clone()));
+            bound.extend(r.fields.iter().map(|f| f.name.clone()));
+            for lb in r.let_vars.iter() {
+                free_vars(&lb.value, &bound, names);
+            }
+            for f in r.fields.iter() {
+                free_vars(&f.value, &bound, names);
+            }
+            for a in r.asserts.iter() {
+                free_vars(&a.cond, &bound, names);
+                if let Some(m) = &a.message {
+                    free_vars(m, &bound, names);
+                }
+            }
+        }
+        ast::Expr::List(es) => es.iter().for_each(|e| free_vars(e, bound, names)),
+        ast::Expr::Call(c) => {
+            free_vars(&c.fun, bound, names);
+            c.args.iter().for_each(|a| free_vars(a, bound, names));
+        }
+        ast::Expr::Fun(f) => {
+            let mut bound = bound.clone();
+            bound.extend(f.params.iter().map(|p| p.name.clone()));
+            free_vars(&f.body, &bound, names)
+        }
+        ast::Expr::ListComp(e, c) => comp(c, &[e], names),
+        ast::Expr::RecComp(k, v, c) => comp(c, &[k, v], names),
     }
 }
 
@@ -265,33 +1456,234 @@ fn eval_rec(re: &ast::Rec, ctx: Rc<Ctx>) -> EvalResult<Rc<RefCell<Rec>>> {
                     // of this (or a child/parent/sibling) record.
                     continue;
                 }
-                let v = eval(&fld.value, Rc::clone(&rec_ctx))?;
-                (*record).borrow_mut().setattr(&fld.name, v);
+                eval_field(fld, Rc::clone(&rec_ctx))?;
             }
         }
+        check_asserts(&re.asserts, &rec_ctx, Some(&record))?;
         Ok(record)
     }
 }
 
 // Evaluate a single field, storing the result in the context's active record.
 fn eval_field(field: &ast::Field, ctx: Rc<Ctx>) -> EvalResult<Val> {
-    let val = eval(&field.value, Rc::clone(&ctx))?;
+    let env = &ctx.env;
+    env.deps.borrow_mut().push(vec![]);
+    env.fields
+        .borrow_mut()
+        .push((Rc::clone(&ctx.rec), field.name.clone()));
+    let val = eval_binding(&field.name, &field.value, Rc::clone(&ctx)).map_err(|e| e.at(field.pos));
+    env.fields.borrow_mut().pop();
+    let deps = env.deps.borrow_mut().pop().unwrap_or_default();
+    let val = val?;
     let mut m = (*ctx.rec).borrow_mut();
     m.setattr(&field.name, val.clone());
+    m.setloc(&field.name, field.pos);
+    let prov = Provenance {
+        name: field.name.clone(),
+        module: env.modules.borrow().last().cloned(),
+        pos: field.pos,
+        deps,
+    };
+    m.provenance.insert(field.name.clone(), Rc::new(prov));
+    if field.hidden {
+        m.hide(&field.name);
+    }
     Ok(val)
 }
 
+// Evaluates `asserts` in `ctx`, the context of `rec` if they belong to a
+// record, and records the ones that fail in the environment.
+fn check_asserts(
+    asserts: &[ast::Assert],
+    ctx: &Rc<Ctx>,
+    rec: Option<&Rc<RefCell<Rec>>>,
+) -> EvalResult<()> {
+    for a in asserts.iter() {
+        let cond = eval(&a.cond, Rc::clone(ctx)).map_err(|e| e.at(a.pos))?;
+        if cond.to_bool() {
+            continue;
+        }
+        let message = match &a.message {
+            Some(m) => match eval(m, Rc::clone(ctx)).map_err(|e| e.at(a.pos))? {
+                Val::Str(s) => s,
+                v => v.to_string(),
+            },
+            None => "assertion failed".to_string(),
+        };
+        ctx.env.failures.borrow_mut().push(AssertFailure {
+            message,
+            pos: a.pos,
+            src: ctx.env.sources.borrow().last().cloned(),
+            rec: rec.cloned(),
+        });
+    }
+    Ok(())
+}
+
+// Runs `f` on code from `src` and fails with all assertions that failed
+// meanwhile, reporting each one with its location and the path of its
+// record in the result.
+fn checked(env: &Rc<Env>, src: &str, f: impl FnOnce() -> EvalResult<Val>) -> EvalResult<Val> {
+    let start = env.failures.borrow().len();
+    let result = env.with_source(Rc::from(src), f);
+    let mut failures = env.failures.borrow_mut().split_off(start);
+    let v = result?;
+    if failures.is_empty() {
+        return Ok(v);
+    }
+    let line_col = |f: &AssertFailure| f.src.as_ref().map(|src| f.pos.line_col(src));
+    failures.sort_by_cached_key(line_col);
+    let lines: Vec<String> = failures
+        .iter()
+        .map(|f| {
+            let at = match line_col(f) {
+                Some((line, col)) => format!("{line}:{col}: "),
+                None => String::new(),
+            };
+            let path = f.rec.as_ref().and_then(|r| rec_path(&v, r, ""));
+            match path {
+                Some(p) if p.is_empty() => format!("  {at}<root>: {}", f.message),
+                Some(p) => format!("  {at}{p}: {}", f.message),
+                None => format!("  {at}{}", f.message),
+            }
+        })
+        .collect();
+    Err(EvalError {
+        message: format!(
+            "{} assertion(s) failed:\n{}",
+            failures.len(),
+            lines.join("\n")
+        ),
+        pos: Some(failures[0].pos),
+        failed_asserts: failures.len(),
+    })
+}
+
+// The path of the record `target` within `v`, like "servers[0].tls", if it is part of it.
+fn rec_path(v: &Val, target: &Rc<RefCell<Rec>>, path: &str) -> Option<String> {
+    match v {
+        Val::Rec(r) if Rc::ptr_eq(r, target) => Some(path.to_string()),
+        Val::Rec(r) => {
+            let r = r.borrow();
+            let mut names: Vec<&String> = r.fields.keys().collect();
+            names.sort();
+            names.into_iter().find_map(|name| {
+                let p = format!("{path}{}", PathElem::Field(name.to_string()));
+                let p = p.strip_prefix('.').unwrap_or(&p);
+                rec_path(&r.fields[name], target, p)
+            })
+        }
+        Val::List(l) => l
+            .iter()
+            .enumerate()
+            .find_map(|(i, e)| rec_path(e, target, &format!("{path}[{i}]"))),
+        _ => None,
+    }
+}
+
+/// Evaluates `e`, parsed from `src`, like `eval`, but fails if any of the
+/// assertions of the records that it creates fails.
+pub fn eval_checked(e: &ast::Expr, src: &str, ctx: Rc<Ctx>) -> EvalResult<Val> {
+    let env = Rc::clone(&ctx.env);
+    checked(&env, src, || eval(e, ctx))
+}
+
+// Evaluate a module, parsed from `src`: its imports and let bindings, in order,
+// followed by its expression and assertions. Fails if any assertion in the
+// module failed.
+pub fn eval_module(m: &ast::Module, src: &str, ctx: Rc<Ctx>) -> EvalResult<Val> {
+    eval_module_with(m, src, ctx, |_| {})
+}
+
+/// Like `eval_module`, but calls `inspect` with the module's scope once its
+/// imports and let bindings are evaluated.
+pub fn eval_module_with<'a>(
+    m: &'a ast::Module,
+    src: &str,
+    ctx: Rc<Ctx<'a>>,
+    inspect: impl FnOnce(&Rc<Ctx<'a>>),
+) -> EvalResult<Val> {
+    let env = Rc::clone(&ctx.env);
+    checked(&env, src, || {
+        let scope = module_scope(m, ctx)?;
+        inspect(&scope);
+        let v = eval(&m.expr, Rc::clone(&scope))?;
+        check_asserts(&m.asserts, &scope, None)?;
+        Ok(v)
+    })
+}
+
+// Evaluate the imports and let bindings of a module into a new scope.
+fn module_scope<'a>(m: &'a ast::Module, ctx: Rc<Ctx<'a>>) -> EvalResult<Rc<Ctx<'a>>> {
+    let scope = Ctx::scope_of(ctx);
+    for imp in m.imports.iter() {
+        let v = import(&imp.path, &scope.env).map_err(|e| e.at(imp.pos))?;
+        scope.setvar(&imp.var.name, v);
+    }
+    for lv in m.let_vars.iter() {
+        let v = eval_binding(&lv.var.name, &lv.value, Rc::clone(&scope))
+            .map_err(|e| e.at(lv.var.pos))?;
+        scope.setvar(&lv.var.name, v);
+    }
+    Ok(scope)
+}
+
+// Resolves, parses and evaluates an imported module. Every module is only
+// evaluated once per environment.
+fn import(path: &str, env: &Rc<Env>) -> EvalResult<Val> {
+    let err = |message: String| EvalError {
+        message,
+        pos: None,
+        failed_asserts: 0,
+    };
+    let Some(resolver) = &env.settings.resolver else {
+        return Err(err(format!(
+            "Cannot import \"{path}\": imports are not enabled"
+        )));
+    };
+    let from = env.modules.borrow().last().cloned();
+    let (name, src) = resolver
+        .resolve(path, from.as_deref())
+        .map_err(|e| err(format!("Cannot import \"{path}\": {e}")))?;
+    if let Some(v) = env.imported.borrow().get(&name) {
+        return Ok(v.clone());
+    }
+    if env.modules.borrow().contains(&name) {
+        let mut cycle = env.modules.borrow().clone();
+        cycle.push(name);
+        return Err(err(format!("Import cycle: {}", cycle.join(" -> "))));
+    }
+    let m = parser::parse_module(&src)
+        .map_err(|e| err(format!("Cannot parse {name}:\n{}", e.message)))?;
+    env.modules.borrow_mut().push(name.clone());
+    let r = eval_module(&m, &src, Ctx::root(Rc::clone(env)));
+    env.modules.borrow_mut().pop();
+    // Positions refer to the imported source, so move them into the message.
+    let v = r.map_err(|e| EvalError {
+        failed_asserts: e.failed_asserts,
+        ..match e.pos {
+            Some(p) => {
+                let (line, col) = p.line_col(&src);
+                err(format!("{name}:{line}:{col}: {}", e.message))
+            }
+            None => err(format!("{name}: {}", e.message)),
+        }
+    })?;
+    env.imported.borrow_mut().insert(name, v.clone());
+    Ok(v)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::parser;
 
     mod h {
+        use crate::ast;
         use crate::eval::*;
         use crate::parser;
-        use crate::ast;
         pub fn force_parse(s: &str) -> Box<ast::Expr> {
-            parser::expr_opt(s).expect(&format!("Expected being able to parse: {}", s))
+            parser::expr_opt(s).unwrap_or_else(|| panic!("Expected being able to parse: {}", s))
         }
         pub fn eval_global(s: &str) -> EvalResult<Val> {
             eval(&force_parse(s), Ctx::global())
@@ -320,11 +1712,519 @@ mod tests {
         assert_eq!(e("1 || 0 && 0"), r(true));
     }
 
+    #[test]
+    fn eval_int_arithmetic() {
+        let e = h::eval_global;
+        let err = |s| e(s).unwrap_err().message;
+        assert_eq!(e("1 << 2"), Ok(Val::Int(4)));
+        assert_eq!(e("-16 >> 2"), Ok(Val::Int(-4)));
+        assert_eq!(e("1 << 2 + 1"), Ok(Val::Int(8)));
+        assert_eq!(
+            err("1 << 64"),
+            "Shift amount must be between 0 and 63 in 1 << 64"
+        );
+        assert_eq!(
+            err("1 >> -1"),
+            "Shift amount must be between 0 and 63 in 1 >> -1"
+        );
+        assert_eq!(
+            err("\"a\" << 1"),
+            "Invalid types for arithmetic operation '<<': str and int"
+        );
+        assert_eq!(err("1 / 0"), "Division by zero");
+        assert_eq!(
+            err("9223372036854775807 + 1"),
+            "Integer overflow in 9223372036854775807 + 1"
+        );
+        assert_eq!(
+            err("-9223372036854775807 - 2"),
+            "Integer overflow in -9223372036854775807 - 2"
+        );
+        assert_eq!(
+            err("9223372036854775807 * 2"),
+            "Integer overflow in 9223372036854775807 * 2"
+        );
+        assert_eq!(
+            err("(-9223372036854775807 - 1) / -1"),
+            "Integer overflow in -9223372036854775808 / -1"
+        );
+        assert_eq!(
+            err("-(-9223372036854775807 - 1)"),
+            "Integer overflow in -(-9223372036854775808)"
+        );
+    }
+
+    #[test]
+    fn eval_deep_equality() {
+        let e = h::eval_global;
+        let r = |b| Ok(Val::Bool(b));
+        assert_eq!(e("{a: 1, b: [1, 2]} == {b: [1, 2], a: 1}"), r(true));
+        assert_eq!(e("{a: 1} == {a: 1, b: 2}"), r(false));
+        assert_eq!(e("{a: 1, h:: 2} == {a: 1}"), r(true));
+        assert_eq!(e("[1, [2, {x: 3}]] != [1, [2, {x: 4}]]"), r(true));
+        assert_eq!(e("1m == 60s && 1KiB == 1024B"), r(true));
+        assert_eq!(e("nil == nil"), r(true));
+        // Values of different types are never equal.
+        assert_eq!(e("1 == \"1\""), r(false));
+        assert_eq!(e("[1] != {a: 1}"), r(true));
+        assert_eq!(e("nil == 0"), r(false));
+        assert!(equal(&Val::Int(1), &Val::Double(1.0)));
+        assert!(!equal(&Val::Double(f64::NAN), &Val::Double(f64::NAN)));
+    }
+
+    #[test]
+    fn eval_ordering() {
+        let e = h::eval_global;
+        let r = |b| Ok(Val::Bool(b));
+        assert_eq!(e("[1, 2] < [1, 3]"), r(true));
+        assert_eq!(e("[1, 2] < [1, 2, 0]"), r(true));
+        assert_eq!(e("[2] > [1, 9]"), r(true));
+        assert_eq!(e("[[1], \"b\"] <= [[1], \"a\"]"), r(false));
+        assert_eq!(e("2s > 1s && 1KiB >= 1000B"), r(true));
+        assert_eq!(
+            e("{a: 1} < {a: 2}").unwrap_err().message,
+            "Invalid types for comparison '<': rec and rec"
+        );
+        assert_eq!(
+            e("1 < \"2\"").unwrap_err().message,
+            "Invalid types for comparison '<': int and str"
+        );
+        let t = |s: &str| Val::Timestamp(s.parse().unwrap());
+        let (t1, t2) = (t("2024-05-01T12:00:00Z"), t("2024-05-01T12:00:01Z"));
+        assert_eq!(compare(&t1, &t2), Some(Ordering::Less));
+        assert!(equal(&t1, &t1.clone()) && !equal(&t1, &t2));
+        assert_eq!(t1.to_string(), "2024-05-01T12:00:00Z");
+        // Ints and doubles are compared exactly, even where a double cannot
+        // represent the int.
+        let (i, d) = (Val::Int, Val::Double);
+        assert_eq!(
+            compare(&i(1 << 53), &d(2f64.powi(53))),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(
+            compare(&i((1 << 53) + 1), &d(2f64.powi(53))),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(compare(&d(-1.5), &i(-1)), Some(Ordering::Less));
+        assert_eq!(
+            compare(&i(i64::MAX), &d(2f64.powi(63))),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            compare(&i(i64::MIN), &d(-2f64.powi(63))),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(compare(&i(0), &d(f64::NAN)), None);
+    }
+
     #[test]
     fn eval_rec() {
         assert_eq!(h::eval_global("{x: 3 - 8}.x"), Ok(Val::Int(-5)));
     }
 
+    #[test]
+    fn eval_shadowed_fields() {
+        // The inner x shadows the outer one, even though the outer one is
+        // evaluated first.
+        assert_eq!(
+            h::eval_global("{x: 1, a: {y: x, x: 2}}.a.y"),
+            Ok(Val::Int(2))
+        );
+        assert_eq!(
+            h::eval_global("{x: 1, a: {f: (n) => x + n, x: 2, y: f(1)}}.a.y"),
+            Ok(Val::Int(3))
+        );
+        assert_eq!(h::eval_global("{x: 1, a: {y: x}}.a.y"), Ok(Val::Int(1)));
+    }
+
+    #[test]
+    fn eval_list() {
+        assert_eq!(
+            h::eval_global("[1, 1 + 1, {x: 3}.x]"),
+            Ok(Val::from(vec![1, 2, 3]))
+        );
+        assert_eq!(h::eval_global("!![]"), Ok(Val::Bool(false)));
+        let depth = |s| h::eval_global(s).unwrap().depth();
+        assert_eq!(depth("1"), 0);
+        assert_eq!(depth("[]"), 1);
+        assert_eq!(depth("[[1], {a: [[]]}]"), 4);
+        assert_eq!(depth("[1, 2].map(x => [x])"), 2);
+    }
+
+    #[test]
+    fn eval_null_safe() {
+        let e = h::eval_global;
+        let r = "{a: {b: {c: 1}}\nn: nil}";
+        assert_eq!(e(&format!("{r}?.a?.b?.c")), Ok(Val::Int(1)));
+        assert_eq!(e(&format!("{r}?.a?.x?.c")), Ok(Val::Nil));
+        assert_eq!(e(&format!("{r}.n?.c")), Ok(Val::Nil));
+        assert_eq!(e(&format!("{r}?.a?.x ?? 2")), Ok(Val::Int(2)));
+        assert_eq!(e(&format!("{r}.a.b.c ?? 2")), Ok(Val::Int(1)));
+        assert_eq!(e("nil ?? nil ?? 3"), Ok(Val::Int(3)));
+        // The default is not evaluated unless needed.
+        assert_eq!(e("1 ?? x"), Ok(Val::Int(1)));
+        assert_eq!(
+            e("1?.a").unwrap_err().message,
+            "Invalid field access on value type 'int'"
+        );
+    }
+
+    #[test]
+    fn eval_comprehensions() {
+        let d = |s: &str| h::eval_global(s).unwrap().to_string();
+        assert_eq!(
+            d("[{id: i, port: 9000 + i} for i in range(0, 4) if i != 2]"),
+            "[{id: 0, port: 9000}, {id: 1, port: 9001}, {id: 3, port: 9003}]"
+        );
+        assert_eq!(d("[i * x for i, x in [5, 6]]"), "[0, 6]");
+        assert_eq!(d("[k for k in {b: 1\na: 2}]"), "[\"a\", \"b\"]");
+        assert_eq!(
+            d("{[k]: v.port for k, v in {web: {port: 80}\ndb: {port: 5432}}}"),
+            "{db: 5432, web: 80}"
+        );
+        assert_eq!(
+            d("{[str.format(\"shard-{}\", i)]: i for i in range(2)}"),
+            "{\"shard-0\": 0, \"shard-1\": 1}"
+        );
+        assert_eq!(
+            d("{\"${k}-port\": v.port for k, v in {web: {port: 80}\ndb: {port: 5432}}}"),
+            "{\"db-port\": 5432, \"web-port\": 80}"
+        );
+        let err = |s: &str| h::eval_global(s).unwrap_err().message;
+        assert_eq!(
+            err("[x for x in 3]"),
+            "Cannot iterate over value of type 'int'"
+        );
+        assert_eq!(
+            err("{[i]: i for i in range(2)}"),
+            "Record comprehension keys must be str, got int"
+        );
+    }
+
+    #[test]
+    fn eval_lambdas() {
+        let d = |s: &str| h::eval_global(s).unwrap().to_string();
+        // Lambdas capture the variables they use, even from nested scopes.
+        assert_eq!(
+            d("{\n offset: 100\n add: x => x + offset\n ports: [1, 2].map(add)\n}.ports"),
+            "[101, 102]"
+        );
+        assert_eq!(d("[n => n * m for m in [2, 3]].map(f => f(5))"), "[10, 15]");
+        assert_eq!(d("(() => 7)()"), "7");
+        assert_eq!(d("x => x"), "<fn lambda(x)>");
+        assert_eq!(
+            h::eval_global("(x => y)(1)").unwrap_err().message,
+            "Unbound variable 'y'"
+        );
+        // Names bound in the body are not captured.
+        let e = parser::parse_expr("[k * y for k in x if k] + [{k: 1, j: k}.j, (z => z + k)(w)]")
+            .ok()
+            .unwrap();
+        let mut names = HashSet::new();
+        free_vars(&e, &HashSet::from(["x".to_string()]), &mut names);
+        assert_eq!(
+            names,
+            HashSet::from(["y".to_string(), "k".to_string(), "w".to_string()])
+        );
+        let err = |s: &str| h::eval_global(s).unwrap_err().message;
+        assert_eq!(err("{\n x:: 1 / 0\n f: () => x\n}"), "Division by zero");
+        // Recursive and mutually recursive functions.
+        assert_eq!(
+            d("{\n f:: n => [f(n - 1) for i in range(1) if n > 0]\n x: f(30)\n}.x.len()"),
+            "1"
+        );
+        assert_eq!(
+            d("{\n a:: n => [b(n - 1) for i in range(1) if n > 0]\n \
+               b:: n => [{b: a(n - 1)} for i in range(1) if n > 0]\n x: a(3)\n}.x"),
+            "[[{b: [[]]}]]"
+        );
+        let src = "let f = n => [f(n - 1) for i in range(1) if n > 0]\nf(2)";
+        let m = parser::parse_module(src).ok().unwrap();
+        assert_eq!(
+            eval_module(&m, src, Ctx::global()).unwrap().to_string(),
+            "[[[]]]"
+        );
+        // Functions share the body of their lambda instead of copying it.
+        let e = parser::parse_expr("[x => x + i for i in range(3)]")
+            .ok()
+            .unwrap();
+        let ast::Expr::ListComp(f, _) = e.as_ref() else {
+            panic!("expected a list comprehension, got {:?}", e);
+        };
+        let ast::Expr::Fun(f) = f.as_ref() else {
+            panic!("expected a lambda, got {:?}", f);
+        };
+        let fs = eval(&e, Ctx::global()).unwrap();
+        assert_eq!(Rc::strong_count(&f.body), 4);
+        drop(fs);
+        assert_eq!(Rc::strong_count(&f.body), 1);
+    }
+
+    #[test]
+    fn eval_methods() {
+        let e = h::eval_global;
+        assert_eq!(e("\"a.com\".endswith(\".com\")"), Ok(Val::Bool(true)));
+        assert_eq!(
+            e("\" a,b \".trim().split(\",\").join(\";\")"),
+            Ok(Val::Str("a;b".to_string()))
+        );
+        assert_eq!(e("1m30s.as_millis()"), Ok(Val::Int(90_000)));
+        assert_eq!(e("2KiB.as_bytes()"), Ok(Val::Int(2048)));
+        // Records have fields, not methods.
+        assert_eq!(e("{len: 3}.len"), Ok(Val::Int(3)));
+        let f = e("\"abc\".len").unwrap();
+        assert_eq!(f.to_string(), "<fn str.len>");
+        let err = |s: &str| e(s).unwrap_err().message;
+        assert_eq!(
+            err("2KiB.foo()"),
+            "No method 'foo' for type 'size'. Available methods: as_bytes, human"
+        );
+        assert_eq!(err("1.len()"), "Invalid field access on value type 'int'");
+        assert_eq!(
+            err("\"a\".endswith()"),
+            "str.endswith: expected 2 argument(s), got 1"
+        );
+    }
+
+    #[test]
+    fn eval_index() {
+        let e = h::eval_global;
+        let r = "{\"content-type\": \"json\"\n\"8080\": [1, 2]}";
+        assert_eq!(
+            e(&format!("{r}[\"content-type\"]")),
+            Ok(Val::Str("json".to_string()))
+        );
+        assert_eq!(
+            e(&format!("{r}[\"80\" + \"80\"][1]")),
+            Err(EvalError {
+                message: "Invalid types for arithmetic operation '+': str and str".to_string(),
+                pos: None,
+                failed_asserts: 0,
+            })
+        );
+        assert_eq!(
+            e(&format!("{r}[str.join([\"80\", \"80\"], \"\")][1]")),
+            Ok(Val::Int(2))
+        );
+        let err = |s: &str| e(s).unwrap_err().message;
+        assert_eq!(err(&format!("{r}[\"x\"]")), "Field does not exist 'x'");
+        assert_eq!(
+            err("[1, 2][2]"),
+            "Index 2 out of range for list of length 2"
+        );
+        assert_eq!(
+            err("[1, 2][-1]"),
+            "Index -1 out of range for list of length 2"
+        );
+        assert_eq!(
+            err("[1][\"a\"]"),
+            "Cannot index value of type 'list' with 'str'"
+        );
+    }
+
+    #[test]
+    fn eval_call() {
+        assert_eq!(
+            h::eval_global("{f: str.upper}.f(\"a\")"),
+            Ok(Val::Str("A".to_string()))
+        );
+        assert_eq!(
+            h::eval_global("1(2)"),
+            Err(EvalError {
+                message: "Cannot call value of type 'int'".to_string(),
+                pos: None,
+                failed_asserts: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn eval_size() {
+        let e = h::eval_global;
+        assert_eq!(e("1KiB + 24B"), Ok(Val::Size(1048)));
+        assert_eq!(e("2 * 512MiB == 1GiB"), Ok(Val::Bool(true)));
+        assert_eq!(e("1GB / 3"), Ok(Val::Size(333_333_333)));
+        assert_eq!(e("1GiB / 512MiB"), Ok(Val::Double(2.0)));
+        assert_eq!(e("1GB > 1GiB"), Ok(Val::Bool(false)));
+        let err = |s: &str| e(s).unwrap_err().message;
+        assert_eq!(err("1MB - 2MB"), "Negative size in 1MB - 2MB");
+        assert_eq!(err("16000000TiB * 2"), "Size overflow in 16000000TiB * 2");
+        assert_eq!(err("1MB * -1"), "Cannot multiply size by negative int -1");
+        assert!(
+            err("1MB + 1").starts_with("Invalid types for arithmetic operation '+': size and int")
+        );
+    }
+
+    #[test]
+    fn eval_duration() {
+        let e = h::eval_global;
+        let d = |s: &str| h::eval_global(s).unwrap().to_string();
+        assert_eq!(d("1h + 30m"), "1h30m");
+        assert_eq!(d("90s * 2"), "3m");
+        assert_eq!(d("1s / 4"), "250ms");
+        assert_eq!(d("1m - 2m"), "-1m");
+        assert_eq!(d("-(5s)"), "-5s");
+        assert_eq!(e("1h / 30m"), Ok(Val::Double(2.0)));
+        assert_eq!(e("60s == 1m"), Ok(Val::Bool(true)));
+        assert_eq!(e("999ms < 1s"), Ok(Val::Bool(true)));
+        let err = |s: &str| e(s).unwrap_err().message;
+        assert_eq!(err("1s / 0"), "Division of duration by zero");
+        assert_eq!(err("100000d * 1000"), "Duration overflow in 100000d * 1000");
+        assert_eq!(
+            err("1s + 1"),
+            "Invalid types for arithmetic operation '+': duration and int"
+        );
+    }
+
+    #[test]
+    fn display() {
+        let d = |s| h::eval_global(s).unwrap().to_string();
+        assert_eq!(
+            d("{b: [1, \"x\"]\na: {}\nc: {d: 1KiB}}"),
+            "{a: {}, b: [1, \"x\"], c: {d: 1KiB}}"
+        );
+        assert_eq!(d("{\"x.y\": 1}"), "{\"x.y\": 1}");
+        assert_eq!(d("str.len"), "<fn str.len>");
+    }
+
+    #[test]
+    fn select() {
+        let v = h::eval_global("{a: {b: [1, {c: 2}]}}").unwrap();
+        assert_eq!(v.select("a.b[1].c"), Ok(Val::Int(2)));
+        let labels = h::eval_global("{labels: {\"app.kubernetes.io/name\": \"web\"}}").unwrap();
+        assert_eq!(
+            labels.select("labels[\"app.kubernetes.io/name\"]"),
+            Ok(Val::Str("web".to_string()))
+        );
+        assert_eq!(
+            labels.select("labels[\"x.y\"]").unwrap_err().message,
+            "Path '.labels[\"x.y\"]' does not exist"
+        );
+        assert_eq!(v.select(""), Ok(v.clone()));
+        assert_eq!(
+            v.select("a.x").unwrap_err().message,
+            "Path '.a.x' does not exist"
+        );
+        assert_eq!(
+            v.select("a.b[2]").unwrap_err().message,
+            "Path '.a.b[2]' does not exist"
+        );
+        assert_eq!(
+            v.select("a.b.c").unwrap_err().message,
+            "Cannot select .c from value of type 'list' at '.a.b'"
+        );
+        assert_eq!(
+            parse_path("a..b").unwrap_err(),
+            "Empty field name in path 'a..b'"
+        );
+        assert_eq!(
+            parse_path("[x]").unwrap_err(),
+            "Invalid index 'x' in path '[x]'"
+        );
+    }
+
+    #[test]
+    fn eval_module_lets() {
+        let src = r#"
+            let x = 2
+            let y = x * 3
+            {
+                a: {
+                    b: y + 1
+                }
+            }.a.b
+            "#;
+        let m = parser::parse_module(src).ok().unwrap();
+        assert_eq!(eval_module(&m, src, Ctx::global()), Ok(Val::Int(7)));
+    }
+
+    #[test]
+    fn eval_hidden_fields() {
+        let src =
+            "{\n  base:: 8000\n  port: base + 80\n  \"x-y\":: {a: 1}\n  b: {c:: base, d: c}\n}";
+        let v = h::eval_global(src).unwrap();
+        assert_eq!(
+            v.to_string(),
+            "{b: {c:: 8000, d: 8000}, base:: 8000, port: 8080, \"x-y\":: {a: 1}}"
+        );
+        // Hidden fields can be accessed, but are not part of the output.
+        assert_eq!(h::eval_global(&format!("{src}.base")), Ok(Val::Int(8000)));
+        assert_eq!(
+            crate::json::to_json(&v).unwrap().to_string(),
+            r#"{"b":{"d":8000},"port":8080}"#
+        );
+        assert_eq!(
+            h::eval_global("[k for k in {a:: 1, b: 2}]"),
+            h::eval_global("[\"b\"]")
+        );
+        // Templates are functions that return records, so every instance
+        // keeps the visibility the template declared.
+        let src = "let server = (p) => {port:: p, next: port + 1}\nserver(80)";
+        let m = parser::parse_module(src).ok().unwrap();
+        let v = eval_module(&m, src, Ctx::global()).unwrap();
+        assert_eq!(v.to_string(), "{next: 81, port:: 80}");
+        assert_eq!(
+            h::eval_global("{a:: 1, a: 2, b: a}").unwrap().to_string(),
+            "{a:: 1, b: 1}"
+        );
+    }
+
+    #[test]
+    fn eval_asserts() {
+        let e = |s: &str| eval_module(&parser::parse_module(s).ok().unwrap(), s, Ctx::global());
+        assert_eq!(
+            e("let n = 3\nassert n > 0 : \"n must be positive\"\n{\n  a: n\n  assert a == n\n}"),
+            h::eval_global("{a: 3}")
+        );
+        // All failures are reported, with the path of their record.
+        let err = e(r#"assert 1 > 2
+{
+  servers: [
+    {port: 80},
+    {
+      port: 0
+      assert port > 0 : str.format("invalid port {}", port)
+    }
+  ]
+  tls: {
+    enabled: 0
+    assert enabled == 1
+  }
+  "a.b": {assert 0 : 42}
+  assert servers.len() > 2 : "need 3 servers"
+}"#)
+        .unwrap_err();
+        assert_eq!(
+            err.message,
+            [
+                "5 assertion(s) failed:",
+                "  1:1: assertion failed",
+                "  7:7: servers[1]: invalid port 0",
+                "  12:5: tls: assertion failed",
+                "  14:11: [\"a.b\"]: 42",
+                "  15:3: <root>: need 3 servers",
+            ]
+            .join("\n")
+        );
+        // Failures in records that are not part of the result have no path.
+        assert_eq!(
+            e("let r = {assert 0}\n1").unwrap_err().message,
+            "1 assertion(s) failed:\n  1:10: assertion failed"
+        );
+        // Errors in assertions are reported like other errors.
+        assert_eq!(e("{assert x}").unwrap_err().message, "Unbound variable 'x'");
+        let ctx = Ctx::global();
+        let src = "[{assert 0}]";
+        let rec = parser::parse_expr(src).ok().unwrap();
+        let err = eval_checked(&rec, src, ctx).unwrap_err();
+        assert_eq!(
+            err.message,
+            "1 assertion(s) failed:\n  1:3: [0]: assertion failed"
+        );
+        assert_eq!(err.failed_asserts, 1);
+        assert_eq!(e("{assert x}").unwrap_err().failed_asserts, 0);
+    }
+
     #[test]
     fn eval_rec_lookup() {
         let rec = parser::expr_opt(
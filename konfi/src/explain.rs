@@ -0,0 +1,235 @@
+// Provenance of evaluated values: where the value of a field was defined and
+// which other fields it was computed from, as recorded by eval_field.
+//
+// Fields are identified by their path in the evaluated value. Fields that are
+// not part of it, like those of a record bound by `let`, have no path.
+
+use crate::ast;
+use crate::eval::{parse_path, PathElem, Provenance, Rec, Val};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+/// A field and where its value was defined.
+#[derive(Debug)]
+pub struct Source {
+    pub name: String,
+    // Path of the field in the evaluated value, if it is part of it.
+    pub path: Option<String>,
+    pub value: Option<Val>,
+    // The module that defines the field, and the position in its source.
+    pub module: Option<String>,
+    pub pos: ast::Pos,
+}
+
+/// The provenance of a field: its definition and the fields that it depends on.
+#[derive(Debug)]
+pub struct Explanation {
+    pub field: Source,
+    pub deps: Vec<Source>,
+}
+
+// The records in `v` and their paths, by their address. Records that occur
+// more than once get the first path in field order.
+type RecPaths = HashMap<*const RefCell<Rec>, (String, Rc<RefCell<Rec>>)>;
+
+fn rec_paths(v: &Val) -> RecPaths {
+    fn walk(v: &Val, path: &str, paths: &mut RecPaths) {
+        match v {
+            Val::Rec(r) => {
+                if paths.contains_key(&Rc::as_ptr(r)) {
+                    return;
+                }
+                paths.insert(Rc::as_ptr(r), (path.to_string(), Rc::clone(r)));
+                let r = r.borrow();
+                let mut names: Vec<&String> = r.fields.keys().collect();
+                names.sort();
+                for name in names {
+                    walk(&r.fields[name], &field_path(path, name), paths);
+                }
+            }
+            Val::List(l) => {
+                for (i, e) in l.iter().enumerate() {
+                    walk(e, &format!("{path}{}", PathElem::Index(i)), paths);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut paths = HashMap::new();
+    walk(v, "", &mut paths);
+    paths
+}
+
+// The path of the field `name` of the record at `path`.
+fn field_path(path: &str, name: &str) -> String {
+    let p = format!("{path}{}", PathElem::Field(name.to_string()));
+    p.strip_prefix('.').map(str::to_string).unwrap_or(p)
+}
+
+fn source(rec: Option<&Rc<RefCell<Rec>>>, prov: &Provenance, paths: &RecPaths) -> Source {
+    let rec = rec.filter(|r| r.borrow().fields.contains_key(&prov.name));
+    Source {
+        name: prov.name.clone(),
+        path: rec
+            .and_then(|r| paths.get(&Rc::as_ptr(r)))
+            .map(|(p, _)| field_path(p, &prov.name)),
+        value: rec.and_then(|r| r.borrow().getattr(&prov.name)),
+        module: prov.module.clone(),
+        pos: prov.pos,
+    }
+}
+
+/// Explains the field at `path` in `v`, e.g. "servers[0].port".
+pub fn explain(v: &Val, path: &str) -> Result<Explanation, String> {
+    let mut elems = parse_path(path)?;
+    let Some(PathElem::Field(name)) = elems.pop() else {
+        return Err(format!("Path '{path}' does not end with a field name"));
+    };
+    let parent = elems.iter().map(|e| e.to_string()).collect::<String>();
+    let parent = parent.strip_prefix('.').unwrap_or(&parent);
+    let rec = match v.select(parent).map_err(|e| e.message)? {
+        Val::Rec(r) if r.borrow().fields.contains_key(&name) => r,
+        _ => return Err(format!("Path '{path}' does not exist")),
+    };
+    let Some(prov) = rec.borrow().provenance(&name) else {
+        return Err(format!("The value of '{path}' was not defined by a field"));
+    };
+    let paths = rec_paths(v);
+    Ok(Explanation {
+        field: source(Some(&rec), &prov, &paths),
+        deps: prov
+            .deps
+            .iter()
+            .map(|d| source(d.rec.upgrade().as_ref(), &d.prov, &paths))
+            .collect(),
+    })
+}
+
+// A string literal in the DOT language.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The dependencies between the fields of `v` as a graph in the DOT language.
+/// Each field points to the fields that its value was computed from. Fields
+/// that are not part of `v` are drawn dashed.
+pub fn graph(v: &Val) -> String {
+    let paths = rec_paths(v);
+    let mut out = String::from("digraph konfi {\n");
+    // Fields that are not part of `v` get a number to tell them apart.
+    let mut others: HashMap<*const Provenance, usize> = HashMap::new();
+    let mut node = |rec: Option<Rc<RefCell<Rec>>>, prov: &Rc<Provenance>| {
+        let s = source(rec.as_ref(), prov, &paths);
+        match s.path {
+            Some(p) => (quote(&p), true),
+            None => {
+                let n = others.len() + 1;
+                let n = *others.entry(Rc::as_ptr(prov)).or_insert(n);
+                (quote(&format!("{}#{}", s.name, n)), false)
+            }
+        }
+    };
+    // All fields of `v` in path order, followed by the fields they depend on.
+    let mut recs: Vec<_> = paths.values().collect();
+    recs.sort_by_key(|(p, _)| p);
+    let mut queue = VecDeque::new();
+    for (_, rec) in recs {
+        let r = rec.borrow();
+        let mut provs: Vec<Rc<Provenance>> = r.provenance.values().cloned().collect();
+        provs.sort_by(|a, b| a.name.cmp(&b.name));
+        queue.extend(provs.into_iter().map(|p| (Some(Rc::clone(rec)), p)));
+    }
+    let mut seen: HashSet<*const Provenance> = queue.iter().map(|(_, p)| Rc::as_ptr(p)).collect();
+    while let Some((rec, prov)) = queue.pop_front() {
+        let (id, in_output) = node(rec, &prov);
+        if in_output {
+            out.push_str(&format!("  {id};\n"));
+        } else {
+            out.push_str(&format!(
+                "  {id} [label={}, style=dashed];\n",
+                quote(&prov.name)
+            ));
+        }
+        for d in prov.deps.iter() {
+            let dep_rec = d.rec.upgrade();
+            out.push_str(&format!(
+                "  {id} -> {};\n",
+                node(dep_rec.clone(), &d.prov).0
+            ));
+            if seen.insert(Rc::as_ptr(&d.prov)) {
+                queue.push_back((dep_rec, Rc::clone(&d.prov)));
+            }
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+
+    const SRC: &str = r#"let base = {offset: 8000}
+{
+  name: "web"
+  port: base.offset + 80
+  servers: [{host: name, listen: port}]
+}"#;
+
+    fn eval(src: &str) -> Val {
+        Engine::new().eval_str(src).unwrap()
+    }
+
+    fn describe(s: &Source) -> String {
+        let (line, col) = s.pos.line_col(SRC);
+        let value = s.value.as_ref().map(|v| v.to_string()).unwrap_or_default();
+        match &s.path {
+            Some(p) => format!("{p} = {value} at {line}:{col}"),
+            None => format!("({}) at {line}:{col}", s.name),
+        }
+    }
+
+    #[test]
+    fn explain_fields() {
+        let v = eval(SRC);
+        let e = explain(&v, "servers[0].listen").unwrap();
+        assert_eq!(describe(&e.field), "servers[0].listen = 8080 at 5:26");
+        let deps: Vec<_> = e.deps.iter().map(describe).collect();
+        assert_eq!(deps, vec!["port = 8080 at 4:3"]);
+        let e = explain(&v, "port").unwrap();
+        let deps: Vec<_> = e.deps.iter().map(describe).collect();
+        assert_eq!(deps, vec!["(offset) at 1:13"]);
+        assert!(explain(&v, "name").unwrap().deps.is_empty());
+        assert_eq!(
+            explain(&v, "servers[0]").unwrap_err(),
+            "Path 'servers[0]' does not end with a field name"
+        );
+        assert_eq!(
+            explain(&v, "servers[0].x").unwrap_err(),
+            "Path 'servers[0].x' does not exist"
+        );
+    }
+
+    #[test]
+    fn dependency_graph() {
+        assert_eq!(
+            graph(&eval(SRC)),
+            [
+                "digraph konfi {",
+                "  \"name\";",
+                "  \"port\";",
+                "  \"port\" -> \"offset#1\";",
+                "  \"servers\";",
+                "  \"servers[0].host\";",
+                "  \"servers[0].host\" -> \"name\";",
+                "  \"servers[0].listen\";",
+                "  \"servers[0].listen\" -> \"port\";",
+                "  \"offset#1\" [label=\"offset\", style=dashed];",
+                "}\n",
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -0,0 +1,264 @@
+// Golden-file tests of konfi files (konfi test).
+//
+// A test is a file NAME.konfi whose expected JSON output is in the golden
+// file NAME.expected.json next to it. Files named NAME_test.konfi need no
+// golden file: they pass if they evaluate without errors, so they hold
+// inline checks written as assertions.
+
+use crate::{engine, load, read_input, to_json_string, CliError, CliResult, EngineArgs};
+use konfi::engine::Engine;
+use konfi::{diff, json};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const EXPECTED_SUFFIX: &str = ".expected.json";
+
+// A konfi file to test and its golden file, if it has one.
+#[derive(Debug, PartialEq)]
+struct Test {
+    file: PathBuf,
+    expected: Option<PathBuf>,
+}
+
+impl Test {
+    // The test for `file`, if it is a konfi file. Its golden file need not exist yet.
+    fn of(file: &Path) -> Option<Test> {
+        let name = file.file_name()?.to_str()?;
+        let stem = name.strip_suffix(".konfi")?;
+        let expected = (!stem.ends_with("_test"))
+            .then(|| file.with_file_name(format!("{stem}{EXPECTED_SUFFIX}")));
+        Some(Test {
+            file: file.to_path_buf(),
+            expected,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Pass,
+    Updated,
+    // The error or the differences from the golden file, one per line.
+    Fail(Vec<String>),
+}
+
+// Finds the tests in `paths`. Files are tested as given, even without a
+// golden file. Directories are searched recursively for konfi files that
+// have a golden file or are named NAME_test.konfi.
+fn discover(paths: &[String]) -> CliResult<Vec<Test>> {
+    let mut tests = vec![];
+    for p in paths.iter() {
+        let path = Path::new(p);
+        if path.is_dir() {
+            let mut files = vec![];
+            walk(path, &mut files)
+                .map_err(|e| CliError::Io(format!("Cannot read directory {}: {}", p, e)))?;
+            tests.extend(
+                files
+                    .iter()
+                    .filter_map(|f| Test::of(f))
+                    .filter(|t| t.expected.as_ref().is_none_or(|e| e.exists())),
+            );
+        } else {
+            tests.push(
+                Test::of(path)
+                    .ok_or_else(|| CliError::Io(format!("{} is not a .konfi file", p)))?,
+            );
+        }
+    }
+    Ok(tests)
+}
+
+// Collects all files below `dir` in name order, skipping hidden directories.
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if !path.is_dir() {
+            files.push(path);
+        } else if !path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with('.'))
+        {
+            walk(&path, files)?;
+        }
+    }
+    Ok(())
+}
+
+// Evaluates the konfi file of `t` and compares the result with its golden
+// file. If `update` is set, golden files that differ (or are missing) are
+// rewritten with the result instead.
+fn run_test(t: &Test, engine: &Engine, update: bool) -> Outcome {
+    match check(t, engine, update) {
+        Ok(o) => o,
+        Err(e) => Outcome::Fail(e.message().lines().map(str::to_string).collect()),
+    }
+}
+
+fn check(t: &Test, engine: &Engine, update: bool) -> CliResult<Outcome> {
+    let file = t.file.display().to_string();
+    let actual = to_json_string(&load(engine, &file, &read_input(&file)?)?)?;
+    let Some(expected_file) = &t.expected else {
+        return Ok(Outcome::Pass);
+    };
+    let expected_name = expected_file.display().to_string();
+    let expected = match fs::read_to_string(expected_file) {
+        Ok(s) => Some(s),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            return Err(CliError::Io(format!(
+                "Cannot read {}: {}",
+                expected_name, e
+            )))
+        }
+    };
+    // The differences from the golden file, or the reason why there are none.
+    let diffs = match expected {
+        Some(expected) => match serde_json::from_str(&expected) {
+            Ok(expected) => {
+                let actual = serde_json::from_str(&actual).expect("konfi output is valid JSON");
+                Ok(diff::diff(
+                    &json::from_json(&expected),
+                    &json::from_json(&actual),
+                ))
+            }
+            Err(e) => Err(CliError::Parse(format!(
+                "Cannot parse {}: {}",
+                expected_name, e
+            ))),
+        },
+        None => Err(CliError::Io(format!(
+            "Missing golden file {}, run with --update to create it",
+            expected_name
+        ))),
+    };
+    match diffs {
+        Ok(diffs) if diffs.is_empty() => Ok(Outcome::Pass),
+        _ if update => {
+            fs::write(expected_file, actual + "\n")
+                .map_err(|e| CliError::Io(format!("Cannot write {}: {}", expected_name, e)))?;
+            Ok(Outcome::Updated)
+        }
+        Ok(diffs) => Ok(Outcome::Fail(diffs.iter().map(|d| d.to_string()).collect())),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn run(paths: &[String], update: bool, opts: &EngineArgs) -> CliResult<()> {
+    let tests = discover(paths)?;
+    if tests.is_empty() {
+        return Err(CliError::Io(format!(
+            "No tests found in {}",
+            paths.join(", ")
+        )));
+    }
+    let (mut failed, mut updated) = (0, 0);
+    for t in tests.iter() {
+        let engine = engine(opts, &t.file.display().to_string())?;
+        match run_test(t, &engine, update) {
+            Outcome::Pass => println!("ok      {}", t.file.display()),
+            Outcome::Updated => {
+                updated += 1;
+                println!("updated {}", t.file.display());
+            }
+            Outcome::Fail(lines) => {
+                failed += 1;
+                println!("FAIL    {}", t.file.display());
+                if t.expected.is_some() && lines.iter().all(|l| l.starts_with(['+', '-', '~'])) {
+                    println!("  (- expected, + actual)");
+                }
+                for l in lines {
+                    println!("  {}", l);
+                }
+            }
+        }
+    }
+    println!(
+        "{} test(s): {} passed, {} failed, {} updated",
+        tests.len(),
+        tests.len() - failed - updated,
+        failed,
+        updated
+    );
+    if failed > 0 {
+        return Err(CliError::Check(format!(
+            "{} of {} test(s) failed",
+            failed,
+            tests.len()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_files() {
+        let dir = std::env::temp_dir().join(format!("konfi-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let write = |f: &str, s: &str| fs::write(dir.join(f), s).unwrap();
+        write("a.konfi", "{\n  port: 80\n  hosts: [\"a\"]\n}");
+        write("a.expected.json", "{\"hosts\": [\"a\"], \"port\": 80}");
+        write("sub/b.konfi", "{\n  port: 8080\n  timeout: 30s\n}");
+        write(
+            "sub/b.expected.json",
+            "{\"port\": 80, \"timeout\": \"30s\"}",
+        );
+        write(
+            "sub/c_test.konfi",
+            "let x = 1\nassert x == 2 : \"x is not 2\"\nx",
+        );
+        write("no_golden.konfi", "1");
+
+        let paths = [dir.display().to_string()];
+        let tests = discover(&paths).unwrap();
+        let files: Vec<_> = tests.iter().map(|t| t.file.clone()).collect();
+        assert_eq!(
+            files,
+            vec![
+                dir.join("a.konfi"),
+                dir.join("sub/b.konfi"),
+                dir.join("sub/c_test.konfi")
+            ]
+        );
+        let engine = Engine::new();
+        let outcomes: Vec<_> = tests.iter().map(|t| run_test(t, &engine, false)).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcome::Pass,
+                Outcome::Fail(vec!["~ port: int 80 -> int 8080".to_string()]),
+                Outcome::Fail(vec![
+                    format!(
+                        "Cannot eval {}: 1 assertion(s) failed:",
+                        dir.join("sub/c_test.konfi").display()
+                    ),
+                    "  2:1: x is not 2".to_string()
+                ]),
+            ]
+        );
+        let no_golden = Test::of(&dir.join("no_golden.konfi")).unwrap();
+        assert!(matches!(
+            run_test(&no_golden, &engine, false),
+            Outcome::Fail(_)
+        ));
+
+        // Updating rewrites (or creates) the golden files that differ, but
+        // keeps those that are only formatted differently.
+        assert_eq!(run_test(&tests[0], &engine, true), Outcome::Pass);
+        assert_eq!(run_test(&tests[1], &engine, true), Outcome::Updated);
+        assert_eq!(run_test(&tests[1], &engine, false), Outcome::Pass);
+        assert_eq!(run_test(&no_golden, &engine, true), Outcome::Updated);
+        assert_eq!(
+            fs::read_to_string(dir.join("no_golden.expected.json")).unwrap(),
+            "1\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
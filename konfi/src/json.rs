@@ -1,5 +1,8 @@
 use serde_json::{Value, Number, Map};
-use crate::eval::Val;
+use crate::eval::{self, Val};
+use crate::units;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct SerializationError {
@@ -12,11 +15,18 @@ pub fn to_json(v: &Val) -> Result<Value, SerializationError> {
         Val::Rec(r) => {
             let mut m = Map::new();
             let r = &*r.borrow();
-            for (f, fv) in r.fields.iter() {
+            for (f, fv) in r.visible() {
                 m.insert(f.clone(), to_json(fv)?);
             }
             Ok(Value::Object(m))
         }
+        Val::List(l) => {
+            let mut vs = Vec::with_capacity(l.len());
+            for v in l.iter() {
+                vs.push(to_json(v)?);
+            }
+            Ok(Value::Array(vs))
+        }
         Val::Bool(b) => Ok(Value::Bool(*b)),
         Val::Int(i) => Ok(Value::Number(Number::from(*i))),
         Val::Double(d) => match Number::from_f64(*d) {
@@ -24,7 +34,33 @@ pub fn to_json(v: &Val) -> Result<Value, SerializationError> {
             None => Err(SerializationError{message: format!("Cannot serialize Double({})", *d)})
         },
         Val::Str(s) => Ok(Value::String(s.clone())),
-        Val::Timestamp(_) => todo!(),
-        Val::Duration(_) => todo!(),
+        Val::Size(s) => Ok(Value::Number(Number::from(*s))),
+        Val::Timestamp(t) => Ok(Value::String(eval::format_timestamp(t))),
+        // Durations become strings like "1h30m", as in konfi source.
+        Val::Duration(d) => Ok(Value::String(units::format_duration(eval::nanos(d)))),
+        Val::NativeFn(nf) => Err(SerializationError {
+            message: format!("Cannot serialize function {}", nf.name),
+        }),
     }
-}
\ No newline at end of file
+}
+// Converts JSON to the corresponding konfi value. Numbers become ints if
+// they fit into an i64, and doubles otherwise.
+pub fn from_json(v: &Value) -> Val {
+    match v {
+        Value::Null => Val::Nil,
+        Value::Bool(b) => Val::Bool(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => Val::Int(i),
+            None => Val::Double(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => Val::Str(s.clone()),
+        Value::Array(vs) => Val::List(vs.iter().map(from_json).collect()),
+        Value::Object(m) => {
+            let mut r = eval::Rec::new();
+            for (f, fv) in m.iter() {
+                r.setattr(f, from_json(fv));
+            }
+            Val::Rec(Rc::new(RefCell::new(r)))
+        }
+    }
+}
@@ -1,5 +1,16 @@
 pub mod ast;
+pub mod builtins;
+pub mod de;
+pub mod diff;
+pub mod engine;
 pub mod parser;
 pub mod strings;
 pub mod eval;
+pub mod explain;
 pub mod json;
+pub mod lint;
+pub mod schema;
+pub mod units;
+
+pub use de::{from_path, from_str, Error, ErrorKind};
+pub use engine::{Engine, EngineBuilder};
@@ -0,0 +1,441 @@
+// Static checks of konfi modules (konfi lint).
+//
+// The linter only looks at the syntax of a module, it does not evaluate it.
+// Variables are resolved like `Ctx::lookup` does: to the innermost let
+// binding, lambda parameter, comprehension variable or record field of that
+// name. Bindings whose name starts with an underscore are never reported as
+// unused.
+
+use crate::ast;
+use crate::eval::{self, Ctx, Val};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    UnusedLet,
+    UnusedImport,
+    Shadowing,
+    ConstantComparison,
+    DuplicateField,
+    RedundantOverride,
+}
+
+impl Check {
+    /// The name of the check in reports, e.g. "unused-let".
+    pub fn code(&self) -> &'static str {
+        match self {
+            Check::UnusedLet => "unused-let",
+            Check::UnusedImport => "unused-import",
+            Check::Shadowing => "shadowing",
+            Check::ConstantComparison => "constant-comparison",
+            Check::DuplicateField => "duplicate-field",
+            Check::RedundantOverride => "redundant-override",
+        }
+    }
+}
+
+/// A problem found by the linter.
+#[derive(Debug, PartialEq)]
+pub struct Warning {
+    pub check: Check,
+    pub message: String,
+    pub pos: ast::Pos,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.check.code(), self.message)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Import,
+    Let,
+    Field,
+    // A lambda parameter or comprehension variable.
+    Param,
+}
+
+struct Binding<'a> {
+    name: &'a str,
+    kind: Kind,
+    pos: ast::Pos,
+    used: bool,
+}
+
+struct Linter<'a> {
+    src: &'a str,
+    scopes: Vec<Vec<Binding<'a>>>,
+    // Position of the enclosing field, let binding or assertion, for
+    // warnings about expressions, which have no position of their own.
+    at: ast::Pos,
+    warnings: Vec<Warning>,
+}
+
+/// Checks `m`, parsed from `src`, and returns the warnings in source order.
+pub fn lint(m: &ast::Module, src: &str) -> Vec<Warning> {
+    let mut l = Linter {
+        src,
+        scopes: vec![],
+        at: ast::Pos::default(),
+        warnings: vec![],
+    };
+    let mut scope: Vec<Binding> = m
+        .imports
+        .iter()
+        .map(|i| Binding::new(&i.var, Kind::Import))
+        .collect();
+    scope.extend(m.let_vars.iter().map(|lb| Binding::new(&lb.var, Kind::Let)));
+    l.push(scope);
+    for lb in m.let_vars.iter() {
+        l.at = lb.var.pos;
+        l.expr(&lb.value);
+    }
+    l.asserts(&m.asserts);
+    l.expr(&m.expr);
+    l.pop();
+    // Positions count the bytes left, so later positions are smaller.
+    l.warnings.sort_by_key(|w| std::cmp::Reverse(w.pos.rem));
+    l.warnings
+}
+
+impl<'a> Binding<'a> {
+    fn new(v: &'a ast::Var, kind: Kind) -> Self {
+        Binding {
+            name: &v.name,
+            kind,
+            pos: v.pos,
+            used: false,
+        }
+    }
+}
+
+impl<'a> Linter<'a> {
+    fn warn(&mut self, check: Check, pos: ast::Pos, message: String) {
+        self.warnings.push(Warning {
+            check,
+            message,
+            pos,
+        });
+    }
+
+    fn line_col(&self, pos: ast::Pos) -> String {
+        let (line, col) = pos.line_col(self.src);
+        format!("{line}:{col}")
+    }
+
+    // The innermost field named `name` in the scopes before `depth`.
+    fn outer_field(&self, name: &str, depth: usize) -> Option<ast::Pos> {
+        self.scopes[..depth]
+            .iter()
+            .rev()
+            .find_map(|s| s.iter().find(|b| b.kind == Kind::Field && b.name == name))
+            .map(|b| b.pos)
+    }
+
+    fn push(&mut self, scope: Vec<Binding<'a>>) {
+        for b in scope.iter().filter(|b| b.kind != Kind::Field) {
+            if let Some(pos) = self.outer_field(b.name, self.scopes.len()) {
+                let at = self.line_col(pos);
+                self.warn(
+                    Check::Shadowing,
+                    b.pos,
+                    format!("'{}' shadows the field '{}' at {}", b.name, b.name, at),
+                );
+            }
+        }
+        self.scopes.push(scope);
+    }
+
+    fn pop(&mut self) {
+        let scope = self.scopes.pop().expect("scopes are balanced");
+        for b in scope.iter().filter(|b| !b.used && !b.name.starts_with('_')) {
+            let (check, what) = match b.kind {
+                Kind::Let => (Check::UnusedLet, "let binding"),
+                Kind::Import => (Check::UnusedImport, "import"),
+                Kind::Field | Kind::Param => continue,
+            };
+            self.warn(check, b.pos, format!("{} '{}' is never used", what, b.name));
+        }
+    }
+
+    fn use_var(&mut self, v: &ast::Var) {
+        let found = self
+            .scopes
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(depth, s)| s.iter().position(|b| b.name == v.name).map(|i| (depth, i)));
+        let Some((depth, i)) = found else {
+            // A builtin, or an undefined variable.
+            return;
+        };
+        let b = &mut self.scopes[depth][i];
+        b.used = true;
+        let (kind, pos) = (b.kind, b.pos);
+        if kind == Kind::Field {
+            if let Some(outer) = self.outer_field(&v.name, depth) {
+                let (at, outer) = (self.line_col(pos), self.line_col(outer));
+                self.warn(
+                    Check::Shadowing,
+                    v.pos,
+                    format!(
+                        "'{}' refers to the field at {}, which shadows the field '{}' at {}",
+                        v.name, at, v.name, outer
+                    ),
+                );
+            }
+        }
+    }
+
+    fn asserts(&mut self, asserts: &'a [ast::Assert]) {
+        for a in asserts.iter() {
+            self.at = a.pos;
+            self.expr(&a.cond);
+            if let Some(m) = &a.message {
+                self.expr(m);
+            }
+        }
+    }
+
+    fn rec(&mut self, r: &'a ast::Rec) {
+        let mut first: HashMap<&str, &ast::Field> = HashMap::new();
+        for f in r.fields.iter() {
+            let Some(prev) = first.get(f.name.as_str()) else {
+                first.insert(&f.name, f);
+                continue;
+            };
+            let at = self.line_col(prev.pos);
+            if prev.value == f.value {
+                self.warn(
+                    Check::RedundantOverride,
+                    f.pos,
+                    format!("field '{}' is set to the same value as at {}", f.name, at),
+                );
+            } else {
+                self.warn(
+                    Check::DuplicateField,
+                    f.pos,
+                    format!(
+                        "field '{}' is already defined at {}, which takes precedence",
+                        f.name, at
+                    ),
+                );
+            }
+        }
+        let mut scope: Vec<Binding> = r
+            .let_vars
+            .iter()
+            .map(|lb| Binding::new(&lb.var, Kind::Let))
+            .collect();
+        scope.extend(r.fields.iter().map(|f| Binding {
+            name: &f.name,
+            kind: Kind::Field,
+            pos: f.pos,
+            used: false,
+        }));
+        self.push(scope);
+        for lb in r.let_vars.iter() {
+            self.at = lb.var.pos;
+            self.expr(&lb.value);
+        }
+        for f in r.fields.iter() {
+            self.at = f.pos;
+            self.expr(&f.value);
+        }
+        self.asserts(&r.asserts);
+        self.pop();
+    }
+
+    // Checks the parts of the comprehension `c` that are evaluated for each
+    // element: `body` and the condition.
+    fn comp(&mut self, body: &[&'a ast::Expr], c: &'a ast::Comp) {
+        self.expr(&c.iter);
+        let vars = c
+            .vars
+            .iter()
+            .map(|v| Binding::new(v, Kind::Param))
+            .collect();
+        self.push(vars);
+        if let Some(cond) = &c.cond {
+            self.expr(cond);
+        }
+        for e in body.iter() {
+            self.expr(e);
+        }
+        self.pop();
+    }
+
+    fn comparison(&mut self, l: &'a ast::Expr, op: ast::BinOp, r: &'a ast::Expr) {
+        use ast::BinOp::*;
+        if !matches!(op, LessThan | GreaterThan | LessEq | GreaterEq | Eq | NotEq) {
+            return;
+        }
+        let message = if is_const(l) && is_const(r) {
+            let e = ast::Expr::BinExpr(Box::new(l.clone()), op, Box::new(r.clone()));
+            match eval::eval(&e, Ctx::global()) {
+                Ok(Val::Bool(b)) => format!("comparison of constants is always {}", b),
+                Ok(_) => return,
+                Err(e) => format!("comparison of constants always fails: {}", e.message),
+            }
+        } else if l == r {
+            format!(
+                "both sides of '{}' are the same, so it is always {}",
+                op.symbol(),
+                matches!(op, LessEq | GreaterEq | Eq)
+            )
+        } else {
+            return;
+        };
+        self.warn(Check::ConstantComparison, self.at, message);
+    }
+
+    fn expr(&mut self, e: &'a ast::Expr) {
+        match e {
+            ast::Expr::Literal(_) => {}
+            ast::Expr::Var(v) => self.use_var(v),
+            ast::Expr::FieldAcc(e, _) | ast::Expr::OptFieldAcc(e, _) | ast::Expr::UnExpr(_, e) => {
+                self.expr(e)
+            }
+            ast::Expr::BinExpr(l, op, r) => {
+                self.comparison(l, *op, r);
+                self.expr(l);
+                self.expr(r);
+            }
+            ast::Expr::Index(l, r) => {
+                self.expr(l);
+                self.expr(r);
+            }
+            ast::Expr::Rec(r) => self.rec(r),
+            ast::Expr::List(es) => es.iter().for_each(|e| self.expr(e)),
+            ast::Expr::Call(c) => {
+                self.expr(&c.fun);
+                c.args.iter().for_each(|a| self.expr(a));
+            }
+            ast::Expr::Fun(f) => {
+                let params = f
+                    .params
+                    .iter()
+                    .map(|p| Binding::new(p, Kind::Param))
+                    .collect();
+                self.push(params);
+                self.expr(&f.body);
+                self.pop();
+            }
+            ast::Expr::ListComp(e, c) => self.comp(&[e], c),
+            ast::Expr::RecComp(k, v, c) => self.comp(&[k, v], c),
+        }
+    }
+}
+
+// Whether `e` is a literal, possibly with unary operators.
+fn is_const(e: &ast::Expr) -> bool {
+    match e {
+        ast::Expr::Literal(_) => true,
+        ast::Expr::UnExpr(_, e) => is_const(e),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_module;
+
+    fn lint_str(src: &str) -> Vec<String> {
+        let m = parse_module(src).ok().unwrap();
+        lint(&m, src)
+            .iter()
+            .map(|w| {
+                let (line, col) = w.pos.line_col(src);
+                format!("{line}:{col}: {w}")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unused_bindings() {
+        let src = r#"import "a.konfi" as a
+import "b.konfi" as b
+let x = 1
+let y = x
+let _z = 3
+{
+  p: b.port
+  f: n => n * 2
+}"#;
+        assert_eq!(
+            lint_str(src),
+            vec![
+                "1:21: unused-import: import 'a' is never used",
+                "4:5: unused-let: let binding 'y' is never used",
+            ]
+        );
+        assert!(lint_str("let x = 1\nassert x > 0\n{}").is_empty());
+    }
+
+    #[test]
+    fn shadowing() {
+        let src = r#"{
+  port: 80
+  ports: [port + 1 for port in [1, 2]]
+  server: {
+    port: 8080
+    url: str.format("http://localhost:{}", port)
+  }
+  other: {host: "h", p: port}
+}"#;
+        assert_eq!(
+            lint_str(src),
+            vec![
+                "3:24: shadowing: 'port' shadows the field 'port' at 2:3",
+                "6:44: shadowing: 'port' refers to the field at 5:5, which shadows the field 'port' at 2:3",
+            ]
+        );
+    }
+
+    #[test]
+    fn constant_comparisons() {
+        let src = r#"let n = 1
+{
+  a: 1 == 1
+  b: n < n
+  c: n != -n
+  d: "x" < 2
+}"#;
+        let warnings = lint_str(src);
+        assert_eq!(warnings.len(), 3);
+        assert_eq!(
+            warnings[0],
+            "3:3: constant-comparison: comparison of constants is always true"
+        );
+        assert_eq!(
+            warnings[1],
+            "4:3: constant-comparison: both sides of '<' are the same, so it is always false"
+        );
+        assert!(warnings[2]
+            .starts_with("6:3: constant-comparison: comparison of constants always fails: "));
+    }
+
+    #[test]
+    fn duplicate_fields() {
+        let src = "{\n  a: 1\n  b: 2\n  a: 3\n  b: 2\n}";
+        assert_eq!(
+            lint_str(src),
+            vec![
+                "4:3: duplicate-field: field 'a' is already defined at 2:3, which takes precedence",
+                "5:3: redundant-override: field 'b' is set to the same value as at 3:3",
+            ]
+        );
+    }
+
+    #[test]
+    fn long_chains() {
+        // The parser bounds how deeply chains nest the AST, and so the
+        // recursion of the linter.
+        let src = vec!["x"; crate::parser::MAX_CHAIN].join(" + ");
+        assert!(lint_str(&format!("let x = 1\n{src}")).is_empty());
+    }
+}
@@ -0,0 +1,874 @@
+// Language server for konfi files (konfi lsp), speaking LSP over stdio.
+
+use konfi::engine::{Engine, FileResolver, Limits};
+use konfi::{ast, builtins, eval, parser};
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as LspNotification, PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, Request as LspRequest};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, Diagnostic,
+    DiagnosticSeverity, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents,
+    HoverParams, HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+    PublishDiagnosticsParams, Range, ServerCapabilities, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Url,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::time::Duration;
+
+// Longest value representation shown on hover.
+const MAX_HOVER_VALUE: usize = 1000;
+
+// Documents are evaluated on every change, so runaway evaluations must stop
+// before they block the server.
+const MAX_STEPS: u64 = 500_000;
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// Converts a byte offset in `text` into an LSP position (UTF-16 based).
+fn to_position(text: &str, offset: usize) -> Position {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
+}
+
+// Converts an LSP position into a byte offset in `text`.
+fn to_offset(text: &str, pos: Position) -> usize {
+    let mut offset = 0;
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        if i as u32 == pos.line {
+            let mut units = 0;
+            for (j, c) in line.char_indices() {
+                if units >= pos.character as usize || c == '\n' {
+                    return offset + j;
+                }
+                units += c.len_utf16();
+            }
+            return offset + line.len();
+        }
+        offset += line.len();
+    }
+    offset
+}
+
+// The range of the identifier `name` starting at `pos`.
+fn ident_range(text: &str, pos: ast::Pos, name: &str) -> Range {
+    let start = pos.offset(text);
+    Range {
+        start: to_position(text, start),
+        end: to_position(text, start + name.len()),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DefKind {
+    Field,
+    Let,
+    Import,
+    // A lambda parameter or comprehension variable.
+    Param,
+}
+
+// A field, let binding, import or parameter in the source.
+#[derive(Clone, Copy, Debug)]
+struct Def<'m> {
+    name: &'m str,
+    kind: DefKind,
+    pos: ast::Pos,
+}
+
+// The identifier under the cursor and the definition it refers to.
+// Variables that are not defined in the document (e.g. builtins) have no definition.
+struct Symbol<'m> {
+    name: &'m str,
+    pos: ast::Pos,
+    def: Option<Def<'m>>,
+}
+
+// The bindings introduced by a module, record, lambda or comprehension.
+#[derive(Default)]
+struct Scope<'m> {
+    imports: &'m [ast::Import],
+    lets: &'m [ast::LetBinding],
+    fields: &'m [ast::Field],
+    params: &'m [ast::Var],
+}
+
+impl<'m> Scope<'m> {
+    // Looks up `name` the way eval::Ctx does: parameters, let bindings (which
+    // may shadow imports), then fields.
+    fn lookup(&self, name: &str) -> Option<Def<'m>> {
+        if let Some(p) = self.params.iter().find(|p| p.name == name) {
+            return Some(Def {
+                name: &p.name,
+                kind: DefKind::Param,
+                pos: p.pos,
+            });
+        }
+        if let Some(lb) = self.lets.iter().find(|lb| lb.var.name == name) {
+            return Some(Def {
+                name: &lb.var.name,
+                kind: DefKind::Let,
+                pos: lb.var.pos,
+            });
+        }
+        if let Some(imp) = self.imports.iter().find(|imp| imp.var.name == name) {
+            return Some(Def {
+                name: &imp.var.name,
+                kind: DefKind::Import,
+                pos: imp.var.pos,
+            });
+        }
+        self.fields.iter().find(|f| f.name == name).map(|f| Def {
+            name: &f.name,
+            kind: DefKind::Field,
+            pos: f.pos,
+        })
+    }
+}
+
+// Finds the symbol at a byte offset by walking the AST, keeping track of the
+// enclosing scopes to resolve variables from the inside out.
+struct Finder<'m, 't> {
+    text: &'t str,
+    offset: usize,
+    scopes: Vec<Scope<'m>>,
+    found: Option<Symbol<'m>>,
+}
+
+impl<'m, 't> Finder<'m, 't> {
+    fn hits(&self, pos: ast::Pos, name: &str) -> bool {
+        let start = pos.offset(self.text);
+        start <= self.offset && self.offset <= start + name.len()
+    }
+
+    fn resolve(&self, name: &str) -> Option<Def<'m>> {
+        self.scopes.iter().rev().find_map(|s| s.lookup(name))
+    }
+
+    fn define(&mut self, def: Def<'m>) {
+        if self.found.is_none() && self.hits(def.pos, def.name) {
+            self.found = Some(Symbol {
+                name: def.name,
+                pos: def.pos,
+                def: Some(def),
+            });
+        }
+    }
+
+    fn scope(
+        &mut self,
+        lets: &'m [ast::LetBinding],
+        fields: &'m [ast::Field],
+        asserts: &'m [ast::Assert],
+    ) {
+        self.scopes.push(Scope {
+            lets,
+            fields,
+            ..Default::default()
+        });
+        for lb in lets.iter() {
+            self.define(Def {
+                name: &lb.var.name,
+                kind: DefKind::Let,
+                pos: lb.var.pos,
+            });
+            self.expr(&lb.value);
+        }
+        for f in fields.iter() {
+            self.define(Def {
+                name: &f.name,
+                kind: DefKind::Field,
+                pos: f.pos,
+            });
+            self.expr(&f.value);
+        }
+        self.asserts(asserts);
+        self.scopes.pop();
+    }
+
+    fn asserts(&mut self, asserts: &'m [ast::Assert]) {
+        for a in asserts.iter() {
+            self.expr(&a.cond);
+            if let Some(m) = &a.message {
+                self.expr(m);
+            }
+        }
+    }
+
+    fn module(&mut self, m: &'m ast::Module) {
+        // The module's imports and let bindings are visible in its expression.
+        self.scopes.push(Scope {
+            imports: &m.imports,
+            lets: &m.let_vars,
+            ..Default::default()
+        });
+        for imp in m.imports.iter() {
+            self.define(Def {
+                name: &imp.var.name,
+                kind: DefKind::Import,
+                pos: imp.var.pos,
+            });
+        }
+        for lb in m.let_vars.iter() {
+            self.define(Def {
+                name: &lb.var.name,
+                kind: DefKind::Let,
+                pos: lb.var.pos,
+            });
+            self.expr(&lb.value);
+        }
+        self.expr(&m.expr);
+        self.asserts(&m.asserts);
+        self.scopes.pop();
+    }
+
+    // Walks `exprs` in a scope where `params` are bound.
+    fn params(&mut self, params: &'m [ast::Var], exprs: &[&'m ast::Expr]) {
+        self.scopes.push(Scope {
+            params,
+            ..Default::default()
+        });
+        for p in params.iter() {
+            self.define(Def {
+                name: &p.name,
+                kind: DefKind::Param,
+                pos: p.pos,
+            });
+        }
+        exprs.iter().for_each(|e| self.expr(e));
+        self.scopes.pop();
+    }
+
+    // The variables of a comprehension are bound in `exprs` and its
+    // condition, but not in the iterated expression.
+    fn comp(&mut self, c: &'m ast::Comp, exprs: &[&'m ast::Expr]) {
+        self.expr(&c.iter);
+        let mut exprs = exprs.to_vec();
+        exprs.extend(c.cond.as_deref());
+        self.params(&c.vars, &exprs);
+    }
+
+    fn expr(&mut self, e: &'m ast::Expr) {
+        if self.found.is_some() {
+            return;
+        }
+        match e {
+            ast::Expr::Literal(_) => {}
+            ast::Expr::Var(v) => {
+                if self.hits(v.pos, &v.name) {
+                    self.found = Some(Symbol {
+                        name: &v.name,
+                        pos: v.pos,
+                        def: self.resolve(&v.name),
+                    });
+                }
+            }
+            ast::Expr::FieldAcc(e, _) | ast::Expr::OptFieldAcc(e, _) | ast::Expr::UnExpr(_, e) => {
+                self.expr(e)
+            }
+            ast::Expr::BinExpr(l, _, r) | ast::Expr::Index(l, r) => {
+                self.expr(l);
+                self.expr(r);
+            }
+            ast::Expr::Rec(r) => self.scope(&r.let_vars, &r.fields, &r.asserts),
+            ast::Expr::List(es) => es.iter().for_each(|e| self.expr(e)),
+            ast::Expr::Call(c) => {
+                self.expr(&c.fun);
+                c.args.iter().for_each(|a| self.expr(a));
+            }
+            ast::Expr::Fun(f) => self.params(&f.params, &[&f.body]),
+            ast::Expr::ListComp(e, c) => self.comp(c, &[e]),
+            ast::Expr::RecComp(k, v, c) => self.comp(c, &[k, v]),
+        }
+    }
+}
+
+// Collects the names of all fields and let bindings in `m`.
+fn collect_defs<'m>(e: &'m ast::Expr, defs: &mut BTreeMap<&'m str, DefKind>) {
+    match e {
+        ast::Expr::Literal(_) | ast::Expr::Var(_) => {}
+        ast::Expr::Fun(f) => collect_defs(&f.body, defs),
+        ast::Expr::ListComp(e, c) => {
+            collect_defs(e, defs);
+            collect_defs(&c.iter, defs);
+        }
+        ast::Expr::RecComp(k, v, c) => {
+            collect_defs(k, defs);
+            collect_defs(v, defs);
+            collect_defs(&c.iter, defs);
+        }
+        ast::Expr::FieldAcc(e, _) | ast::Expr::OptFieldAcc(e, _) | ast::Expr::UnExpr(_, e) => {
+            collect_defs(e, defs)
+        }
+        ast::Expr::BinExpr(l, _, r) | ast::Expr::Index(l, r) => {
+            collect_defs(l, defs);
+            collect_defs(r, defs);
+        }
+        ast::Expr::Rec(r) => {
+            for lb in r.let_vars.iter() {
+                defs.insert(&lb.var.name, DefKind::Let);
+                collect_defs(&lb.value, defs);
+            }
+            for f in r.fields.iter() {
+                // Quoted field names cannot be referred to as variables.
+                if parser::is_ident(&f.name) {
+                    defs.insert(&f.name, DefKind::Field);
+                }
+                collect_defs(&f.value, defs);
+            }
+            for a in r.asserts.iter() {
+                collect_defs(&a.cond, defs);
+                a.message.iter().for_each(|m| collect_defs(m, defs));
+            }
+        }
+        ast::Expr::List(es) => es.iter().for_each(|e| collect_defs(e, defs)),
+        ast::Expr::Call(c) => {
+            collect_defs(&c.fun, defs);
+            c.args.iter().for_each(|a| collect_defs(a, defs));
+        }
+    }
+}
+
+// Finds the value of the field defined at `pos` in the evaluated value `v`.
+fn field_value(v: &eval::Val, name: &str, pos: ast::Pos) -> Option<eval::Val> {
+    match v {
+        eval::Val::Rec(r) => {
+            let r = r.borrow();
+            if r.getloc(name).is_some_and(|p| p.rem == pos.rem) {
+                return r.getattr(name);
+            }
+            r.fields.values().find_map(|fv| field_value(fv, name, pos))
+        }
+        eval::Val::List(vs) => vs.iter().find_map(|fv| field_value(fv, name, pos)),
+        _ => None,
+    }
+}
+
+// The result of parsing and evaluating one document.
+#[derive(Default)]
+struct Analysis {
+    module: Option<ast::Module>,
+    value: Option<eval::Val>,
+    // Values of the module's imports and let bindings, by position of their variable.
+    let_vals: HashMap<usize, eval::Val>,
+    errors: Vec<(ast::Pos, String)>,
+}
+
+// Parses and evaluates `text`. Imports are resolved relative to the
+// document's file, if it has one, and cannot leave its directory.
+fn analyze(text: &str, path: Option<&str>) -> Analysis {
+    let mut a = Analysis::default();
+    let m = match parser::parse_module(text) {
+        Ok(m) => m,
+        Err(e) => {
+            a.errors
+                .extend(e.errors.into_iter().map(|e| (e.pos, e.message)));
+            return a;
+        }
+    };
+    let mut b = Engine::builder().limits(Limits {
+        max_steps: Some(MAX_STEPS),
+        timeout: Some(TIMEOUT),
+        ..Default::default()
+    });
+    // Documents without a file have no directory to import from.
+    if let Some(p) = path {
+        b = b.import_resolver(FileResolver::new(super::import_base(p)));
+    }
+    let engine = b.build();
+    let result = eval::eval_module_with(&m, text, engine.context(path), |scope| {
+        // Remember the values of imports and let bindings for hovers.
+        let vars = m.imports.iter().map(|imp| &imp.var);
+        for var in vars.chain(m.let_vars.iter().map(|lb| &lb.var)) {
+            if let Some(v) = scope.getval(&var.name) {
+                a.let_vals.insert(var.pos.rem, v);
+            }
+        }
+    });
+    match result {
+        Ok(v) => a.value = Some(v),
+        Err(e) => a
+            .errors
+            .push((e.pos.unwrap_or(ast::Pos { rem: text.len() }), e.message)),
+    }
+    a.module = Some(m);
+    a
+}
+
+struct Document {
+    text: String,
+    // The document's file, if it is one.
+    path: Option<String>,
+    analysis: Analysis,
+    // The last version of the document that parsed, used for completion
+    // while the user is typing.
+    last_module: Option<ast::Module>,
+}
+
+impl Document {
+    fn new(text: String, path: Option<String>) -> Self {
+        let analysis = analyze(&text, path.as_deref());
+        Document {
+            text,
+            path,
+            analysis,
+            last_module: None,
+        }
+    }
+
+    fn update(&mut self, text: String) {
+        let mut analysis = analyze(&text, self.path.as_deref());
+        if analysis.module.is_none() {
+            self.last_module = self.analysis.module.take().or(self.last_module.take());
+        }
+        std::mem::swap(&mut self.analysis, &mut analysis);
+        self.text = text;
+    }
+
+    fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.analysis
+            .errors
+            .iter()
+            .map(|(pos, message)| {
+                // Underline the identifier (or character) at the error position.
+                let start = pos.offset(&self.text);
+                let rest = &self.text[start..];
+                let len = match rest.find(|c: char| !(c.is_alphanumeric() || c == '_')) {
+                    Some(0) => rest.chars().next().map_or(0, char::len_utf8),
+                    Some(n) => n,
+                    None => rest.len(),
+                };
+                Diagnostic {
+                    range: Range {
+                        start: to_position(&self.text, start),
+                        end: to_position(&self.text, start + len),
+                    },
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    source: Some("konfi".to_string()),
+                    message: message.clone(),
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    fn symbol_at(&self, position: Position) -> Option<Symbol<'_>> {
+        let m = self.analysis.module.as_ref()?;
+        let mut f = Finder {
+            text: &self.text,
+            offset: to_offset(&self.text, position),
+            scopes: vec![],
+            found: None,
+        };
+        f.module(m);
+        f.found
+    }
+
+    fn hover(&self, position: Position) -> Option<Hover> {
+        let sym = self.symbol_at(position)?;
+        let (kind, val) = match sym.def {
+            Some(d) if d.kind == DefKind::Field => (
+                "field",
+                self.analysis
+                    .value
+                    .as_ref()
+                    .and_then(|v| field_value(v, d.name, d.pos)),
+            ),
+            // Parameters have a value per call or iteration, not one to show.
+            Some(d) if d.kind == DefKind::Param => ("param", None),
+            Some(d) => (
+                if d.kind == DefKind::Import {
+                    "import"
+                } else {
+                    "let"
+                },
+                self.analysis.let_vals.get(&d.pos.rem).cloned(),
+            ),
+            None => ("builtin", eval::Ctx::global().getval(sym.name)),
+        };
+        let mut value = match &val {
+            Some(v) => format!("{}: {}\n```konfi\n{}\n```", kind, v.typ(), v),
+            None if kind == "param" => kind.to_string(),
+            None => format!("{}: (not evaluated)", kind),
+        };
+        if value.len() > MAX_HOVER_VALUE {
+            let mut end = MAX_HOVER_VALUE;
+            while !value.is_char_boundary(end) {
+                end -= 1;
+            }
+            value.truncate(end);
+            value.push_str(" ...\n```");
+        }
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("**{}** {}", sym.name, value),
+            }),
+            range: Some(ident_range(&self.text, sym.pos, sym.name)),
+        })
+    }
+
+    fn definition(&self, position: Position) -> Option<Range> {
+        let d = self.symbol_at(position)?.def?;
+        Some(ident_range(&self.text, d.pos, d.name))
+    }
+
+    fn completion(&self, position: Position) -> Vec<CompletionItem> {
+        let offset = to_offset(&self.text, position);
+        let line = &self.text[self.text[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0)..offset];
+        // The identifier being typed, and what precedes it.
+        let prefix = line.trim_end_matches(|c: char| c.is_alphanumeric() || c == '_');
+        if let Some(before_dot) = prefix.strip_suffix('.') {
+            let module = &before_dot[before_dot
+                .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .map(|i| i + 1)
+                .unwrap_or(0)..];
+            if let Some((name, fns)) = builtins::MODULES.iter().find(|(n, _)| *n == module) {
+                return fns
+                    .iter()
+                    .map(|(f, _)| CompletionItem {
+                        label: f.to_string(),
+                        kind: Some(CompletionItemKind::FUNCTION),
+                        detail: Some(format!("{}.{}", name, f)),
+                        ..Default::default()
+                    })
+                    .collect();
+            }
+        }
+        let mut defs = BTreeMap::new();
+        if let Some(m) = self.analysis.module.as_ref().or(self.last_module.as_ref()) {
+            for imp in m.imports.iter() {
+                defs.insert(imp.var.name.as_str(), DefKind::Import);
+            }
+            for lb in m.let_vars.iter() {
+                defs.insert(lb.var.name.as_str(), DefKind::Let);
+                collect_defs(&lb.value, &mut defs);
+            }
+            collect_defs(&m.expr, &mut defs);
+            for a in m.asserts.iter() {
+                collect_defs(&a.cond, &mut defs);
+            }
+        }
+        let after_dot = prefix.ends_with('.');
+        let mut items: Vec<CompletionItem> = defs
+            .into_iter()
+            .filter(|(_, k)| !after_dot || *k == DefKind::Field)
+            .map(|(name, k)| CompletionItem {
+                label: name.to_string(),
+                kind: Some(match k {
+                    DefKind::Field => CompletionItemKind::FIELD,
+                    DefKind::Let | DefKind::Param => CompletionItemKind::VARIABLE,
+                    DefKind::Import => CompletionItemKind::MODULE,
+                }),
+                ..Default::default()
+            })
+            .collect();
+        if !after_dot {
+            items.extend(builtins::MODULES.iter().map(|(name, _)| CompletionItem {
+                label: name.to_string(),
+                kind: Some(CompletionItemKind::MODULE),
+                detail: Some("builtin module".to_string()),
+                ..Default::default()
+            }));
+            items.extend(builtins::FUNCTIONS.iter().map(|(name, _)| CompletionItem {
+                label: name.to_string(),
+                kind: Some(CompletionItemKind::FUNCTION),
+                detail: Some("builtin function".to_string()),
+                ..Default::default()
+            }));
+        }
+        items
+    }
+}
+
+#[derive(Default)]
+struct Server {
+    docs: HashMap<Url, Document>,
+}
+
+type LspResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+impl Server {
+    fn handle_request(&self, req: Request) -> Response {
+        let id = req.id.clone();
+        let result = match req.method.as_str() {
+            HoverRequest::METHOD => serde_json::from_value(req.params).map(|p: HoverParams| {
+                let tdp = p.text_document_position_params;
+                let doc = self.docs.get(&tdp.text_document.uri);
+                serde_json::to_value(doc.and_then(|d| d.hover(tdp.position)))
+            }),
+            GotoDefinition::METHOD => {
+                serde_json::from_value(req.params).map(|p: GotoDefinitionParams| {
+                    let tdp = p.text_document_position_params;
+                    let uri = tdp.text_document.uri;
+                    let loc = self.docs.get(&uri).and_then(|d| d.definition(tdp.position));
+                    serde_json::to_value(
+                        loc.map(|range| GotoDefinitionResponse::Scalar(Location { uri, range })),
+                    )
+                })
+            }
+            Completion::METHOD => serde_json::from_value(req.params).map(|p: CompletionParams| {
+                let tdp = p.text_document_position;
+                let doc = self.docs.get(&tdp.text_document.uri);
+                serde_json::to_value(doc.map(|d| d.completion(tdp.position)))
+            }),
+            _ => {
+                return Response::new_err(
+                    id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("Unsupported request {}", req.method),
+                )
+            }
+        };
+        match result.and_then(|r| r) {
+            Ok(v) => Response::new_ok(id, v),
+            Err(e) => Response::new_err(id, ErrorCode::InvalidParams as i32, e.to_string()),
+        }
+    }
+
+    // Handles a document notification and returns the document's new diagnostics.
+    fn handle_notification(
+        &mut self,
+        n: Notification,
+    ) -> LspResult<Option<PublishDiagnosticsParams>> {
+        let (uri, version) = match n.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let p: lsp_types::DidOpenTextDocumentParams = serde_json::from_value(n.params)?;
+                let td = p.text_document;
+                let doc = Document::new(td.text, file_path(&td.uri));
+                self.docs.insert(td.uri.clone(), doc);
+                (td.uri, Some(td.version))
+            }
+            DidChangeTextDocument::METHOD => {
+                let p: lsp_types::DidChangeTextDocumentParams = serde_json::from_value(n.params)?;
+                let td = p.text_document;
+                // We only support full document sync, so the last change has the full text.
+                if let Some(change) = p.content_changes.into_iter().last() {
+                    match self.docs.get_mut(&td.uri) {
+                        Some(doc) => doc.update(change.text),
+                        None => {
+                            let doc = Document::new(change.text, file_path(&td.uri));
+                            self.docs.insert(td.uri.clone(), doc);
+                        }
+                    }
+                }
+                (td.uri, Some(td.version))
+            }
+            DidCloseTextDocument::METHOD => {
+                let p: lsp_types::DidCloseTextDocumentParams = serde_json::from_value(n.params)?;
+                self.docs.remove(&p.text_document.uri);
+                return Ok(Some(PublishDiagnosticsParams {
+                    uri: p.text_document.uri,
+                    diagnostics: vec![],
+                    version: None,
+                }));
+            }
+            _ => return Ok(None),
+        };
+        let diagnostics = self
+            .docs
+            .get(&uri)
+            .map(|d| d.diagnostics())
+            .unwrap_or_default();
+        Ok(Some(PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version,
+        }))
+    }
+}
+
+// The path of the file behind `uri`, for resolving imports.
+fn file_path(uri: &Url) -> Option<String> {
+    let p = uri.to_file_path().ok()?;
+    Some(p.display().to_string())
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![".".to_string()]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+pub fn run() -> LspResult<()> {
+    let (conn, io_threads) = Connection::stdio();
+    conn.initialize(serde_json::to_value(capabilities())?)?;
+    let mut server = Server::default();
+    for msg in &conn.receiver {
+        match msg {
+            Message::Request(req) => {
+                if conn.handle_shutdown(&req)? {
+                    break;
+                }
+                conn.sender
+                    .send(Message::Response(server.handle_request(req)))?;
+            }
+            Message::Notification(n) => {
+                if let Some(params) = server.handle_notification(n)? {
+                    conn.sender.send(Message::Notification(Notification::new(
+                        PublishDiagnostics::METHOD.to_string(),
+                        params,
+                    )))?;
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+    drop(conn);
+    io_threads.join()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = "let base = 8000
+{
+  port: base + 1
+  name: \"web\"
+  url: str.join([name, \":\"])
+}
+";
+
+    fn pos(line: u32, character: u32) -> Position {
+        Position { line, character }
+    }
+
+    #[test]
+    fn positions() {
+        let text = "a\nbä c";
+        assert_eq!(to_position(text, 5), pos(1, 2));
+        assert_eq!(to_offset(text, pos(1, 2)), 5);
+        assert_eq!(to_offset(text, pos(1, 99)), text.len());
+        assert_eq!(to_offset(text, pos(0, 5)), 1);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let d = Document::new("{\n  a: 1 + \"x\"\n}".to_string(), None);
+        let ds = d.diagnostics();
+        assert_eq!(ds.len(), 1);
+        assert_eq!(
+            ds[0].range,
+            Range {
+                start: pos(1, 2),
+                end: pos(1, 3)
+            }
+        );
+        assert!(ds[0].message.contains("Invalid types"), "{}", ds[0].message);
+        let d = Document::new("{\n  a: \n}".to_string(), None);
+        assert_eq!(d.diagnostics().len(), 1);
+        // All syntax errors are reported at once.
+        let d = Document::new("{\n  a 1\n  b: 2\n  c: )\n}".to_string(), None);
+        let ds = d.diagnostics();
+        assert_eq!(ds.len(), 2);
+        assert_eq!(ds[0].message, "expected ':' after field name, found '1'");
+        assert_eq!(ds[1].range.start, pos(3, 5));
+        assert!(Document::new(SRC.to_string(), None)
+            .diagnostics()
+            .is_empty());
+    }
+
+    #[test]
+    fn hover_and_definition() {
+        let d = Document::new(SRC.to_string(), None);
+        let h = d.hover(pos(4, 18)).expect("hover on `name`");
+        let HoverContents::Markup(m) = h.contents else {
+            panic!("Expected markup, got {:?}", h.contents);
+        };
+        assert_eq!(m.value, "**name** field: str\n```konfi\n\"web\"\n```");
+        let HoverContents::Markup(m) = d.hover(pos(2, 10)).unwrap().contents else {
+            panic!("Expected markup");
+        };
+        assert!(m.value.starts_with("**base** let: int"), "{}", m.value);
+        assert_eq!(
+            d.definition(pos(4, 18)),
+            Some(Range {
+                start: pos(3, 2),
+                end: pos(3, 6)
+            })
+        );
+        assert_eq!(d.definition(pos(2, 10)).unwrap().start, pos(0, 4));
+        // Builtins have no definition in the document.
+        assert!(d.definition(pos(4, 8)).is_none());
+        assert!(d.hover(pos(1, 0)).is_none());
+    }
+
+    #[test]
+    fn limits() {
+        let src = "[x for x in range(1000000)]";
+        let ds = Document::new(src.to_string(), None).diagnostics();
+        assert_eq!(ds.len(), 1);
+        assert_eq!(
+            ds[0].message,
+            "Maximum number of 500000 evaluation steps exceeded"
+        );
+    }
+
+    #[test]
+    fn imports() {
+        let dir = std::env::temp_dir().join(format!("konfi-lsp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.konfi"), "{port: 80}").unwrap();
+        let path = dir.join("main.konfi").display().to_string();
+        let src = "import \"lib.konfi\" as lib\n{port: lib.port}";
+        let d = Document::new(src.to_string(), Some(path));
+        assert!(d.diagnostics().is_empty(), "{:?}", d.diagnostics());
+        let src = "import \"../lib.konfi\" as lib\nlib";
+        let d = Document::new(
+            src.to_string(),
+            Some(dir.join("main.konfi").display().to_string()),
+        );
+        assert!(d.diagnostics()[0]
+            .message
+            .contains("outside of the base directory"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn params() {
+        let d = Document::new("{x: 1, f: (x) => x + 1}".to_string(), None);
+        assert_eq!(d.definition(pos(0, 17)).unwrap().start, pos(0, 11));
+        let d = Document::new("{ys: [y * 2 for y in [1, 2]], y: 3}".to_string(), None);
+        assert_eq!(d.definition(pos(0, 6)).unwrap().start, pos(0, 16));
+        let HoverContents::Markup(m) = d.hover(pos(0, 6)).unwrap().contents else {
+            panic!("Expected markup");
+        };
+        assert_eq!(m.value, "**y** param");
+        // The iterated expression is outside of the comprehension's scope.
+        let d = Document::new("{y: [1], ys: [y for y in y]}".to_string(), None);
+        assert_eq!(d.definition(pos(0, 25)).unwrap().start, pos(0, 1));
+    }
+
+    #[test]
+    fn completion() {
+        let mut d = Document::new(SRC.to_string(), None);
+        let labels = |items: Vec<CompletionItem>| -> Vec<String> {
+            items.into_iter().map(|i| i.label).collect()
+        };
+        let all = labels(d.completion(pos(2, 8)));
+        assert!(all.contains(&"base".to_string()));
+        assert!(all.contains(&"url".to_string()));
+        assert!(all.contains(&"net".to_string()));
+        // Completion keeps working while the document does not parse.
+        d.update(SRC.replace("+ 1", "+ str."));
+        let fns = labels(d.completion(pos(2, 19)));
+        assert!(fns.contains(&"join".to_string()), "{:?}", fns);
+        assert!(!fns.contains(&"base".to_string()));
+        assert!(labels(d.completion(pos(0, 0))).contains(&"port".to_string()));
+    }
+}
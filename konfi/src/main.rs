@@ -1,29 +1,635 @@
-use clap::Parser;
-use konfi::{parser, eval, json};
-use std::{fs, io};
+use clap::error::ErrorKind;
+use clap::{Args as ClapArgs, CommandFactory, Parser, Subcommand, ValueEnum};
+use konfi::engine::{Engine, FileResolver, Limits};
+use konfi::{diff, eval, explain, json, lint, parser, schema, units};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{self, ExitCode};
+use std::time::Duration;
+
+mod golden;
+mod lsp;
+mod repl;
+mod watch;
 
 #[derive(Parser, Debug)]
 #[command(name = "konfi")]
 #[command(author = "Dennis Walter <dennis.walter@gmail.com>")]
 #[command(version = "1.0")]
 #[command(about = "Konfi config language processor", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
-    input_file: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// File to evaluate, same as `konfi eval FILE`.
+    input_file: Option<String>,
+    #[command(flatten)]
+    out: OutputArgs,
+    #[command(flatten)]
+    opts: EngineArgs,
 }
 
-fn main() -> io::Result<()> {
-    let args = Args::parse();
-    let input = fs::read_to_string(&args.input_file)?;
-    match parser::parse_module(&input) {
-        Ok(module) => {
-            let val = eval::eval(&module.expr, eval::Ctx::global()).expect("Cannot eval module");
-            let j = json::to_json(&val).expect("Cannot serialize to JSON");
-            println!("{}", serde_json::to_string_pretty(&j).expect("Cannot pretty-print JSON"));
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Evaluate a konfi file or expression and print it as JSON.
+    Eval {
+        /// Expression to evaluate instead of a file.
+        #[arg(
+            short,
+            long = "expr",
+            value_name = "EXPR",
+            conflicts_with = "input_file"
+        )]
+        expr: Option<String>,
+        /// File to evaluate, or - for stdin.
+        #[arg(required_unless_present = "expr")]
+        input_file: Option<String>,
+        #[command(flatten)]
+        out: OutputArgs,
+        #[command(flatten)]
+        opts: EngineArgs,
+    },
+    /// Evaluate a konfi file and print the value at a path, e.g. servers.web.port.
+    Get {
+        /// File to evaluate, or - for stdin.
+        input_file: String,
+        path: String,
+        #[command(flatten)]
+        out: OutputArgs,
+        #[command(flatten)]
+        opts: EngineArgs,
+    },
+    /// Validate the value of a konfi file against a schema.
+    Check {
+        /// File containing the schema declaration.
+        #[arg(long, value_name = "FILE")]
+        schema: String,
+        input_file: String,
+        #[command(flatten)]
+        opts: EngineArgs,
+    },
+    /// Show how the values of two konfi files differ, field by field.
+    Diff {
+        /// Compare FILE at git revision REV with the working tree, or with
+        /// REV2 if given as REV..REV2.
+        #[arg(long, value_name = "REV")]
+        rev: Option<String>,
+        /// The old and the new file, or a single file with --rev.
+        #[arg(required = true, num_args = 1..=2)]
+        files: Vec<String>,
+        #[command(flatten)]
+        opts: EngineArgs,
+    },
+    /// Show where the value of a field is defined and which fields it depends on.
+    Explain {
+        /// File to evaluate, or - for stdin.
+        input_file: String,
+        /// Path of the field, e.g. servers.web.port.
+        path: String,
+        #[command(flatten)]
+        opts: EngineArgs,
+    },
+    /// Print the dependencies between the fields of a konfi file as a DOT graph.
+    Graph {
+        /// File to evaluate, or - for stdin.
+        input_file: String,
+        #[command(flatten)]
+        out: OutputArgs,
+        #[command(flatten)]
+        opts: EngineArgs,
+    },
+    /// Compare the values of konfi files with their golden files NAME.expected.json.
+    Test {
+        /// Rewrite the golden files that differ instead of failing.
+        #[arg(long)]
+        update: bool,
+        /// Files and directories to test. Directories are searched recursively
+        /// for files with a golden file and files named NAME_test.konfi.
+        #[arg(default_value = ".")]
+        paths: Vec<String>,
+        #[command(flatten)]
+        opts: EngineArgs,
+    },
+    /// Report unused bindings, shadowed fields and suspicious code in konfi files.
+    Lint {
+        /// Output format of the warnings.
+        #[arg(long, value_enum, default_value_t = LintFormat::Text)]
+        format: LintFormat,
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Evaluate a konfi file whenever it or one of its imports changes.
+    Watch {
+        input_file: String,
+        #[command(flatten)]
+        out: OutputArgs,
+        #[command(flatten)]
+        opts: EngineArgs,
+    },
+    /// Start an interactive read-eval-print loop.
+    Repl {
+        #[command(flatten)]
+        opts: EngineArgs,
+    },
+    /// Run a language server for editors, speaking LSP over stdio.
+    Lsp,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LintFormat {
+    /// One line per warning: FILE:LINE:COL: CHECK: MESSAGE.
+    Text,
+    /// A JSON array of warnings, e.g. for annotations in CI.
+    Json,
+}
+
+#[derive(ClapArgs, Debug)]
+struct OutputArgs {
+    /// Write the output to FILE instead of stdout.
+    #[arg(short, long, visible_alias = "out", value_name = "FILE")]
+    output: Option<String>,
+}
+
+// Options of the evaluation: values passed in from outside, access to
+// files and the environment, and resource limits.
+#[derive(ClapArgs, Debug)]
+struct EngineArgs {
+    /// Set the external variable NAME, read with ext("NAME"), to the value of EXPR.
+    #[arg(long = "set", value_name = "NAME=EXPR", value_parser = parse_assignment)]
+    set: Vec<(String, String)>,
+    /// Set the external variable NAME to the string STR.
+    #[arg(long = "set-str", value_name = "NAME=STR", value_parser = parse_assignment)]
+    set_str: Vec<(String, String)>,
+    /// Allow env("VAR") to read the environment variable VAR.
+    #[arg(long = "allow-env", value_name = "VAR")]
+    allow_env: Vec<String>,
+    /// Allow imports of other files, relative to the importing file. Only
+    /// files below the directory of the input file can be imported.
+    #[arg(long)]
+    allow_imports: bool,
+    /// Maximum nesting depth of expressions during evaluation and of values,
+    /// at most 10000.
+    #[arg(long, value_name = "N", value_parser = parse_max_depth)]
+    max_depth: Option<usize>,
+    /// Maximum number of evaluation steps.
+    #[arg(long, value_name = "N")]
+    max_steps: Option<u64>,
+    /// Maximum length of strings (in bytes) and lists (in elements).
+    #[arg(long, value_name = "N")]
+    max_size: Option<usize>,
+    /// Maximum evaluation time, e.g. 500ms or 10s.
+    #[arg(long, value_name = "DURATION", value_parser = parse_timeout)]
+    timeout: Option<Duration>,
+}
+
+fn parse_assignment(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("expected NAME=VALUE, got \"{}\"", s)),
+    }
+}
+
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    let nanos = units::parse_duration(s)?;
+    u64::try_from(nanos)
+        .map(Duration::from_nanos)
+        .map_err(|_| "timeout must not be negative".to_string())
+}
+
+fn parse_max_depth(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(n) if n <= MAX_DEPTH => Ok(n),
+        Ok(_) => Err(format!("the maximum depth is {MAX_DEPTH}")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Errors of the konfi command. Each kind has its own exit code, so that
+// scripts can tell them apart. clap uses exit code 2 for usage errors.
+#[derive(Debug)]
+enum CliError {
+    Io(String),
+    Parse(String),
+    Eval(String),
+    Serialize(String),
+    Check(String),
+}
+
+impl CliError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Io(_) => 1,
+            CliError::Parse(_) => 3,
+            CliError::Eval(_) => 4,
+            CliError::Serialize(_) => 5,
+            CliError::Check(_) => 6,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            CliError::Io(m)
+            | CliError::Parse(m)
+            | CliError::Eval(m)
+            | CliError::Serialize(m)
+            | CliError::Check(m) => m,
         }
+    }
+}
+
+type CliResult<T> = Result<T, CliError>;
+
+// The largest --max-depth. Commands run on a thread whose stack is large
+// enough to evaluate this deeply: debug builds need about 12KiB per level.
+const MAX_DEPTH: usize = 10_000;
+const STACK_SIZE: usize = MAX_DEPTH * (16 << 10) + (8 << 20);
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let result = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || run(args))
+        .map_err(|e| CliError::Io(format!("Cannot start konfi: {}", e)))
+        .and_then(|t| t.join().unwrap_or_else(|e| std::panic::resume_unwind(e)));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
         Err(e) => {
-            println!("Cannot parse {}:\n{}", args.input_file, e.message);
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, ""));
+            eprintln!("{}", e.message());
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn run(args: Args) -> CliResult<()> {
+    match args.command {
+        Some(Command::Eval {
+            expr: Some(expr),
+            out,
+            opts,
+            ..
+        }) => run_eval_expr(&expr, &out, &engine(&opts, "-")?),
+        Some(Command::Eval {
+            input_file: Some(input_file),
+            out,
+            opts,
+            ..
+        }) => run_eval(&input_file, "", &out, &engine(&opts, &input_file)?),
+        Some(Command::Eval { .. }) => unreachable!("clap requires an expression or a file"),
+        Some(Command::Get {
+            input_file,
+            path,
+            out,
+            opts,
+        }) => run_eval(&input_file, &path, &out, &engine(&opts, &input_file)?),
+        Some(Command::Check {
+            schema,
+            input_file,
+            opts,
+        }) => run_check(&schema, &input_file, &engine(&opts, &input_file)?),
+        Some(Command::Diff { rev, files, opts }) => run_diff(rev.as_deref(), &files, &opts),
+        Some(Command::Explain {
+            input_file,
+            path,
+            opts,
+        }) => run_explain(&input_file, &path, &engine(&opts, &input_file)?),
+        Some(Command::Graph {
+            input_file,
+            out,
+            opts,
+        }) => run_graph(&input_file, &out, &engine(&opts, &input_file)?),
+        Some(Command::Test {
+            update,
+            paths,
+            opts,
+        }) => golden::run(&paths, update, &opts),
+        Some(Command::Lint { format, files }) => run_lint(&files, format),
+        Some(Command::Watch {
+            input_file,
+            out,
+            opts,
+        }) => watch::run(&input_file, out.output.as_deref(), &opts),
+        Some(Command::Repl { opts }) => {
+            repl::run(engine(&opts, "-")?).map_err(|e| CliError::Io(e.to_string()))
+        }
+        Some(Command::Lsp) => lsp::run().map_err(|e| CliError::Io(e.to_string())),
+        None => match args.input_file {
+            Some(input_file) => run_eval(
+                &input_file,
+                "",
+                &args.out,
+                &engine(&args.opts, &input_file)?,
+            ),
+            None => Err(CliError::Io(
+                "No input file given. Run konfi --help for usage.".to_string(),
+            )),
+        },
+    }
+}
+
+// Reads the contents of `input_file`, or of stdin if it is "-".
+fn read_input(input_file: &str) -> CliResult<String> {
+    let r = if input_file == "-" {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input).map(|_| input)
+    } else {
+        fs::read_to_string(input_file)
+    };
+    r.map_err(|e| CliError::Io(format!("Cannot read {}: {}", input_file, e)))
+}
+
+// The engine used by all commands to evaluate `input_file`. Imports and
+// environment variables are only available if allowed on the command line.
+fn engine(opts: &EngineArgs, input_file: &str) -> CliResult<Engine> {
+    engine_with(opts, FileResolver::new(import_base(input_file)))
+}
+
+// The directory that the imports of `input_file` must stay in: the directory
+// of the file, or the current directory for stdin.
+fn import_base(input_file: &str) -> &Path {
+    match Path::new(input_file).parent() {
+        Some(dir) if input_file != "-" && !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+// Like `engine`, but imports (if allowed) are resolved by `resolver`.
+fn engine_with(opts: &EngineArgs, resolver: FileResolver) -> CliResult<Engine> {
+    let mut limits = Limits {
+        max_steps: opts.max_steps,
+        max_size: opts.max_size,
+        timeout: opts.timeout,
+        ..Default::default()
+    };
+    if let Some(max_depth) = opts.max_depth {
+        limits.max_depth = max_depth;
+    }
+    // --set values are evaluated with the same limits, but without any
+    // of the other settings.
+    let set_engine = Engine::builder().limits(limits).build();
+    let mut b = Engine::builder().limits(limits);
+    if opts.allow_imports {
+        b = b.import_resolver(resolver);
+    }
+    for (name, expr) in opts.set.iter() {
+        let e = parser::parse_expr(expr)
+            .map_err(|e| CliError::Parse(format!("Cannot parse --set {}:\n{}", name, e.message)))?;
+        let v = eval::eval_checked(&e, expr, set_engine.context(None))
+            .map_err(|e| eval_error(&format!("Cannot eval --set {}", name), e))?;
+        b = b.ext(name, v);
+    }
+    for (name, s) in opts.set_str.iter() {
+        b = b.ext(name, s.as_str());
+    }
+    for var in opts.allow_env.iter() {
+        b = b.allow_env(var);
+    }
+    Ok(b.build())
+}
+
+fn load(engine: &Engine, input_file: &str, input: &str) -> CliResult<eval::Val> {
+    let name = (input_file != "-").then_some(input_file);
+    load_as(engine, input_file, name, input)
+}
+
+// Evaluates `input`, the module `name`, referring to it as `label` in errors.
+fn load_as(engine: &Engine, label: &str, name: Option<&str>, input: &str) -> CliResult<eval::Val> {
+    let module = parser::parse_module(input)
+        .map_err(|e| CliError::Parse(format!("Cannot parse {}:\n{}", label, e.message)))?;
+    eval::eval_module(&module, input, engine.context(name))
+        .map_err(|e| eval_error(&format!("Cannot eval {}", label), e))
+}
+
+// The error for a failed evaluation. Failed assertions have an exit code of
+// their own.
+fn eval_error(what: &str, e: eval::EvalError) -> CliError {
+    let message = format!("{}: {}", what, e.message);
+    if e.failed_asserts > 0 {
+        CliError::Check(message)
+    } else {
+        CliError::Eval(message)
+    }
+}
+
+fn to_json_string(val: &eval::Val) -> CliResult<String> {
+    let j = json::to_json(val)
+        .map_err(|e| CliError::Serialize(format!("Cannot serialize to JSON: {}", e.message)))?;
+    serde_json::to_string_pretty(&j)
+        .map_err(|e| CliError::Serialize(format!("Cannot serialize to JSON: {}", e)))
+}
+
+// Writes `val` as pretty-printed JSON to the output file or stdout.
+fn write_json(val: &eval::Val, out: &OutputArgs) -> CliResult<()> {
+    write_output(to_json_string(val)?, out)
+}
+
+// Writes `s` to the output file or stdout.
+fn write_output(s: String, out: &OutputArgs) -> CliResult<()> {
+    match &out.output {
+        Some(f) => {
+            fs::write(f, s + "\n").map_err(|e| CliError::Io(format!("Cannot write {}: {}", f, e)))
         }
+        None => {
+            println!("{}", s);
+            Ok(())
+        }
+    }
+}
+
+fn run_eval(input_file: &str, path: &str, out: &OutputArgs, engine: &Engine) -> CliResult<()> {
+    let input = read_input(input_file)?;
+    let val = load(engine, input_file, &input)?
+        .select(path)
+        .map_err(|e| {
+            CliError::Eval(format!(
+                "Cannot get {} from {}: {}",
+                path, input_file, e.message
+            ))
+        })?;
+    write_json(&val, out)
+}
+
+fn run_eval_expr(expr: &str, out: &OutputArgs, engine: &Engine) -> CliResult<()> {
+    let e = parser::parse_expr(expr)
+        .map_err(|e| CliError::Parse(format!("Cannot parse expression:\n{}", e.message)))?;
+    let val = eval::eval_checked(&e, expr, engine.context(None))
+        .map_err(|e| eval_error("Cannot eval expression", e))?;
+    write_json(&val, out)
+}
+
+// Reads `path` at the git revision `rev`.
+fn git_show(rev: &str, path: &Path) -> Result<String, String> {
+    let out = process::Command::new("git")
+        .arg("show")
+        .arg(format!("{}:./{}", rev, path.display()))
+        .output()
+        .map_err(|e| format!("Cannot run git: {}", e))?;
+    if !out.status.success() {
+        return Err(String::from_utf8_lossy(&out.stderr).trim().to_string());
+    }
+    String::from_utf8(out.stdout).map_err(|e| e.to_string())
+}
+
+// Evaluates `input_file` as of the git revision `rev`, including its imports.
+fn load_rev(rev: &str, input_file: &str, opts: &EngineArgs) -> CliResult<eval::Val> {
+    let label = format!("{}:{}", rev, input_file);
+    let input = git_show(rev, Path::new(input_file))
+        .map_err(|e| CliError::Io(format!("Cannot read {}: {}", label, e)))?;
+    let r = rev.to_string();
+    let resolver = FileResolver::with_reader(".", move |p| git_show(&r, p));
+    load_as(
+        &engine_with(opts, resolver)?,
+        &label,
+        Some(input_file),
+        &input,
+    )
+}
+
+fn run_diff(rev: Option<&str>, files: &[String], opts: &EngineArgs) -> CliResult<()> {
+    let load_file = |f: &str| load(&engine(opts, f)?, f, &read_input(f)?);
+    let (old, new) = match (rev, files) {
+        (None, [old, new]) => (load_file(old)?, load_file(new)?),
+        (Some(range), [file]) => match range.split_once("..") {
+            Some((r1, r2)) => (load_rev(r1, file, opts)?, load_rev(r2, file, opts)?),
+            None => (load_rev(range, file, opts)?, load_file(file)?),
+        },
+        (None, _) => Args::command()
+            .error(
+                ErrorKind::WrongNumberOfValues,
+                "diff needs two files, or one file with --rev",
+            )
+            .exit(),
+        (Some(_), _) => Args::command()
+            .error(
+                ErrorKind::WrongNumberOfValues,
+                "diff --rev needs exactly one file",
+            )
+            .exit(),
+    };
+    for d in diff::diff(&old, &new) {
+        println!("{}", d);
+    }
+    Ok(())
+}
+
+fn run_check(schema_file: &str, input_file: &str, engine: &Engine) -> CliResult<()> {
+    let schema_input = read_input(schema_file)?;
+    let s = schema::Schema::from_val(&load(engine, schema_file, &schema_input)?)
+        .map_err(|e| CliError::Check(format!("Invalid schema {}: {}", schema_file, e.message)))?;
+    let input = read_input(input_file)?;
+    let violations = s.check(&load(engine, input_file, &input)?);
+    for v in violations.iter() {
+        let loc = match v.pos {
+            Some(p) => {
+                let (line, col) = p.line_col(&input);
+                format!("{}:{}:{}", input_file, line, col)
+            }
+            None => input_file.to_string(),
+        };
+        let path = if v.path.is_empty() { "<root>" } else { &v.path };
+        println!("{}: {}: {}", loc, path, v.message);
+    }
+    if !violations.is_empty() {
+        return Err(CliError::Check(format!(
+            "{} schema violation(s) in {}",
+            violations.len(),
+            input_file
+        )));
+    }
+    Ok(())
+}
+
+fn run_explain(input_file: &str, path: &str, engine: &Engine) -> CliResult<()> {
+    let input = read_input(input_file)?;
+    let val = load(engine, input_file, &input)?;
+    let e = explain::explain(&val, path)
+        .map_err(|e| CliError::Eval(format!("Cannot explain {} in {}: {}", path, input_file, e)))?;
+    // Positions refer to the source of the module that defines the field.
+    let mut sources = HashMap::from([(input_file.to_string(), input)]);
+    let mut location = |s: &explain::Source| {
+        let module = s.module.as_deref().unwrap_or(input_file);
+        let src = sources
+            .entry(module.to_string())
+            .or_insert_with(|| fs::read_to_string(module).unwrap_or_default());
+        if s.pos.rem <= src.len() {
+            let (line, col) = s.pos.line_col(src);
+            format!("{}:{}:{}", module, line, col)
+        } else {
+            module.to_string()
+        }
+    };
+    let describe = |s: &explain::Source| {
+        let path = s
+            .path
+            .clone()
+            .unwrap_or_else(|| format!("{} (not in the output)", s.name));
+        match &s.value {
+            Some(v) => format!("{} = {}", path, v),
+            None => path,
+        }
+    };
+    println!("{}", describe(&e.field));
+    println!("  defined at {}", location(&e.field));
+    if e.deps.is_empty() {
+        println!("  depends on no other fields");
+    } else {
+        println!("  depends on:");
+        for d in e.deps.iter() {
+            println!("    {} at {}", describe(d), location(d));
+        }
+    }
+    Ok(())
+}
+
+fn run_lint(files: &[String], format: LintFormat) -> CliResult<()> {
+    let mut warnings = vec![];
+    for file in files.iter() {
+        let input = read_input(file)?;
+        let module = parser::parse_module(&input)
+            .map_err(|e| CliError::Parse(format!("Cannot parse {}:\n{}", file, e.message)))?;
+        for w in lint::lint(&module, &input) {
+            let (line, col) = w.pos.line_col(&input);
+            warnings.push((file, line, col, w));
+        }
+    }
+    match format {
+        LintFormat::Text => {
+            for (file, line, col, w) in warnings.iter() {
+                println!("{}:{}:{}: {}", file, line, col, w);
+            }
+        }
+        LintFormat::Json => {
+            let ws: Vec<_> = warnings
+                .iter()
+                .map(|(file, line, col, w)| {
+                    serde_json::json!({
+                        "file": file,
+                        "line": line,
+                        "column": col,
+                        "check": w.check.code(),
+                        "message": w.message,
+                    })
+                })
+                .collect();
+            let s = serde_json::to_string_pretty(&ws)
+                .map_err(|e| CliError::Serialize(format!("Cannot serialize to JSON: {}", e)))?;
+            println!("{}", s);
+        }
+    }
+    if !warnings.is_empty() {
+        return Err(CliError::Check(format!(
+            "{} warning(s) in {} file(s)",
+            warnings.len(),
+            files.len()
+        )));
     }
     Ok(())
 }
+
+fn run_graph(input_file: &str, out: &OutputArgs, engine: &Engine) -> CliResult<()> {
+    let input = read_input(input_file)?;
+    let val = load(engine, input_file, &input)?;
+    let dot = explain::graph(&val);
+    write_output(dot.trim_end().to_string(), out)
+}
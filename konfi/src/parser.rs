@@ -1,13 +1,16 @@
 use crate::ast;
-use crate::strings::parse_string;
+use crate::strings::{parse_string, parse_template_text};
+use crate::units;
+use std::cell::{Cell, RefCell};
 use std::num::ParseIntError;
+use std::thread::LocalKey;
 
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_while, take_while1},
-    character::complete::{char, multispace0, multispace1, one_of},
-    combinator::{all_consuming, cut, map, map_res, opt, recognize},
-    error::{FromExternalError, ParseError},
+    character::complete::{char, multispace0, multispace1, one_of, satisfy, space0},
+    combinator::{all_consuming, cut, map, map_opt, not, opt, recognize, value},
+    error::{ErrorKind, FromExternalError, ParseError, VerboseError, VerboseErrorKind},
     multi::{many0, many1, separated_list0},
     sequence::{delimited, pair, preceded, terminated, tuple},
     Finish, IResult,
@@ -32,20 +35,122 @@ where
     alt((tag("\r\n"), tag("\n")))(i)
 }
 
-fn int_literal<'a, E>(input: &'a str) -> IResult<&str, ast::Literal, E>
+// Integer literals: decimal, or hexadecimal (0xFF00), octal (0o755) and
+// binary (0b1010), with optional '_' separators after any digit and after the
+// prefix, as in 1_000_ or 0x_FF. A literal that does not fit into an i64
+// fails with ErrorKind::MapRes instead of backtracking.
+fn int_literal<'a, E>(input: &'a str) -> IResult<&'a str, ast::Literal, E>
 where
     E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError>,
 {
-    map_res(
-        recognize(pair(
-            opt(one_of("+-")),
-            many1(terminated(one_of("0123456789"), many0(char('_')))),
+    let digits = |radix: u32| {
+        recognize(many1(terminated(
+            satisfy(move |c| c.is_digit(radix)),
+            many0(char('_')),
+        )))
+    };
+    // A prefix without digits fails with `kind`, see `describe`.
+    let prefixed = |prefix: &'static str, radix: u32, kind: ErrorKind| {
+        let rest = move |i| {
+            preceded(many0(char('_')), digits(radix))(i)
+                .map_err(|_: nom::Err<E>| nom::Err::Failure(E::from_error_kind(i, kind)))
+        };
+        map(preceded(tag(prefix), rest), move |d| (radix, d))
+    };
+    let (rest, (sign, (radix, digits))) = pair(
+        opt(one_of("+-")),
+        alt((
+            prefixed("0x", 16, ErrorKind::HexDigit),
+            prefixed("0o", 8, ErrorKind::OctDigit),
+            prefixed("0b", 2, ErrorKind::Digit),
+            map(digits(10), |d| (10, d)),
         )),
-        |res: &str| i64::from_str_radix(&str::replace(&res, "_", ""), 10).map(ast::Literal::Int),
+    )(input)?;
+    let mut n: String = sign.into_iter().collect();
+    n.push_str(&digits.replace('_', ""));
+    match i64::from_str_radix(&n, radix) {
+        Ok(n) => Ok((rest, ast::Literal::Int(n))),
+        Err(e) => Err(nom::Err::Failure(E::from_external_error(
+            input,
+            ErrorKind::MapRes,
+            e,
+        ))),
+    }
+}
+
+// Byte-size literals like 512MiB, 2GB or 100B. The unit prefixes follow
+// units::multiplier, but in contrast to size.parse the "B" is required. A
+// literal that does not fit into a u64 fails with ErrorKind::MapOpt.
+// "0b" is the prefix of a binary integer literal, not a size of 0 bytes.
+fn size_literal<'a, E>(input: &'a str) -> IResult<&'a str, ast::Literal, E>
+where
+    E: ParseError<&'a str>,
+{
+    let (input, _) = not(tag("0b"))(input)?;
+    let (rest, (num, unit)) = terminated(
+        pair(
+            recognize(many1(terminated(one_of("0123456789"), many0(char('_'))))),
+            recognize(pair(
+                opt(pair(one_of("kKmMgGtT"), opt(char('i')))),
+                one_of("bB"),
+            )),
+        ),
+        not(satisfy(|c| c.is_alphanumeric() || c == '_')),
+    )(input)?;
+    let size = num
+        .replace('_', "")
+        .parse::<u64>()
+        .ok()
+        .zip(units::unit_multiplier(unit))
+        .and_then(|(n, m)| n.checked_mul(m));
+    match size {
+        Some(s) => Ok((rest, ast::Literal::Size(s))),
+        // All units accepted above are valid, so the size is too large.
+        None => Err(nom::Err::Failure(E::from_error_kind(
+            input,
+            ErrorKind::MapOpt,
+        ))),
+    }
+}
+
+// Duration literals like 30s, 250ms or 1h30m, see units::parse_duration.
+fn duration_literal<'a, E>(input: &'a str) -> IResult<&'a str, ast::Literal, E>
+where
+    E: ParseError<&'a str>,
+{
+    map_opt(
+        terminated(
+            recognize(many1(pair(
+                recognize(many1(terminated(one_of("0123456789"), many0(char('_'))))),
+                take_while1(|c: char| c.is_ascii_alphabetic()),
+            ))),
+            not(satisfy(|c| c.is_alphanumeric() || c == '_')),
+        ),
+        |d: &str| units::parse_duration(d).ok().map(ast::Literal::Duration),
     )(input)
 }
 
-fn ident<'a, E>(input: &'a str) -> IResult<&str, String, E>
+// A keyword like "for", which must not be followed by further identifier characters.
+fn keyword<'a, E>(kw: &'static str) -> impl Fn(&'a str) -> IResult<&'a str, &'a str, E>
+where
+    E: ParseError<&'a str>,
+{
+    move |i| {
+        terminated(
+            tag(kw),
+            not(satisfy(|c: char| c.is_alphanumeric() || c == '_')),
+        )(i)
+    }
+}
+
+fn nil_literal<'a, E>(input: &'a str) -> IResult<&'a str, ast::Literal, E>
+where
+    E: ParseError<&'a str>,
+{
+    map(keyword("nil"), |_| ast::Literal::Nil)(input)
+}
+
+fn ident<'a, E>(input: &'a str) -> IResult<&'a str, String, E>
 where
     E: ParseError<&'a str>,
 {
@@ -58,11 +163,25 @@ where
     )(input)
 }
 
-fn var<'a, E>(input: &'a str) -> IResult<&str, ast::Var, E>
+/// Whether `name` can be written as is, rather than as a quoted field name.
+pub fn is_ident(name: &str) -> bool {
+    all_consuming(ident::<nom::error::Error<&str>>)(name).is_ok()
+}
+
+// Field names are identifiers or string literals like "content-type".
+fn field_name<'a, E>(input: &'a str) -> IResult<&'a str, String, E>
+where
+    E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError>,
+{
+    alt((ident, parse_string))(input)
+}
+
+fn var<'a, E>(input: &'a str) -> IResult<&'a str, ast::Var, E>
 where
     E: ParseError<&'a str>,
 {
-    map(ident, |v| ast::Var { name: v })(input)
+    let pos = ast::Pos::at(input);
+    map(ident, move |v| ast::Var { name: v, pos })(input)
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
@@ -74,6 +193,7 @@ enum BinopPrecedence {
     Equality,       // == !=
     LogicalAnd,     // &&
     LogicalOr,      // ||
+    Coalesce,       // ??
 }
 
 impl BinopPrecedence {
@@ -82,6 +202,7 @@ impl BinopPrecedence {
     }
     pub fn next(&self) -> Self {
         match *self {
+            Self::Coalesce => Self::LogicalOr,
             Self::LogicalOr => Self::LogicalAnd,
             Self::LogicalAnd => Self::Equality,
             Self::Equality => Self::Relational,
@@ -95,7 +216,7 @@ impl BinopPrecedence {
     }
 }
 
-fn unop<'a, E>(input: &'a str) -> IResult<&str, ast::UnOp, E>
+fn unop<'a, E>(input: &'a str) -> IResult<&'a str, ast::UnOp, E>
 where
     E: ParseError<&'a str>,
 {
@@ -106,7 +227,7 @@ where
     ))(input)
 }
 
-fn binop<'a, E>(lvl: BinopPrecedence, input: &'a str) -> IResult<&str, ast::BinOp, E>
+fn binop<'a, E>(lvl: BinopPrecedence, input: &'a str) -> IResult<&'a str, ast::BinOp, E>
 where
     E: ParseError<&'a str>,
 {
@@ -120,8 +241,8 @@ where
             map(tag("-"), |_| ast::BinOp::Minus),
         ))(input),
         BinopPrecedence::Shift => alt((
-            map(tag(">>"), |_| ast::BinOp::ShiftLeft),
-            map(tag("<<"), |_| ast::BinOp::ShiftRight),
+            map(tag(">>"), |_| ast::BinOp::ShiftRight),
+            map(tag("<<"), |_| ast::BinOp::ShiftLeft),
         ))(input),
         BinopPrecedence::Relational => alt((
             map(tag("<="), |_| ast::BinOp::LessEq),
@@ -135,50 +256,151 @@ where
         ))(input),
         BinopPrecedence::LogicalAnd => map(tag("&&"), |_| ast::BinOp::LogicalAnd)(input),
         BinopPrecedence::LogicalOr => map(tag("||"), |_| ast::BinOp::LogicalOr)(input),
+        BinopPrecedence::Coalesce => map(tag("??"), |_| ast::BinOp::Coalesce)(input),
+    }
+}
+
+// Maximum nesting depth of expressions. The parser is recursive, so deeper
+// nesting would overflow the stack.
+pub const MAX_NESTING: usize = 100;
+
+// Maximum number of binary operators and suffixes like `.f` that an
+// expression is nested in. Each of them nests the AST one level deeper, and
+// everything that walks the AST is recursive.
+pub const MAX_CHAIN: usize = 1000;
+
+thread_local! {
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+    static CHAIN: Cell<usize> = const { Cell::new(0) };
+    // Errors in record fields and let bindings that the current parse
+    // skipped to continue with the next one, see `rec` and `module`.
+    static RECOVERED: RefCell<Vec<SyntaxError>> = const { RefCell::new(vec![]) };
+}
+
+// Decrements the nesting depth (or chain length) when dropped.
+struct NestingGuard(&'static LocalKey<Cell<usize>>);
+
+impl NestingGuard {
+    fn enter<'a, E: ParseError<&'a str>>(input: &'a str) -> Result<Self, nom::Err<E>> {
+        Self::count(&NESTING, MAX_NESTING, ErrorKind::TooLarge, input)
+    }
+
+    // Enters an operator or suffix of a chain.
+    fn chain<'a, E: ParseError<&'a str>>(input: &'a str) -> Result<Self, nom::Err<E>> {
+        Self::count(&CHAIN, MAX_CHAIN, ErrorKind::Count, input)
+    }
+
+    fn count<'a, E: ParseError<&'a str>>(
+        counter: &'static LocalKey<Cell<usize>>,
+        max: usize,
+        kind: ErrorKind,
+        input: &'a str,
+    ) -> Result<Self, nom::Err<E>> {
+        let depth = counter.get() + 1;
+        if depth > max {
+            return Err(nom::Err::Failure(E::from_error_kind(input, kind)));
+        }
+        counter.set(depth);
+        Ok(NestingGuard(counter))
+    }
+}
+
+impl Drop for NestingGuard {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
     }
 }
 
-fn atom<'a, E>(input: &'a str) -> IResult<&str, Box<ast::Expr>, E>
+fn atom<'a, E>(input: &'a str) -> IResult<&'a str, Box<ast::Expr>, E>
 where
     E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
 {
+    let _nesting = NestingGuard::enter(input)?;
     let (r1, e) = alt((
+        rec_comp,
         rec,
+        list,
+        // Must come before parenthesized expressions and variables.
+        lambda,
         delimited(char('('), cut(ws(expr)), char(')')),
         map(parse_string, |s| {
             Box::new(ast::Expr::Literal(ast::Literal::Str(s)))
         }),
+        map(size_literal, |l| Box::new(ast::Expr::Literal(l))),
+        map(duration_literal, |l| Box::new(ast::Expr::Literal(l))),
         map(int_literal, |l| Box::new(ast::Expr::Literal(l))),
+        map(nil_literal, |l| Box::new(ast::Expr::Literal(l))),
         map(pair(ws(unop), atom), |(op, e)| {
             Box::new(ast::Expr::UnExpr(op, e))
         }),
         map(var, |v| Box::new(ast::Expr::Var(v))),
     ))(input)?;
-    // Try to parse a field access suffix.
-    match many0(preceded(ws(char::<&'a str, E>('.')), var))(r1) {
-        Ok((r2, fs)) => {
-            let mut d = e;
-            for f in fs.into_iter() {
-                d = Box::new(ast::Expr::FieldAcc(d, f.name));
+    // Try to parse field access and call suffixes.
+    let (mut r, mut d) = (r1, e);
+    let mut _nesting = vec![];
+    loop {
+        _nesting.push(NestingGuard::chain(r)?);
+        match suffix::<E>(r) {
+            Ok((r2, s)) => {
+                d = Box::new(match s {
+                    Suffix::Field(f) => ast::Expr::FieldAcc(d, f),
+                    Suffix::OptField(f) => ast::Expr::OptFieldAcc(d, f),
+                    Suffix::Call(args) => ast::Expr::Call(ast::Call { fun: d, args }),
+                    Suffix::Index(k) => ast::Expr::Index(d, k),
+                });
+                r = r2;
             }
-            Ok((r2, d))
+            Err(nom::Err::Failure(e)) => return Err(nom::Err::Failure(e)),
+            Err(_) => return Ok((r, d)),
         }
-        _ => Ok((r1, e)),
     }
 }
 
-pub fn expr<'a, E>(input: &'a str) -> IResult<&str, Box<ast::Expr>, E>
+enum Suffix {
+    Field(String),         // .f
+    OptField(String),      // ?.f
+    Call(Vec<ast::Expr>),  // (a, b)
+    Index(Box<ast::Expr>), // [k]
+}
+
+fn suffix<'a, E>(input: &'a str) -> IResult<&'a str, Suffix, E>
 where
     E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
 {
-    gen_expr::<E>(BinopPrecedence::LogicalOr, input)
+    alt((
+        map(preceded(ws(char('.')), var), |v| Suffix::Field(v.name)),
+        map(preceded(ws(tag("?.")), var), |v| Suffix::OptField(v.name)),
+        // No whitespace is allowed between the function and its arguments.
+        map(
+            preceded(
+                terminated(char('('), multispace0),
+                cut(terminated(
+                    separated_list0(ws(char(',')), map(expr, |e| *e)),
+                    preceded(multispace0, char(')')),
+                )),
+            ),
+            Suffix::Call,
+        ),
+        // Like calls, indexing allows no whitespace before the '['.
+        map(
+            preceded(char('['), cut(terminated(ws(expr), char(']')))),
+            Suffix::Index,
+        ),
+    ))(input)
+}
+
+pub fn expr<'a, E>(input: &'a str) -> IResult<&'a str, Box<ast::Expr>, E>
+where
+    E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
+{
+    gen_expr::<E>(BinopPrecedence::Coalesce, input)
 }
 
 // Binary operators have different precedence ('*' binds more tightly than '+').
 // BinopPrecedence encodes the precedence of all binary operators and is used
 // here to obtain a generic recursive parser for all binary operators without the
 // usual expr=>term=>factor=>atom hierarchy.
-fn gen_expr<'a, E>(lvl: BinopPrecedence, input: &'a str) -> IResult<&str, Box<ast::Expr>, E>
+fn gen_expr<'a, E>(lvl: BinopPrecedence, input: &'a str) -> IResult<&'a str, Box<ast::Expr>, E>
 where
     E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
 {
@@ -193,6 +415,9 @@ where
     // If no suitable operator follows, just return the first term.
     match ws(expr_binop)(r1) {
         Ok((r2, op)) => {
+            // Operators associate to the right, so each one in a chain nests
+            // the rest of it one level deeper.
+            let _nesting = NestingGuard::chain(r2)?;
             let (r2, b) = gen_expr::<E>(lvl, r2)?;
             Ok((r2, Box::new(ast::Expr::BinExpr(a, op, b))))
         }
@@ -200,7 +425,7 @@ where
     }
 }
 
-fn let_binding<'a, E>(input: &'a str) -> IResult<&str, ast::LetBinding, E>
+fn let_binding<'a, E>(input: &'a str) -> IResult<&'a str, ast::LetBinding, E>
 where
     E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
 {
@@ -210,78 +435,651 @@ where
     )(input)
 }
 
-fn rec_field<'a, E>(input: &'a str) -> IResult<&str, ast::Field, E>
+// Whether `input` starts with an assertion rather than a field called "assert".
+fn is_assert(input: &str) -> bool {
+    tuple((
+        keyword::<VerboseError<&str>>("assert"),
+        multispace1,
+        not(char(':')),
+    ))(input)
+    .is_ok()
+}
+
+// Whether `input` starts with a let binding rather than a field called "let".
+fn is_let(input: &str) -> bool {
+    tuple((
+        keyword::<VerboseError<&str>>("let"),
+        multispace1,
+        not(char(':')),
+    ))(input)
+    .is_ok()
+}
+
+// Assertions: assert cond : "message". The message is optional.
+fn assertion<'a, E>(input: &'a str) -> IResult<&'a str, ast::Assert, E>
 where
     E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
 {
-    map(pair(terminated(ident, ws(char(':'))), expr), |(v, e)| {
-        ast::Field { name: v, value: e }
-    })(input)
+    let pos = ast::Pos::at(input);
+    map(
+        tuple((
+            keyword("assert"),
+            multispace1,
+            expr,
+            opt(preceded(ws(char(':')), cut(expr))),
+        )),
+        move |(_, _, cond, message)| ast::Assert { cond, message, pos },
+    )(input)
 }
 
-fn rec<'a, E>(input: &'a str) -> IResult<&str, Box<ast::Expr>, E>
+fn rec_field<'a, E>(input: &'a str) -> IResult<&'a str, ast::Field, E>
 where
     E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
 {
+    let pos = ast::Pos::at(input);
     map(
+        tuple((field_name, ws(field_sep), expr)),
+        move |(v, hidden, e)| ast::Field {
+            name: v,
+            value: e,
+            hidden,
+            pos,
+        },
+    )(input)
+}
+
+// The separator between field name and value: ':', or '::' for hidden fields.
+fn field_sep<'a, E>(input: &'a str) -> IResult<&'a str, bool, E>
+where
+    E: ParseError<&'a str>,
+{
+    alt((value(true, tag("::")), value(false, tag(":"))))(input)
+}
+
+// Error recovery: a record field or let binding that cannot be parsed is
+// recorded as a SyntaxError and skipped, so that parsing continues with the
+// next one and a single run reports all syntax errors.
+
+fn recover(e: SyntaxError) {
+    RECOVERED.with_borrow_mut(|errors| errors.push(e));
+}
+
+fn syntax_error(message: String, at: &str) -> SyntaxError {
+    SyntaxError {
+        message,
+        pos: ast::Pos::at(at),
+    }
+}
+
+// A short description of the token at the start of `input` for messages.
+fn token(input: &str) -> String {
+    let word: String = input
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect();
+    match input.chars().next() {
+        None => "end of input".to_string(),
+        Some('\n' | '\r') => "end of line".to_string(),
+        Some(_) if !word.is_empty() => format!("'{word}'"),
+        Some(c) => format!("'{c}'"),
+    }
+}
+
+// The number literal at the start of `input`, including its sign and unit.
+fn literal_token(input: &str) -> &str {
+    let sign = usize::from(input.starts_with(['+', '-']));
+    let len = input[sign..]
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(input.len() - sign);
+    &input[..sign + len]
+}
+
+// Turns the innermost error of a failed parse into a SyntaxError.
+fn describe(e: &VerboseError<&str>) -> SyntaxError {
+    let Some((rest, kind)) = e.errors.first() else {
+        return syntax_error("invalid syntax".to_string(), "");
+    };
+    let too_deep = e.errors.iter().find_map(|(_, k)| match k {
+        VerboseErrorKind::Nom(ErrorKind::TooLarge) => Some(format!(
+            "expressions are nested more than {MAX_NESTING} levels deep"
+        )),
+        VerboseErrorKind::Nom(ErrorKind::Count) => Some(format!(
+            "expressions are nested in more than {MAX_CHAIN} operators and suffixes"
+        )),
+        _ => None,
+    });
+    let message = match kind {
+        _ if too_deep.is_some() => too_deep.unwrap_or_default(),
+        VerboseErrorKind::Nom(ErrorKind::MapRes) => {
+            format!("integer literal {} is out of range", literal_token(rest))
+        }
+        VerboseErrorKind::Nom(ErrorKind::MapOpt) => {
+            format!("size literal {} is out of range", literal_token(rest))
+        }
+        // The digits after the prefix of an integer literal, see int_literal.
+        VerboseErrorKind::Nom(ErrorKind::HexDigit) => {
+            format!(
+                "expected hexadecimal digits after '0x', found {}",
+                token(rest)
+            )
+        }
+        VerboseErrorKind::Nom(ErrorKind::OctDigit) => {
+            format!("expected octal digits after '0o', found {}", token(rest))
+        }
+        VerboseErrorKind::Nom(ErrorKind::Digit) => {
+            format!("expected binary digits after '0b', found {}", token(rest))
+        }
+        VerboseErrorKind::Char(c) => format!("expected '{c}', found {}", token(rest)),
+        VerboseErrorKind::Nom(ErrorKind::Eof) => format!("unexpected {}", token(rest)),
+        _ => format!("expected an expression, found {}", token(rest)),
+    };
+    syntax_error(message, rest)
+}
+
+// The error for a record field in `input` that failed to parse with `e`.
+fn field_error(input: &str, e: VerboseError<&str>) -> SyntaxError {
+    let Ok((r, _)) = field_name::<VerboseError<&str>>(input) else {
+        return syntax_error(
+            format!("expected field name, found {}", token(input)),
+            input,
+        );
+    };
+    let (r, _) = multispace0::<&str, VerboseError<&str>>(r).unwrap_or((r, ""));
+    let Ok((r, _)) = terminated(field_sep::<VerboseError<&str>>, multispace0)(r) else {
+        return syntax_error(
+            format!("expected ':' after field name, found {}", token(r)),
+            r,
+        );
+    };
+    value_error(r, e, "':'")
+}
+
+// The error for a let binding in `input` that failed to parse with `e`.
+fn let_error(input: &str, e: VerboseError<&str>) -> SyntaxError {
+    let (r, _) = pair(tag::<&str, &str, VerboseError<&str>>("let"), multispace1)(input)
+        .unwrap_or((input, ("", "")));
+    let Ok((r, _)) = var::<VerboseError<&str>>(r) else {
+        return syntax_error(
+            format!("expected variable name after 'let', found {}", token(r)),
+            r,
+        );
+    };
+    let (r, _) = multispace0::<&str, VerboseError<&str>>(r).unwrap_or((r, ""));
+    let Ok((r, _)) = terminated(char::<&str, VerboseError<&str>>('='), multispace0)(r) else {
+        return syntax_error(
+            format!("expected '=' after variable name, found {}", token(r)),
+            r,
+        );
+    };
+    value_error(r, e, "'='")
+}
+
+// The error for an assertion in `input` that failed to parse with `e`.
+fn assert_error(input: &str, e: VerboseError<&str>) -> SyntaxError {
+    let (r, _) = pair(tag::<&str, &str, VerboseError<&str>>("assert"), multispace1)(input)
+        .unwrap_or((input, ("", "")));
+    value_error(r, e, "'assert'")
+}
+
+// The error for the value at `input` after `after`.
+fn value_error(input: &str, e: VerboseError<&str>, after: &str) -> SyntaxError {
+    // Too deeply nested expressions and out of range literals have messages
+    // of their own.
+    let specific = e.errors.iter().any(|(_, k)| {
+        matches!(
+            k,
+            VerboseErrorKind::Nom(
+                ErrorKind::TooLarge | ErrorKind::Count | ErrorKind::MapRes | ErrorKind::MapOpt
+            )
+        )
+    });
+    let err = describe(&e);
+    if err.pos.rem == input.len() && !specific {
+        syntax_error(
+            format!("expected a value after {after}, found {}", token(input)),
+            input,
+        )
+    } else {
+        err
+    }
+}
+
+// The indentation of the last line in the whitespace `ws`, if it has a line break.
+fn indentation(ws: &str) -> Option<usize> {
+    ws.rfind('\n').map(|i| ws.len() - i - 1)
+}
+
+// Skips the broken item (field or let binding) at the start of `input`. It
+// ends at a line break or '}' outside of brackets and strings, but a line
+// that is indented by more than `indent` continues the item. Inside
+// brackets, a line that is indented no more than `indent` ends it.
+fn skip_item(input: &str, indent: Option<usize>) -> &str {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                '\n' => in_string = false,
+                _ => {}
+            }
+            if c != '\n' {
+                continue;
+            }
+        }
+        match c {
+            '"' => in_string = true,
+            '(' | '[' | '{' => depth += 1,
+            '}' if depth == 0 => return &input[i..],
+            ')' | ']' | '}' => depth = depth.saturating_sub(1),
+            '\n' => {
+                let next = &input[i + 1..];
+                let n = next.len() - next.trim_start_matches([' ', '\t']).len();
+                if indent.map_or(depth == 0, |ind| n <= ind) {
+                    return &input[i..];
+                }
+            }
+            _ => {}
+        }
+    }
+    ""
+}
+
+fn rec<'a, E>(input: &'a str) -> IResult<&'a str, Box<ast::Expr>, E>
+where
+    E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
+{
+    let (mut i, ws) = preceded(char('{'), multispace0)(input)?;
+    let mut indent = indentation(ws);
+    let mut fields = vec![];
+    let mut asserts = vec![];
+    loop {
+        if let Ok((r, _)) = char::<&str, E>('}')(i) {
+            let rec = ast::Rec {
+                let_vars: vec![],
+                fields,
+                asserts,
+            };
+            return Ok((r, Box::new(ast::Expr::Rec(rec))));
+        }
+        if i.is_empty() {
+            return Err(nom::Err::Failure(E::from_char(i, '}')));
+        }
+        // Fields are parsed on their own, so that an error in one of them
+        // can be reported and skipped.
+        if is_assert(i) {
+            match assertion::<VerboseError<&str>>(i) {
+                Ok((r, a)) => {
+                    asserts.push(a);
+                    i = r;
+                }
+                Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                    recover(assert_error(i, e));
+                    i = skip_item(i, indent);
+                }
+                Err(nom::Err::Incomplete(n)) => return Err(nom::Err::Incomplete(n)),
+            }
+        } else if is_let(i) {
+            // Let bindings are only allowed in modules, but get the same
+            // messages there and in records.
+            match let_binding::<VerboseError<&str>>(i) {
+                Ok(_) => recover(syntax_error(
+                    "let bindings are only allowed before the expression of a module".to_string(),
+                    i,
+                )),
+                Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => recover(let_error(i, e)),
+                Err(nom::Err::Incomplete(n)) => return Err(nom::Err::Incomplete(n)),
+            }
+            i = skip_item(i, indent);
+        } else {
+            match rec_field::<VerboseError<&str>>(i) {
+                Ok((r, f))
+                    if fields.is_empty() && asserts.is_empty() && !f.hidden && is_comp(i, r) =>
+                {
+                    // {"${k}": v for k, v in r}
+                    let (_, k) = cut(interpolated_key)(i)?;
+                    let (r, c) = cut(terminated(
+                        preceded(multispace1, comp),
+                        preceded(multispace0, char('}')),
+                    ))(r)?;
+                    return Ok((r, Box::new(ast::Expr::RecComp(k, f.value, c))));
+                }
+                Ok((r, f)) => {
+                    fields.push(f);
+                    i = r;
+                }
+                Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                    recover(field_error(i, e));
+                    i = skip_item(i, indent);
+                }
+                Err(nom::Err::Incomplete(n)) => return Err(nom::Err::Incomplete(n)),
+            }
+        }
+        // Fields and assertions end at a line break, a comma or the closing brace.
+        let r = match eol::<VerboseError<&str>>(i) {
+            Ok((r, _)) => r,
+            Err(_) => {
+                let (r, _) = space0::<&str, VerboseError<&str>>(i).unwrap_or((i, ""));
+                if let Some(r) = r.strip_prefix(',') {
+                    r
+                } else if r.is_empty() || r.starts_with('}') {
+                    r
+                } else {
+                    recover(syntax_error(
+                        format!(
+                            "expected ',', a line break or '}}' after field, found {}",
+                            token(r)
+                        ),
+                        r,
+                    ));
+                    skip_item(r, indent)
+                }
+            }
+        };
+        let (r, ws) = multispace0::<&str, VerboseError<&str>>(r).unwrap_or((r, ""));
+        indent = indentation(ws).or(indent);
+        i = r;
+    }
+}
+
+// Whether the field that `rest` follows is the key and value of a record
+// comprehension with a string key: {"${k}": v for k, v in r}.
+fn is_comp(field: &str, rest: &str) -> bool {
+    field.starts_with('"')
+        && tuple((
+            multispace1::<&str, VerboseError<&str>>,
+            keyword("for"),
+            multispace1,
+        ))(rest)
+        .is_ok()
+}
+
+// Lambdas: x => x * 2 or (acc, x) => acc + x.
+fn lambda<'a, E>(input: &'a str) -> IResult<&'a str, Box<ast::Expr>, E>
+where
+    E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
+{
+    let params = alt((
+        map(var, |v| vec![v]),
         delimited(
-            terminated(char('{'), multispace0),
-            // many0(delimited(multispace0, rec_field, eol)), //
-            separated_list0(eol, preceded(multispace0, rec_field)),
-            preceded(multispace0, char('}')),
+            terminated(char('('), multispace0),
+            separated_list0(ws(char(',')), var),
+            preceded(multispace0, char(')')),
         ),
-        |fs| {
-            Box::new(ast::Expr::Rec(ast::Rec {
-                let_vars: vec![],
-                fields: fs,
+    ));
+    map(
+        pair(terminated(params, ws(tag("=>"))), cut(expr)),
+        |(params, body)| {
+            Box::new(ast::Expr::Fun(ast::Fun {
+                params,
+                body: body.into(),
             }))
         },
     )(input)
 }
 
-pub fn expr_opt(input: &str) -> Option<Box<ast::Expr>> {
-    match expr::<nom::error::VerboseError<&str>>(input) {
-        Ok((i, e)) => {
-            if i.is_empty() {
-                Some(e)
-            } else {
-                None
-            }
+// The "for x in xs if cond" part of comprehensions.
+fn comp<'a, E>(input: &'a str) -> IResult<&'a str, ast::Comp, E>
+where
+    E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
+{
+    let (i, _) = pair(keyword("for"), multispace1)(input)?;
+    let (i, first) = cut(var)(i)?;
+    let (i, second) = opt(preceded(ws(char(',')), cut(var)))(i)?;
+    let (i, iter) = cut(preceded(ws(keyword("in")), expr))(i)?;
+    let (i, cond) = opt(preceded(ws(keyword("if")), cut(expr)))(i)?;
+    let vars = std::iter::once(first).chain(second).collect();
+    Ok((i, ast::Comp { vars, iter, cond }))
+}
+
+// Record comprehensions: {[k]: v for k, v in r if cond}. The brackets
+// distinguish the computed key from a field name. Keys can also be strings
+// with interpolated expressions, {"${k}": v for k, v in r}, which `rec`
+// recognizes once it finds the "for" after the first field.
+fn rec_comp<'a, E>(input: &'a str) -> IResult<&'a str, Box<ast::Expr>, E>
+where
+    E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
+{
+    let (i, _) = pair(char('{'), pair(multispace0, char('[')))(input)?;
+    map(
+        cut(tuple((
+            terminated(ws(expr), char(']')),
+            preceded(ws(char(':')), expr),
+            terminated(
+                preceded(multispace1, comp),
+                preceded(multispace0, char('}')),
+            ),
+        ))),
+        |(k, v, c)| Box::new(ast::Expr::RecComp(k, v, c)),
+    )(i)
+}
+
+// The key of a record comprehension written as a string, in which each
+// ${e} is replaced by the value of e: "${name}-port" is "{}-port".format(name).
+fn interpolated_key<'a, E>(input: &'a str) -> IResult<&'a str, Box<ast::Expr>, E>
+where
+    E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
+{
+    let (mut i, _) = char('"')(input)?;
+    let mut text = String::new();
+    let mut format = String::new();
+    let mut args = vec![];
+    loop {
+        let (r, t) = parse_template_text(i)?;
+        text.push_str(&t);
+        format.push_str(&t.replace('{', "{{").replace('}', "}}"));
+        if let Ok((r, _)) = char::<&str, E>('"')(r) {
+            i = r;
+            break;
         }
-        Err(_) => None,
+        let (r, e) = delimited(tag("${"), ws(expr), char('}'))(r)?;
+        format.push_str("{}");
+        args.push(*e);
+        i = r;
+    }
+    if args.is_empty() {
+        return Ok((i, Box::new(ast::Expr::Literal(ast::Literal::Str(text)))));
     }
+    let fun = ast::Expr::FieldAcc(
+        Box::new(ast::Expr::Literal(ast::Literal::Str(format))),
+        String::from("format"),
+    );
+    let call = ast::Call {
+        fun: Box::new(fun),
+        args,
+    };
+    Ok((i, Box::new(ast::Expr::Call(call))))
 }
 
-pub fn module<'a, E>(input: &'a str) -> IResult<&str, ast::Module, E>
+// List literals: [a, b, c]. Elements are separated by commas and may span
+// multiple lines; a trailing comma is allowed.
+fn list<'a, E>(input: &'a str) -> IResult<&'a str, Box<ast::Expr>, E>
 where
     E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
 {
-    let (input1, let_vars) =
-        preceded(multispace0, many0(delimited(multispace0, let_binding, eol)))(input)?;
+    let (i, _) = terminated(char('['), multispace0)(input)?;
+    if let Ok((r, _)) = char::<&str, E>(']')(i) {
+        return Ok((r, Box::new(ast::Expr::List(vec![]))));
+    }
+    let (i, first) = cut(expr)(i)?;
+    // A comprehension: [e for x in xs if cond].
+    match preceded(multispace1, comp::<E>)(i) {
+        Ok((r, c)) => {
+            let (r, _) = cut(preceded(multispace0, char(']')))(r)?;
+            return Ok((r, Box::new(ast::Expr::ListComp(first, c))));
+        }
+        Err(nom::Err::Error(_)) => {}
+        Err(e) => return Err(e),
+    }
+    let (r, rest) = cut(terminated(
+        many0(preceded(ws(char(',')), map(expr, |e| *e))),
+        pair(opt(ws(char(','))), preceded(multispace0, char(']'))),
+    ))(i)?;
+    let mut es = vec![*first];
+    es.extend(rest);
+    Ok((r, Box::new(ast::Expr::List(es))))
+}
+
+pub fn expr_opt(input: &str) -> Option<Box<ast::Expr>> {
+    parse_all(input, expr).ok()
+}
+
+fn import<'a, E>(input: &'a str) -> IResult<&'a str, ast::Import, E>
+where
+    E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
+{
+    let pos = ast::Pos::at(input);
+    map(
+        tuple((
+            tag("import"),
+            multispace1,
+            parse_string,
+            multispace1,
+            tag("as"),
+            multispace1,
+            var,
+        )),
+        move |(_, _, path, _, _, _, var)| ast::Import { path, var, pos },
+    )(input)
+}
+
+pub fn module<'a, E>(input: &'a str) -> IResult<&'a str, ast::Module, E>
+where
+    E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError> + 'a,
+{
+    let (input, imports) =
+        preceded(multispace0, many0(delimited(multispace0, import, eol)))(input)?;
+    let (mut input1, _) = multispace0(input)?;
+    let mut let_vars = vec![];
+    let mut asserts = vec![];
+    // Like record fields, let bindings and assertions are parsed on their own
+    // to recover from errors.
+    loop {
+        let (parsed, what) = if pair(tag::<&str, &str, E>("let"), multispace1)(input1).is_ok() {
+            let parsed = match let_binding::<VerboseError<&str>>(input1) {
+                Ok((r, lb)) => {
+                    let_vars.push(lb);
+                    Ok(r)
+                }
+                Err(e) => Err(e.map(|e| let_error(input1, e))),
+            };
+            (parsed, "let binding")
+        } else if is_assert(input1) {
+            let parsed = match assertion::<VerboseError<&str>>(input1) {
+                Ok((r, a)) => {
+                    asserts.push(a);
+                    Ok(r)
+                }
+                Err(e) => Err(e.map(|e| assert_error(input1, e))),
+            };
+            (parsed, "assertion")
+        } else {
+            break;
+        };
+        let r = match parsed {
+            Ok(r) => match eol::<VerboseError<&str>>(r) {
+                Ok((r, _)) => r,
+                Err(_) => {
+                    let (r, _) = space0::<&str, VerboseError<&str>>(r).unwrap_or((r, ""));
+                    recover(syntax_error(
+                        format!("expected end of line after {what}, found {}", token(r)),
+                        r,
+                    ));
+                    skip_item(r, Some(0))
+                }
+            },
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                recover(e);
+                skip_item(input1, Some(0))
+            }
+            Err(nom::Err::Incomplete(n)) => return Err(nom::Err::Incomplete(n)),
+        };
+        (input1, _) = multispace0(r)?;
+    }
     // In contrast to all other grammar rules, the module eats any trailing whitespace.
     let (input2, e) = delimited(multispace0, expr, multispace0)(input1)?;
     Ok((
         input2,
         ast::Module {
-            let_vars: let_vars,
+            imports,
+            let_vars,
+            asserts,
             expr: e,
         },
     ))
 }
 
+/// A syntax error with a concise message.
+#[derive(Debug, Clone)]
+pub struct SyntaxError {
+    pub message: String,
+    pub pos: ast::Pos,
+}
+
 pub struct KonfiParseError {
+    // All errors, one per line, as "line:col: message".
     pub message: String,
+    // Where the first error is.
+    pub pos: ast::Pos,
+    // All errors in the order of their position.
+    pub errors: Vec<SyntaxError>,
 }
 
-pub fn parse_module(input: &str) -> Result<ast::Module, KonfiParseError> {
-    match all_consuming(module::<nom::error::VerboseError<&str>>)(input).finish() {
-        Ok((_, m)) => Ok(m),
-        Err(e) => Err(KonfiParseError {
-            message: nom::error::convert_error(input, e),
-        }),
+impl KonfiParseError {
+    fn new(input: &str, mut errors: Vec<SyntaxError>) -> Self {
+        errors.sort_by_key(|e| std::cmp::Reverse(e.pos.rem));
+        errors.dedup_by_key(|e| e.pos.rem);
+        let message = errors
+            .iter()
+            .map(|e| {
+                let (line, col) = e.pos.line_col(input);
+                format!("{line}:{col}: {}", e.message)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let pos = errors.first().map(|e| e.pos).unwrap_or_default();
+        KonfiParseError {
+            message,
+            pos,
+            errors,
+        }
     }
 }
 
+// Parses all of `input` with `p`. Fails if there was any error, including
+// those that the parser recovered from.
+fn parse_all<'a, O, P>(input: &'a str, mut p: P) -> Result<O, KonfiParseError>
+where
+    P: nom::Parser<&'a str, O, VerboseError<&'a str>>,
+{
+    RECOVERED.with_borrow_mut(Vec::clear);
+    let result = all_consuming(|i| p.parse(i))(input).finish();
+    let mut errors = RECOVERED.take();
+    match result {
+        Ok((_, o)) if errors.is_empty() => return Ok(o),
+        Ok(_) => {}
+        Err(e) => errors.push(describe(&e)),
+    }
+    Err(KonfiParseError::new(input, errors))
+}
+
+pub fn parse_module(input: &str) -> Result<ast::Module, KonfiParseError> {
+    parse_all(input, module)
+}
+
+// Parses a standalone expression, ignoring surrounding whitespace.
+pub fn parse_expr(input: &str) -> Result<Box<ast::Expr>, KonfiParseError> {
+    parse_all(input, ws(expr))
+}
+
+// Parses a standalone let binding, ignoring surrounding whitespace.
+pub fn parse_let_binding(input: &str) -> Result<ast::LetBinding, KonfiParseError> {
+    parse_all(input, ws(let_binding))
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -338,6 +1136,7 @@ mod tests {
         pub fn var(s: &str) -> ast::Var {
             ast::Var {
                 name: String::from(s),
+                pos: ast::Pos::default(),
             }
         }
 
@@ -359,11 +1158,14 @@ mod tests {
                 fs.push(ast::Field {
                     name: f.to_string(),
                     value: e,
+                    hidden: false,
+                    pos: ast::Pos::default(),
                 });
             }
             Box::new(ast::Expr::Rec(ast::Rec {
                 let_vars: vec![],
                 fields: fs,
+                asserts: vec![],
             }))
         }
 
@@ -375,6 +1177,7 @@ mod tests {
             LetBinding {
                 var: ast::Var {
                     name: x.to_string(),
+                    pos: ast::Pos::default(),
                 },
                 value: e,
             }
@@ -386,6 +1189,97 @@ mod tests {
         assert_finish!("123", int_literal, h::ilit(123));
         assert_finish!("+1", int_literal, h::ilit(1));
         assert_finish!("-2", int_literal, h::ilit(-2));
+        assert_finish!("1_000", int_literal, h::ilit(1000));
+        assert_finish!("0xFF00", int_literal, h::ilit(0xFF00));
+        assert_finish!("0xdead_beef", int_literal, h::ilit(0xdead_beef));
+        assert_finish!("0o755", int_literal, h::ilit(0o755));
+        assert_finish!("0b1010", int_literal, h::ilit(0b1010));
+        // '_' may follow the prefix and any digit.
+        assert_finish!("0x_FF", int_literal, h::ilit(0xFF));
+        assert_finish!("0xFF_", int_literal, h::ilit(0xFF));
+        assert_finish!("0o__7_5_5", int_literal, h::ilit(0o755));
+        assert_finish!("0b_1010_", int_literal, h::ilit(0b1010));
+        assert_finish!("1_000_", int_literal, h::ilit(1000));
+        assert_finish!("-0x8000_0000_0000_0000", int_literal, h::ilit(i64::MIN));
+        assert_finish!("9223372036854775807", int_literal, h::ilit(i64::MAX));
+    }
+
+    #[test]
+    fn int_literal_errors() {
+        let message = |s| parse_expr(s).unwrap_err().message;
+        assert_eq!(
+            message("9223372036854775808"),
+            "1:1: integer literal 9223372036854775808 is out of range"
+        );
+        assert_eq!(
+            message("[1, 0x1_0000_0000_0000_0000]"),
+            "1:5: integer literal 0x1_0000_0000_0000_0000 is out of range"
+        );
+        assert_eq!(
+            parse_module("{\n  a: -0b11111111111111111111111111111111111111111111111111111111111111111\n  b: 1\n}")
+                .unwrap_err()
+                .message,
+            "2:6: integer literal -0b11111111111111111111111111111111111111111111111111111111111111111 is out of range"
+        );
+        assert_eq!(
+            message("0o8"),
+            "1:3: expected octal digits after '0o', found '8'"
+        );
+        // A prefix without digits is not a size literal like 0B.
+        assert_eq!(
+            message("0b"),
+            "1:3: expected binary digits after '0b', found end of input"
+        );
+        assert_eq!(
+            message("[0x, 1]"),
+            "1:4: expected hexadecimal digits after '0x', found ','"
+        );
+        assert!(matches!(
+            parse_expr("0B").as_deref(),
+            Ok(ast::Expr::Literal(ast::Literal::Size(0)))
+        ));
+        // A literal needs at least one digit, and cannot start with '_'.
+        assert!(parse_expr("0x_").is_err());
+        assert!(parse_expr("0b__").is_err());
+        assert!(parse_expr("0_x1").is_err());
+        assert!(matches!(
+            parse_expr("_1").as_deref(),
+            Ok(ast::Expr::Var(v)) if v.name == "_1"
+        ));
+    }
+
+    #[test]
+    fn size_literal_works() {
+        let size = |n| ast::Literal::Size(n);
+        assert_finish!("100B", size_literal, size(100));
+        assert_finish!("512MiB", size_literal, size(512 << 20));
+        assert_finish!("2GB", size_literal, size(2_000_000_000));
+        assert_finish!("1_024kb", size_literal, size(1_024_000));
+        assert_finish!("3TiB", size_literal, size(3 << 40));
+        // Units require a trailing "B":
+        assert!(size_literal::<nom::error::Error<&str>>("2G").is_err());
+        assert!(size_literal::<nom::error::Error<&str>>("2GBx").is_err());
+        assert!(size_literal::<nom::error::Error<&str>>("20000000TiB").is_err());
+        let message = |s| parse_expr(s).unwrap_err().message;
+        assert_eq!(
+            message("20000000TiB"),
+            "1:1: size literal 20000000TiB is out of range"
+        );
+        assert_eq!(
+            message("[1B, 99_999_999_999_999_999_999B]"),
+            "1:6: size literal 99_999_999_999_999_999_999B is out of range"
+        );
+    }
+
+    #[test]
+    fn duration_literal_works() {
+        let d = |n| ast::Literal::Duration(n);
+        assert_finish!("30s", duration_literal, d(30_000_000_000));
+        assert_finish!("1h30m", duration_literal, d(5_400_000_000_000));
+        assert_finish!("250ms", duration_literal, d(250_000_000));
+        assert!(duration_literal::<nom::error::Error<&str>>("5min").is_err());
+        assert!(duration_literal::<nom::error::Error<&str>>("5").is_err());
+        assert!(duration_literal::<nom::error::Error<&str>>("5s_").is_err());
     }
 
     #[test]
@@ -450,6 +1344,141 @@ mod tests {
         );
     }
 
+    #[test]
+    fn comprehensions_and_lambdas() {
+        let v = h::var_expr;
+        let comp = |vars: &[&str], iter, cond| ast::Comp {
+            vars: vars.iter().map(|x| h::var(x)).collect(),
+            iter,
+            cond,
+        };
+        assert_finish!(
+            "[x for x in xs if x]",
+            expr,
+            Box::new(ast::Expr::ListComp(
+                v("x"),
+                comp(&["x"], v("xs"), Some(v("x")))
+            ))
+        );
+        assert_finish!(
+            "{ [k]: v for k, v in r }",
+            expr,
+            Box::new(ast::Expr::RecComp(
+                v("k"),
+                v("v"),
+                comp(&["k", "v"], v("r"), None)
+            ))
+        );
+        let format = |f: &str, args| {
+            Box::new(ast::Expr::Call(ast::Call {
+                fun: Box::new(ast::Expr::FieldAcc(
+                    Box::new(ast::Expr::Literal(ast::Literal::Str(f.to_string()))),
+                    "format".to_string(),
+                )),
+                args,
+            }))
+        };
+        assert_finish!(
+            "{ \"${k}\": v for k, v in r }",
+            expr,
+            Box::new(ast::Expr::RecComp(
+                format("{}", vec![*v("k")]),
+                v("v"),
+                comp(&["k", "v"], v("r"), None)
+            ))
+        );
+        assert_finish!(
+            "{\"{${k}-${ v }}\": v\n  for k, v in r}",
+            expr,
+            Box::new(ast::Expr::RecComp(
+                format("{{{}-{}}}", vec![*v("k"), *v("v")]),
+                v("v"),
+                comp(&["k", "v"], v("r"), None)
+            ))
+        );
+        // Without interpolation, the key is a plain string.
+        assert_finish!(
+            "{\"$k\": v for k, v in r}",
+            expr,
+            Box::new(ast::Expr::RecComp(
+                Box::new(ast::Expr::Literal(ast::Literal::Str("$k".to_string()))),
+                v("v"),
+                comp(&["k", "v"], v("r"), None)
+            ))
+        );
+        // A field named "for" after the first one is not a comprehension.
+        assert!(matches!(
+            parse_expr("{\"k\": v\n  for: 1}").as_deref(),
+            Ok(ast::Expr::Rec(_))
+        ));
+        assert!(parse_expr("{\"${k\": v for k, v in r}").is_err());
+        assert_finish!(
+            "(a, b) => a",
+            expr,
+            Box::new(ast::Expr::Fun(ast::Fun {
+                params: vec![h::var("a"), h::var("b")],
+                body: v("a").into(),
+            }))
+        );
+        // Variables that merely start with a keyword.
+        assert_finish!(
+            "[format for format in iffy]",
+            expr,
+            Box::new(ast::Expr::ListComp(
+                v("format"),
+                comp(&["format"], v("iffy"), None)
+            ))
+        );
+        assert!(parse_expr("[x for in xs]").is_err());
+    }
+
+    #[test]
+    fn expr_null_safe() {
+        use ast::BinOp::{Coalesce, LogicalOr};
+        let v = h::var_expr;
+        let opt = |e, f: &str| Box::new(ast::Expr::OptFieldAcc(e, f.to_string()));
+        assert_finish!("a?.b?.c", expr, opt(opt(v("a"), "b"), "c"));
+        assert_finish!("a?.b.c", expr, h::acc_expr(opt(v("a"), "b"), "c"));
+        // ?? binds less tightly than all other operators.
+        assert_finish!(
+            "a ?? b || c",
+            expr,
+            h::binexpr(v("a"), Coalesce, h::binexpr(v("b"), LogicalOr, v("c")))
+        );
+        assert_finish!(
+            "nil ?? nil_x",
+            expr,
+            h::binexpr(
+                Box::new(ast::Expr::Literal(ast::Literal::Nil)),
+                Coalesce,
+                v("nil_x")
+            )
+        );
+    }
+
+    #[test]
+    fn expr_call() {
+        let l = h::ilit_expr;
+        let v = h::var_expr;
+        let call = |f, args: Vec<Box<ast::Expr>>| {
+            Box::new(ast::Expr::Call(ast::Call {
+                fun: f,
+                args: args.into_iter().map(|e| *e).collect(),
+            }))
+        };
+        assert_finish!("f()", expr, call(v("f"), vec![]));
+        assert_finish!("f(1, x)", expr, call(v("f"), vec![l(1), v("x")]));
+        assert_finish!(
+            "str.len(\"a\").x",
+            expr,
+            h::acc_expr(
+                call(h::acc_expr(v("str"), "len"), vec![h::slit_expr("a")]),
+                "x"
+            )
+        );
+        assert_finish!("f(1)(2)", expr, call(call(v("f"), vec![l(1)]), vec![l(2)]));
+    }
+
     #[test]
     fn rec_works() {
         let l = h::ilit_expr;
@@ -457,6 +1486,32 @@ mod tests {
         let r = h::rec_expr;
         assert_finish!("{}", rec, r(vec![]));
         assert_finish!("{}", rec, r(vec![]));
+        // Hidden fields.
+        let hidden = |name: &str| ast::Field {
+            name: name.to_string(),
+            value: l(1),
+            hidden: true,
+            pos: ast::Pos::default(),
+        };
+        let rec_of = |fields| {
+            Box::new(ast::Expr::Rec(ast::Rec {
+                let_vars: vec![],
+                fields,
+                asserts: vec![],
+            }))
+        };
+        assert_finish!("{a:: 1}", rec, rec_of(vec![hidden("a")]));
+        assert_finish!(
+            "{\"a b\" :: 1, c: 1}",
+            rec,
+            rec_of(vec![
+                hidden("a b"),
+                ast::Field {
+                    hidden: false,
+                    ..hidden("c")
+                }
+            ])
+        );
         assert_finish!(
             r#"{
             x: 7
@@ -465,6 +1520,14 @@ mod tests {
             rec,
             r(vec![("x", l(7)), ("y", l(10))])
         );
+        assert_finish!(
+            r#"{
+            "content-type": "json"
+            "8080": 1
+        }"#,
+            rec,
+            r(vec![("content-type", s("json")), ("8080", l(1))])
+        );
         assert_finish!(
             r#"{
             x: {
@@ -478,6 +1541,74 @@ mod tests {
         );
     }
 
+    #[test]
+    fn rec_comma_separator() {
+        let l = h::ilit_expr;
+        let r = h::rec_expr;
+        // Fields may also be separated by commas, also at the end of a line,
+        // and the last field may be followed by one.
+        assert_finish!("{x: 7, y: 10}", rec, r(vec![("x", l(7)), ("y", l(10))]));
+        assert_finish!("{x: 7 , y: 10,}", rec, r(vec![("x", l(7)), ("y", l(10))]));
+        assert_finish!(
+            "{\n  x: 7,\n  y: 10,\n}",
+            rec,
+            r(vec![("x", l(7)), ("y", l(10))])
+        );
+        assert_finish!(
+            "{\n  x: 7, y: 8\n  z: 10\n}",
+            rec,
+            r(vec![("x", l(7)), ("y", l(8)), ("z", l(10))])
+        );
+        let (_, e) = rec::<VerboseError<&str>>("{x: 7, assert x > 0, y:: 1}").unwrap();
+        let ast::Expr::Rec(re) = *e else {
+            panic!("Expected a record, got {:?}", e);
+        };
+        assert_eq!(re.fields.len(), 2);
+        assert_eq!(re.asserts.len(), 1);
+        // A comma must follow a field: no empty fields, and no comma at the
+        // start of a line.
+        let err = |s| parse_expr(s).err().unwrap().message;
+        assert_eq!(err("{x: 7,, y: 10}"), "1:7: expected field name, found ','");
+        assert_eq!(err("{,}"), "1:2: expected field name, found ','");
+        assert_eq!(
+            err("{x: 7\n, y: 10}"),
+            "2:1: expected field name, found ','"
+        );
+        assert_eq!(
+            err("{x: 7 y: 10}"),
+            "1:7: expected ',', a line break or '}' after field, found 'y'"
+        );
+    }
+
+    #[test]
+    fn list_works() {
+        let l = h::ilit_expr;
+        let list = |es: Vec<Box<ast::Expr>>| {
+            Box::new(ast::Expr::List(es.into_iter().map(|e| *e).collect()))
+        };
+        assert_finish!("[]", expr, list(vec![]));
+        assert_finish!("[1, 2, 3]", expr, list(vec![l(1), l(2), l(3)]));
+        assert_finish!(
+            r#"[
+            1,
+            [2],
+        ]"#,
+            expr,
+            list(vec![l(1), list(vec![l(2)])])
+        );
+    }
+
+    #[test]
+    fn rec_field_pos() {
+        let input = "{\n  a: 1\n  bb: 2\n}";
+        let (_, e) = rec::<nom::error::VerboseError<&str>>(input).unwrap();
+        let ast::Expr::Rec(r) = *e else {
+            panic!("Expected a record, got {:?}", e);
+        };
+        assert_eq!(r.fields[0].pos.line_col(input), (2, 3));
+        assert_eq!(r.fields[1].pos.line_col(input), (3, 3));
+    }
+
     #[test]
     fn let_binding_works() {
         assert_finish!(
@@ -486,6 +1617,7 @@ mod tests {
             ast::LetBinding {
                 var: ast::Var {
                     name: "x".to_string(),
+                    pos: ast::Pos::default(),
                 },
                 value: h::ilit_expr(7),
             }
@@ -507,6 +1639,8 @@ mod tests {
         "#,
             module,
             ast::Module {
+                imports: vec![],
+                asserts: vec![],
                 let_vars: vec![
                     h::letvar("x", h::ilit_expr(1)),
                     h::letvar("y", h::ilit_expr(2)),
@@ -514,5 +1648,112 @@ mod tests {
                 expr: r(vec![("a", h::ilit_expr(1))]),
             }
         );
+        assert_finish!(
+            "import \"lib/base.konfi\" as base\nlet x = base.x\nx",
+            module,
+            ast::Module {
+                imports: vec![ast::Import {
+                    path: "lib/base.konfi".to_string(),
+                    var: h::var("base"),
+                    pos: ast::Pos::default(),
+                }],
+                asserts: vec![],
+                let_vars: vec![h::letvar("x", h::acc_expr(h::var_expr("base"), "x"))],
+                expr: h::var_expr("x"),
+            }
+        );
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let nested = |n| format!("{}1{}", "{a: [".repeat(n), "]}".repeat(n));
+        assert!(parse_expr(&nested(MAX_NESTING / 2 - 1)).is_ok());
+        let e = parse_expr(&nested(MAX_NESTING)).unwrap_err();
+        assert!(e.message.contains("nested more than"), "{}", e.message);
+        // Chains of operators and suffixes are limited separately.
+        let chain = |n, sep| vec!["x"; n].join(sep);
+        assert!(parse_expr(&chain(MAX_CHAIN, " + ")).is_ok());
+        assert!(parse_expr(&chain(MAX_CHAIN, ".")).is_ok());
+        for sep in [" + ", ".", " && "] {
+            let e = parse_expr(&chain(20_000, sep)).unwrap_err();
+            assert!(
+                e.message.contains("more than 1000 operators"),
+                "{}",
+                e.message
+            );
+        }
+        // Chains in parentheses are nested in the chain around them.
+        let half = chain(MAX_CHAIN / 2 + 1, " + ");
+        let e = parse_expr(&format!("{half} + ({half})")).unwrap_err();
+        assert!(
+            e.message.contains("more than 1000 operators"),
+            "{}",
+            e.message
+        );
+        // Long lists are not nested.
+        assert!(parse_expr(&format!("[{}]", chain(20_000, ", "))).is_ok());
+        // The depth is reset after an error.
+        assert!(parse_expr("[[1]]").is_ok());
+    }
+
+    #[test]
+    fn error_recovery() {
+        let input = "let = 1\nlet y 2\nlet z = 3\n{\n  a 1\n  b: [1, 2\n  c: ,\n  d: 4 5\n  e: {\n    f: )\n  }\n}\n";
+        let e = parse_module(input).unwrap_err();
+        assert_eq!(
+            e.message,
+            [
+                "1:5: expected variable name after 'let', found '='",
+                "2:7: expected '=' after variable name, found '2'",
+                "5:5: expected ':' after field name, found '1'",
+                "7:3: expected ']', found 'c'",
+                "7:6: expected a value after ':', found ','",
+                "8:8: expected ',', a line break or '}' after field, found '5'",
+                "10:8: expected a value after ':', found ')'",
+            ]
+            .join("\n")
+        );
+        assert_eq!(e.errors.len(), 7);
+        assert_eq!(e.pos.line_col(input), (1, 5));
+        // The final error of a parse that ends early.
+        let e = parse_expr("{\n  a: 1").unwrap_err();
+        assert_eq!(e.message, "2:7: expected '}', found end of input");
+        assert!(parse_module("let x = 1\n{\n  a: x\n}").is_ok());
+        // Let bindings in records.
+        let e = parse_expr("{\n  let x 5\n  let y = 1\n  a: 1\n}").unwrap_err();
+        assert_eq!(
+            e.message,
+            [
+                "2:9: expected '=' after variable name, found '5'",
+                "3:3: let bindings are only allowed before the expression of a module",
+            ]
+            .join("\n")
+        );
+        assert!(parse_expr("{let: 1, let : 2}").is_ok());
+    }
+
+    #[test]
+    fn asserts() {
+        let input = "let x = 1\nassert x > 0\n{\n  assert: 1\n  assert x : \"msg\"\n}";
+        let m = parse_module(input).ok().unwrap();
+        assert_eq!(m.asserts.len(), 1);
+        assert_eq!(m.asserts[0].pos.line_col(input), (2, 1));
+        let ast::Expr::Rec(r) = m.expr.as_ref() else {
+            panic!("expected a record, got {:?}", m.expr);
+        };
+        assert_eq!(r.fields.len(), 1);
+        assert_eq!(r.asserts.len(), 1);
+        assert_eq!(r.asserts[0].pos.line_col(input), (5, 3));
+        assert_eq!(r.asserts[0].message, Some(h::slit_expr("msg")));
+        let e = parse_module("assert ,\n{\n  assert x : )\n  assert y z\n}").unwrap_err();
+        assert_eq!(
+            e.message,
+            [
+                "1:8: expected a value after 'assert', found ','",
+                "3:14: expected an expression, found ')'",
+                "4:12: expected ',', a line break or '}' after field, found 'z'",
+            ]
+            .join("\n")
+        );
     }
 }
@@ -0,0 +1,170 @@
+// Interactive read-eval-print loop (konfi repl).
+
+use konfi::engine::Engine;
+use konfi::{eval, parser};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::fs;
+use std::rc::Rc;
+
+const HELP: &str = "\
+Enter an expression to evaluate it, or `let x = <expr>` to bind a variable.
+Commands:
+  :load FILE   evaluate FILE and bind each of its top-level fields
+  :type EXPR   print the type of EXPR
+  :help        show this help
+  :quit        exit (or press Ctrl-D)";
+
+const HISTORY_FILE: &str = ".konfi_history";
+
+// The result of processing one line of input.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Print(String),
+    Silent,
+    Quit,
+}
+
+struct Repl {
+    engine: Engine,
+    // All let bindings and loaded fields live in this scope.
+    scope: Rc<eval::Ctx<'static>>,
+}
+
+impl Repl {
+    fn new(engine: Engine) -> Self {
+        let scope = eval::Ctx::scope_of(engine.context(None));
+        Repl { engine, scope }
+    }
+
+    fn eval_expr(&self, input: &str) -> Result<eval::Val, String> {
+        let e = parser::parse_expr(input).map_err(|e| e.message)?;
+        eval::eval_checked(&e, input, Rc::clone(&self.scope)).map_err(|e| e.message)
+    }
+
+    fn process(&self, line: &str) -> Result<Outcome, String> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(Outcome::Silent);
+        }
+        if let Some(cmd) = line.strip_prefix(':') {
+            let (cmd, arg) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+            let arg = arg.trim();
+            return match cmd {
+                "load" | "l" => self.load(arg),
+                "type" | "t" => Ok(Outcome::Print(self.eval_expr(arg)?.typ().to_string())),
+                "help" | "h" | "?" => Ok(Outcome::Print(HELP.to_string())),
+                "quit" | "q" => Ok(Outcome::Quit),
+                _ => Err(format!("Unknown command ':{cmd}'. Type :help for help.")),
+            };
+        }
+        if line.starts_with("let ") {
+            let lb = parser::parse_let_binding(line).map_err(|e| e.message)?;
+            let v = eval::eval_checked(&lb.value, line, Rc::clone(&self.scope))
+                .map_err(|e| e.message)?;
+            self.scope.setvar(&lb.var.name, v);
+            return Ok(Outcome::Silent);
+        }
+        Ok(Outcome::Print(self.eval_expr(line)?.to_string()))
+    }
+
+    // Evaluates the module in `path` and binds its fields in the REPL scope.
+    fn load(&self, path: &str) -> Result<Outcome, String> {
+        if path.is_empty() {
+            return Err("Usage: :load FILE".to_string());
+        }
+        let input = fs::read_to_string(path).map_err(|e| format!("Cannot read {path}: {e}"))?;
+        let m = parser::parse_module(&input)
+            .map_err(|e| format!("Cannot parse {path}:\n{}", e.message))?;
+        let v = eval::eval_module(&m, &input, self.engine.context(Some(path)))
+            .map_err(|e| format!("Cannot eval {path}: {}", e.message))?;
+        let eval::Val::Rec(r) = v else {
+            return Err(format!("{path} evaluates to a {}, not a rec", v.typ()));
+        };
+        let mut names: Vec<String> = r.borrow().fields.keys().cloned().collect();
+        names.sort();
+        for (f, fv) in r.borrow().fields.iter() {
+            self.scope.setvar(f, fv.clone());
+        }
+        Ok(Outcome::Print(format!(
+            "Loaded {} field(s) from {path}: {}",
+            names.len(),
+            names.join(", ")
+        )))
+    }
+}
+
+pub fn run(engine: Engine) -> rustyline::Result<()> {
+    let repl = Repl::new(engine);
+    let mut rl = DefaultEditor::new()?;
+    let history = std::env::var("HOME")
+        .map(|h| format!("{h}/{HISTORY_FILE}"))
+        .unwrap_or(HISTORY_FILE.to_string());
+    // There is no history file on first use.
+    let _ = rl.load_history(&history);
+    println!("konfi REPL. Type :help for help.");
+    loop {
+        match rl.readline("konfi> ") {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    rl.add_history_entry(line.as_str())?;
+                }
+                match repl.process(&line) {
+                    Ok(Outcome::Print(s)) => println!("{s}"),
+                    Ok(Outcome::Silent) => {}
+                    Ok(Outcome::Quit) => break,
+                    Err(e) => eprintln!("{e}"),
+                }
+            }
+            // Ctrl-C discards the current line, Ctrl-D exits.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    rl.save_history(&history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn print(s: &str) -> Result<Outcome, String> {
+        Ok(Outcome::Print(s.to_string()))
+    }
+
+    #[test]
+    fn let_and_eval() {
+        let r = Repl::new(Engine::new());
+        assert_eq!(r.process("let x = 2"), Ok(Outcome::Silent));
+        assert_eq!(r.process("let y = {a: x * 3}"), Ok(Outcome::Silent));
+        assert_eq!(r.process("y"), print("{a: 6}"));
+        assert_eq!(r.process("  y.a + x  "), print("8"));
+        assert_eq!(r.process(":type y"), print("rec"));
+        assert_eq!(r.process(":q"), Ok(Outcome::Quit));
+        assert!(r.process("z").unwrap_err().contains("Unbound variable 'z'"));
+        assert!(r.process(":nope").is_err());
+    }
+
+    #[test]
+    fn load_file() {
+        let path = std::env::temp_dir().join(format!("konfi-repl-{}.konfi", std::process::id()));
+        fs::write(
+            &path,
+            "let p = 80\n{\n  port: p\n  host: \"localhost\"\n}\n",
+        )
+        .unwrap();
+        let r = Repl::new(Engine::new());
+        let out = r.process(&format!(":load {}", path.display()));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            out,
+            print(&format!(
+                "Loaded 2 field(s) from {}: host, port",
+                path.display()
+            ))
+        );
+        assert_eq!(r.process("port + 1"), print("81"));
+        assert_eq!(r.process("host"), print("\"localhost\""));
+    }
+}
@@ -0,0 +1,626 @@
+// Schema declarations and type checking of evaluated values.
+//
+// A schema is itself a konfi value, so it can use let bindings and records
+// like any other konfi file:
+//
+//     {
+//         host: "str"
+//         port: {
+//             type: "int"
+//             min: 1
+//             max: 65535
+//         }
+//         name: {
+//             type: "str?"
+//             regex: "[a-z][a-z0-9-]*"
+//         }
+//         tags: ["str"]
+//         limits: {
+//             cpus: "int"
+//             mem_mb: "int?"
+//         }
+//     }
+//
+// A schema is one of
+//   * a type name: "int", "double", "str", "bool", "size", "duration",
+//     "list", "rec" or "any". A trailing "?" makes the value optional, i.e. it may
+//     be missing or nil.
+//   * a record without a "type" field: a record with exactly the given fields.
+//   * a list with a single element: a list whose elements match that schema.
+//   * a record with a "type" field: a type name with constraints:
+//       - "int", "double", "size": min, max (inclusive)
+//       - "str": regex (must match the whole string), min_len, max_len
+//       - "list": of (element schema), min_len, max_len
+//       - "rec": fields (record schema), open (if truthy, allows extra fields)
+//     To declare a record with a field that is itself called "type", use
+//     a detailed spec with type "rec" and the record schema in "fields".
+
+use crate::ast;
+use crate::eval::{Rec, Val};
+use regex::Regex;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Debug)]
+pub struct SchemaError {
+    pub message: String,
+}
+
+type SchemaResult<T> = Result<T, SchemaError>;
+
+#[derive(Debug)]
+pub struct Schema {
+    pub typ: Type,
+    pub optional: bool,
+}
+
+#[derive(Debug)]
+pub enum Type {
+    Any,
+    Bool,
+    Duration,
+    Size {
+        min: Option<u64>,
+        max: Option<u64>,
+    },
+    Int {
+        min: Option<i64>,
+        max: Option<i64>,
+    },
+    Double {
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    Str {
+        regex: Option<Regex>,
+        min_len: Option<usize>,
+        max_len: Option<usize>,
+    },
+    List {
+        of: Option<Box<Schema>>,
+        min_len: Option<usize>,
+        max_len: Option<usize>,
+    },
+    Rec(Option<RecSchema>),
+}
+
+#[derive(Debug)]
+pub struct RecSchema {
+    pub fields: Vec<(String, Schema)>,
+    // Open record schemas allow fields that are not declared.
+    pub open: bool,
+}
+
+/// A value that does not conform to its schema.
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    // Path of the offending value, e.g. "servers[1].port".
+    pub path: String,
+    pub message: String,
+    // Position of the field definition that produced the value, if known.
+    pub pos: Option<ast::Pos>,
+}
+
+fn schema_error<T>(message: String) -> SchemaResult<T> {
+    Err(SchemaError { message })
+}
+
+impl Schema {
+    /// Builds a schema from its declaration as a konfi value.
+    pub fn from_val(v: &Val) -> SchemaResult<Schema> {
+        match v {
+            Val::Str(s) => Self::from_type_name(s, None),
+            Val::List(l) => match &l[..] {
+                [elem] => Ok(Schema {
+                    typ: Type::List {
+                        of: Some(Box::new(Self::from_val(elem)?)),
+                        min_len: None,
+                        max_len: None,
+                    },
+                    optional: false,
+                }),
+                _ => schema_error(format!(
+                    "List schemas must have exactly one element, got {}",
+                    l.len()
+                )),
+            },
+            Val::Rec(r) => {
+                let r = r.borrow();
+                match r.getattr("type") {
+                    Some(Val::Str(t)) => Self::from_type_name(&t, Some(&r)),
+                    _ => Ok(Schema {
+                        typ: Type::Rec(Some(RecSchema::from_rec(&r)?)),
+                        optional: false,
+                    }),
+                }
+            }
+            _ => schema_error(format!("Invalid schema value of type '{}'", v.typ())),
+        }
+    }
+
+    // Builds a schema from a type name and, if given, a record of constraints.
+    fn from_type_name(name: &str, spec: Option<&Rec>) -> SchemaResult<Schema> {
+        let (name, optional) = match name.strip_suffix('?') {
+            Some(n) => (n, true),
+            None => (name, false),
+        };
+        let c = Constraints { spec };
+        let typ = match name {
+            "any" => {
+                c.allow(&[])?;
+                Type::Any
+            }
+            "bool" => {
+                c.allow(&[])?;
+                Type::Bool
+            }
+            "duration" => {
+                c.allow(&[])?;
+                Type::Duration
+            }
+            "size" => {
+                c.allow(&["min", "max"])?;
+                Type::Size {
+                    min: c.size("min")?,
+                    max: c.size("max")?,
+                }
+            }
+            "int" => {
+                c.allow(&["min", "max"])?;
+                Type::Int {
+                    min: c.int("min")?,
+                    max: c.int("max")?,
+                }
+            }
+            "double" => {
+                c.allow(&["min", "max"])?;
+                Type::Double {
+                    min: c.double("min")?,
+                    max: c.double("max")?,
+                }
+            }
+            "str" => {
+                c.allow(&["regex", "min_len", "max_len"])?;
+                let regex = match c.get("regex") {
+                    None => None,
+                    Some(Val::Str(re)) => match Regex::new(&format!("^(?:{re})$")) {
+                        Ok(re) => Some(re),
+                        Err(e) => return schema_error(format!("Invalid regex '{re}': {e}")),
+                    },
+                    Some(v) => {
+                        return schema_error(format!("'regex' must be a str, got {}", v.typ()))
+                    }
+                };
+                Type::Str {
+                    regex,
+                    min_len: c.len("min_len")?,
+                    max_len: c.len("max_len")?,
+                }
+            }
+            "list" => {
+                c.allow(&["of", "min_len", "max_len"])?;
+                let of = match c.get("of") {
+                    Some(v) => Some(Box::new(Self::from_val(&v)?)),
+                    None => None,
+                };
+                Type::List {
+                    of,
+                    min_len: c.len("min_len")?,
+                    max_len: c.len("max_len")?,
+                }
+            }
+            "rec" => {
+                c.allow(&["fields", "open"])?;
+                match c.get("fields") {
+                    Some(Val::Rec(r)) => {
+                        let mut rs = RecSchema::from_rec(&r.borrow())?;
+                        rs.open = c.get("open").is_some_and(|v| v.to_bool());
+                        Type::Rec(Some(rs))
+                    }
+                    Some(v) => {
+                        return schema_error(format!("'fields' must be a rec, got {}", v.typ()))
+                    }
+                    None => Type::Rec(None),
+                }
+            }
+            _ => return schema_error(format!("Unknown type '{name}'")),
+        };
+        Ok(Schema { typ, optional })
+    }
+
+    /// Checks `v` against this schema and returns all violations.
+    pub fn check(&self, v: &Val) -> Vec<Violation> {
+        let mut vs = Vec::new();
+        self.check_at(v, "", None, &mut vs);
+        vs
+    }
+
+    fn check_at(&self, v: &Val, path: &str, pos: Option<ast::Pos>, vs: &mut Vec<Violation>) {
+        let mut violation = |message: String| {
+            vs.push(Violation {
+                path: path.to_string(),
+                message,
+                pos,
+            })
+        };
+        if *v == Val::Nil {
+            if !self.optional {
+                violation("expected a value, got nil".to_string());
+            }
+            return;
+        }
+        match (&self.typ, v) {
+            (Type::Any, _) => {}
+            (Type::Bool, Val::Bool(_)) => {}
+            (Type::Duration, Val::Duration(_)) => {}
+            (Type::Size { min, max }, Val::Size(s)) => {
+                if let Some(m) = min.filter(|m| s < m) {
+                    violation(format!("{v} is less than the minimum {}", Val::Size(m)));
+                }
+                if let Some(m) = max.filter(|m| s > m) {
+                    violation(format!("{v} is greater than the maximum {}", Val::Size(m)));
+                }
+            }
+            (Type::Int { min, max }, Val::Int(i)) => {
+                if let Some(m) = min.filter(|m| i < m) {
+                    violation(format!("{i} is less than the minimum {m}"));
+                }
+                if let Some(m) = max.filter(|m| i > m) {
+                    violation(format!("{i} is greater than the maximum {m}"));
+                }
+            }
+            (Type::Double { min, max }, Val::Double(_) | Val::Int(_)) => {
+                let d = match v {
+                    Val::Int(i) => *i as f64,
+                    Val::Double(d) => *d,
+                    _ => unreachable!(),
+                };
+                if let Some(m) = min.filter(|m| d < *m) {
+                    violation(format!("{d} is less than the minimum {m}"));
+                }
+                if let Some(m) = max.filter(|m| d > *m) {
+                    violation(format!("{d} is greater than the maximum {m}"));
+                }
+            }
+            (
+                Type::Str {
+                    regex,
+                    min_len,
+                    max_len,
+                },
+                Val::Str(s),
+            ) => {
+                if let Some(re) = regex.as_ref().filter(|re| !re.is_match(s)) {
+                    let re = re.as_str();
+                    // Strip the anchors we added in from_type_name.
+                    violation(format!(
+                        "\"{s}\" does not match the regex '{}'",
+                        &re[4..re.len() - 2]
+                    ));
+                }
+                check_len(s.chars().count(), *min_len, *max_len, &mut violation);
+            }
+            (
+                Type::List {
+                    of,
+                    min_len,
+                    max_len,
+                },
+                Val::List(l),
+            ) => {
+                check_len(l.len(), *min_len, *max_len, &mut violation);
+                if let Some(of) = of {
+                    for (i, elem) in l.iter().enumerate() {
+                        of.check_at(elem, &format!("{path}[{i}]"), pos, vs);
+                    }
+                }
+            }
+            (Type::Rec(rs), Val::Rec(r)) => {
+                if let Some(rs) = rs {
+                    rs.check_at(r, path, pos, vs);
+                }
+            }
+            (t, _) => violation(format!("expected {}, got {}", t.name(), v.typ())),
+        }
+    }
+}
+
+fn check_len(
+    len: usize,
+    min_len: Option<usize>,
+    max_len: Option<usize>,
+    violation: &mut impl FnMut(String),
+) {
+    if let Some(m) = min_len.filter(|m| len < *m) {
+        violation(format!("length {len} is less than the minimum {m}"));
+    }
+    if let Some(m) = max_len.filter(|m| len > *m) {
+        violation(format!("length {len} is greater than the maximum {m}"));
+    }
+}
+
+impl Type {
+    fn name(&self) -> &str {
+        match self {
+            Type::Any => "any",
+            Type::Bool => "bool",
+            Type::Duration => "duration",
+            Type::Size { .. } => "size",
+            Type::Int { .. } => "int",
+            Type::Double { .. } => "double",
+            Type::Str { .. } => "str",
+            Type::List { .. } => "list",
+            Type::Rec(_) => "rec",
+        }
+    }
+}
+
+impl RecSchema {
+    fn from_rec(r: &Rec) -> SchemaResult<RecSchema> {
+        let mut fields = Vec::with_capacity(r.fields.len());
+        for (name, v) in r.fields.iter() {
+            match Schema::from_val(v) {
+                Ok(s) => fields.push((name.clone(), s)),
+                Err(e) => return schema_error(format!("{name}: {}", e.message)),
+            }
+        }
+        // Report violations in a stable order.
+        fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(RecSchema {
+            fields,
+            open: false,
+        })
+    }
+
+    fn check_at(
+        &self,
+        r: &Rc<RefCell<Rec>>,
+        path: &str,
+        pos: Option<ast::Pos>,
+        vs: &mut Vec<Violation>,
+    ) {
+        let r = r.borrow();
+        let field_path = |f: &str| {
+            if path.is_empty() {
+                f.to_string()
+            } else {
+                format!("{path}.{f}")
+            }
+        };
+        // Hidden fields are not part of the output, so they are not checked.
+        for (f, s) in self.fields.iter() {
+            match r.getattr(f).filter(|_| !r.is_hidden(f)) {
+                Some(v) => s.check_at(&v, &field_path(f), r.getloc(f), vs),
+                None if s.optional => {}
+                None => vs.push(Violation {
+                    path: field_path(f),
+                    message: "missing required field".to_string(),
+                    pos,
+                }),
+            }
+        }
+        if self.open {
+            return;
+        }
+        let mut unknown: Vec<&String> = r
+            .visible()
+            .map(|(f, _)| f)
+            .filter(|f| !self.fields.iter().any(|(g, _)| g == *f))
+            .collect();
+        unknown.sort();
+        for f in unknown {
+            vs.push(Violation {
+                path: field_path(f),
+                message: "unknown field".to_string(),
+                pos: r.getloc(f),
+            });
+        }
+    }
+}
+
+// Typed access to the constraints of a detailed type spec.
+struct Constraints<'a> {
+    spec: Option<&'a Rec>,
+}
+
+impl<'a> Constraints<'a> {
+    fn get(&self, key: &str) -> Option<Val> {
+        self.spec.and_then(|r| r.getattr(key))
+    }
+
+    // Fails if the spec has any keys other than "type" and `keys`.
+    fn allow(&self, keys: &[&str]) -> SchemaResult<()> {
+        let Some(r) = self.spec else {
+            return Ok(());
+        };
+        let mut bad: Vec<&String> = r
+            .fields
+            .keys()
+            .filter(|k| *k != "type" && !keys.contains(&k.as_str()))
+            .collect();
+        bad.sort();
+        match bad.first() {
+            Some(k) => schema_error(format!("Invalid constraint '{k}'")),
+            None => Ok(()),
+        }
+    }
+
+    fn int(&self, key: &str) -> SchemaResult<Option<i64>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Val::Int(i)) => Ok(Some(i)),
+            Some(v) => schema_error(format!("'{key}' must be an int, got {}", v.typ())),
+        }
+    }
+
+    fn double(&self, key: &str) -> SchemaResult<Option<f64>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Val::Int(i)) => Ok(Some(i as f64)),
+            Some(Val::Double(d)) => Ok(Some(d)),
+            Some(v) => schema_error(format!("'{key}' must be a number, got {}", v.typ())),
+        }
+    }
+
+    fn size(&self, key: &str) -> SchemaResult<Option<u64>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Val::Size(s)) => Ok(Some(s)),
+            Some(v) => schema_error(format!("'{key}' must be a size, got {}", v.typ())),
+        }
+    }
+
+    fn len(&self, key: &str) -> SchemaResult<Option<usize>> {
+        match self.int(key)? {
+            None => Ok(None),
+            Some(i) if i >= 0 => Ok(Some(i as usize)),
+            Some(i) => schema_error(format!("'{key}' must not be negative, got {i}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval;
+    use crate::parser;
+
+    fn eval_str(s: &str) -> Val {
+        let m = parser::parse_module(s)
+            .ok()
+            .unwrap_or_else(|| panic!("Expected being able to parse: {}", s));
+        eval::eval_module(&m, s, eval::Ctx::global()).unwrap()
+    }
+
+    fn check(schema: &str, input: &str) -> Vec<(String, String)> {
+        let s = Schema::from_val(&eval_str(schema)).unwrap();
+        s.check(&eval_str(input))
+            .into_iter()
+            .map(|v| (v.path, v.message))
+            .collect()
+    }
+
+    fn violation(path: &str, message: &str) -> (String, String) {
+        (path.to_string(), message.to_string())
+    }
+
+    #[test]
+    fn check_ok() {
+        let schema = r#"{
+            host: "str"
+            port: {
+                type: "int"
+                min: 1
+                max: 65535
+            }
+            tags: ["str"]
+            opt: "int?"
+        }"#;
+        let input = r#"{
+            domain:: "example.com"
+            host: domain
+            port: 8080
+            tags: ["a", "b"]
+        }"#;
+        assert_eq!(check(schema, input), vec![]);
+        // Hidden fields are not part of the checked value.
+        assert_eq!(
+            check(schema, "{\n host:: \"h\"\n port: 1\n tags: []\n}"),
+            vec![violation("host", "missing required field")]
+        );
+    }
+
+    #[test]
+    fn check_violations() {
+        let schema = r#"{
+            host: {
+                type: "str"
+                regex: "[a-z.]+"
+            }
+            port: {
+                type: "int"
+                min: 1
+            }
+            backends: [{
+                name: "str"
+            }]
+        }"#;
+        let input = r#"{
+            host: "Example.com"
+            port: "8080"
+            backends: [{name: "a"}, {nmae: "b"}]
+            prot: 1
+        }"#;
+        assert_eq!(
+            check(schema, input),
+            vec![
+                violation("backends[1].name", "missing required field"),
+                violation("backends[1].nmae", "unknown field"),
+                violation("host", "\"Example.com\" does not match the regex '[a-z.]+'"),
+                violation("port", "expected int, got str"),
+                violation("prot", "unknown field"),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_ranges() {
+        let schema = r#"{
+            a: {
+                type: "int"
+                min: 1
+            }
+            b: {
+                type: "double"
+                max: 2
+            }
+            c: {
+                type: "list"
+                of: "int"
+                max_len: 1
+            }
+            d: {
+                type: "size"
+                max: 1GiB
+            }
+        }"#;
+        let input = r#"{
+            a: 0
+            b: 3
+            c: [1, 2]
+            d: 2GiB
+        }"#;
+        assert_eq!(
+            check(schema, input),
+            vec![
+                violation("a", "0 is less than the minimum 1"),
+                violation("b", "3 is greater than the maximum 2"),
+                violation("c", "length 2 is greater than the maximum 1"),
+                violation("d", "2GiB is greater than the maximum 1GiB"),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_violation_pos() {
+        let input = "{\n  a: {\n    b: 1\n  }\n}";
+        let s = Schema::from_val(&eval_str(r#"{a: {b: "str"}}"#)).unwrap();
+        let vs = s.check(&eval_str(input));
+        assert_eq!(vs.len(), 1);
+        assert_eq!(vs[0].pos.map(|p| p.line_col(input)), Some((3, 5)));
+    }
+
+    #[test]
+    fn invalid_schema() {
+        let err = |s| Schema::from_val(&eval_str(s)).unwrap_err().message;
+        assert_eq!(err(r#""integer""#), "Unknown type 'integer'");
+        assert_eq!(
+            err("{a: {\n type: \"str\"\n min: 1\n}}"),
+            "a: Invalid constraint 'min'"
+        );
+        assert_eq!(
+            err(r#"["int", "str"]"#),
+            "List schemas must have exactly one element, got 2"
+        );
+    }
+}
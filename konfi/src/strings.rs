@@ -1,10 +1,10 @@
 use nom::branch::alt;
-use nom::bytes::streaming::{is_not, take_while_m_n};
+use nom::bytes::streaming::{is_not, tag, take_while_m_n};
 use nom::character::streaming::{char, multispace1};
-use nom::combinator::{map, map_opt, map_res, value, verify};
+use nom::combinator::{complete, map, map_opt, map_res, not, value, verify};
 use nom::error::{FromExternalError, ParseError};
 use nom::multi::fold_many0;
-use nom::sequence::{delimited, preceded};
+use nom::sequence::{delimited, preceded, terminated};
 use nom::IResult;
 
 // This code is essentially a copy of
@@ -98,7 +98,41 @@ where
         },
     );
 
-    delimited(char('"'), build_string, char('"'))(input)
+    // The fragment parsers are streaming; at the end of input, report an
+    // ordinary error instead of Incomplete.
+    complete(delimited(char('"'), build_string, char('"')))(input)
+}
+
+/// Parse the text of an interpolated string up to the closing quote or the
+/// next interpolation `${`. A `$` that is not followed by `{` is ordinary text.
+pub fn parse_template_text<'a, E>(input: &'a str) -> IResult<&'a str, String, E>
+where
+    E: ParseError<&'a str> + FromExternalError<&'a str, std::num::ParseIntError>,
+{
+    let build_string = fold_many0(
+        alt((
+            map(
+                verify(is_not("\"$\\"), |s: &str| !s.is_empty()),
+                StringFragment::Literal,
+            ),
+            map(
+                terminated(tag("$"), not(char('{'))),
+                StringFragment::Literal,
+            ),
+            map(parse_escaped_char, StringFragment::EscapedChar),
+            value(StringFragment::EscapedWS, parse_escaped_whitespace),
+        )),
+        String::new,
+        |mut string, fragment| {
+            match fragment {
+                StringFragment::Literal(s) => string.push_str(s),
+                StringFragment::EscapedChar(c) => string.push(c),
+                StringFragment::EscapedWS => {}
+            }
+            string
+        },
+    );
+    complete(build_string)(input)
 }
 
 #[cfg(test)]
@@ -118,6 +152,9 @@ mod tests {
             Ok(("", String::from("123\n456\n789\n")))
         );
         assert_eq!(p(r#""\\begin{foo}""#), Ok(("", String::from("\\begin{foo}"))));
+        // Unterminated strings are errors, not Incomplete.
+        assert!(matches!(p("\"foo"), Err(nom::Err::Error(_))));
+        assert!(matches!(p(""), Err(nom::Err::Error(_))));
     }
 
     #[test]
@@ -140,4 +177,12 @@ mod tests {
             Ok(("", String::from("abcdefghi ")))
         );
     }
+
+    #[test]
+    fn parse_template_text_stops_at_interpolation() {
+        let p = |s| parse_template_text::<nom::error::Error<&str>>(s);
+        assert_eq!(p("a$b\n${k}\""), Ok(("${k}\"", String::from("a$b\n"))));
+        assert_eq!(p("${k}\""), Ok(("${k}\"", String::new())));
+        assert_eq!(p("x$\""), Ok(("\"", String::from("x$"))));
+    }
 }
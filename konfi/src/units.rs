@@ -0,0 +1,217 @@
+// Units of byte sizes and durations.
+//
+// Byte-size units follow the same rules as netw's parse_num_with_units:
+// a unit prefix k, m, g or t (case-insensitive) multiplies by powers of
+// 1000 (SI), or by powers of 1024 (IEC) if it is followed by an "i".
+
+/// Multiplier for the given unit prefix, e.g. 'M' and iec=true => 1024^2.
+pub fn multiplier(prefix: char, iec: bool) -> Option<u64> {
+    let m: u64 = if iec { 1024 } else { 1000 };
+    match prefix.to_ascii_uppercase() {
+        'K' => Some(m),
+        'M' => Some(m * m),
+        'G' => Some(m * m * m),
+        'T' => Some(m * m * m * m),
+        _ => None,
+    }
+}
+
+/// Splits a size unit like "MiB", "kB", "G" or "" into its multiplier.
+/// The trailing "B" is optional, as in netw.
+pub fn unit_multiplier(unit: &str) -> Option<u64> {
+    let unit = unit
+        .strip_suffix('B')
+        .or_else(|| unit.strip_suffix('b'))
+        .unwrap_or(unit);
+    let mut cs = unit.chars();
+    match (cs.next(), cs.next(), cs.next()) {
+        (None, _, _) => Some(1),
+        (Some(p), None, _) => multiplier(p, false),
+        (Some(p), Some('i'), None) => multiplier(p, true),
+        _ => None,
+    }
+}
+
+/// Parses a number with an optional size unit, e.g. "512MiB" or "2G".
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let i = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(i);
+    if num.is_empty() {
+        return Err(format!("Invalid size \"{s}\""));
+    }
+    let Some(m) = unit_multiplier(unit) else {
+        return Err(format!("Invalid size unit \"{unit}\" in \"{s}\""));
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(m))
+        .ok_or_else(|| format!("Size \"{s}\" is too large"))
+}
+
+// Units used to display sizes, largest first.
+const IEC_UNITS: [(&str, u64); 4] = [
+    ("TiB", 1 << 40),
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+];
+const SI_UNITS: [(&str, u64); 4] = [
+    ("TB", 1_000_000_000_000),
+    ("GB", 1_000_000_000),
+    ("MB", 1_000_000),
+    ("kB", 1_000),
+];
+
+/// Formats `bytes` exactly, using the largest unit that divides it,
+/// e.g. 512MiB, 2GB or 1000001B.
+pub fn format_exact(bytes: u64) -> String {
+    let unit = IEC_UNITS
+        .iter()
+        .chain(SI_UNITS.iter())
+        .filter(|(_, m)| bytes != 0 && bytes.is_multiple_of(*m))
+        .max_by_key(|(_, m)| *m);
+    match unit {
+        Some((u, m)) => format!("{}{}", bytes / m, u),
+        None => format!("{bytes}B"),
+    }
+}
+
+/// Formats `bytes` approximately for humans, e.g. "1.5 GiB".
+pub fn format_human(bytes: u64, iec: bool) -> String {
+    let units = if iec { &IEC_UNITS } else { &SI_UNITS };
+    for (u, m) in units.iter() {
+        if bytes >= *m {
+            let v = bytes as f64 / *m as f64;
+            let s = format!("{v:.1}");
+            return format!("{} {}", s.strip_suffix(".0").unwrap_or(&s), u);
+        }
+    }
+    format!("{bytes} B")
+}
+
+// Duration units in nanoseconds, largest first.
+const DURATION_UNITS: [(&str, i64); 7] = [
+    ("d", 86_400_000_000_000),
+    ("h", 3_600_000_000_000),
+    ("m", 60_000_000_000),
+    ("s", 1_000_000_000),
+    ("ms", 1_000_000),
+    ("us", 1_000),
+    ("ns", 1),
+];
+
+/// Parses a duration like "90s", "1h30m" or "250ms" into nanoseconds.
+/// Digits may be separated by "_", as in number literals.
+pub fn parse_duration(s: &str) -> Result<i64, String> {
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err("Empty duration".to_string());
+    }
+    let mut total: i64 = 0;
+    while !rest.is_empty() {
+        let i = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '_'))
+            .unwrap_or(rest.len());
+        let (num, r) = rest.split_at(i);
+        let j = r
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(r.len());
+        let (unit, r) = r.split_at(j);
+        if num.is_empty() || !num.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(format!("Invalid duration \"{s}\""));
+        }
+        let Some((_, m)) = DURATION_UNITS.iter().find(|(u, _)| *u == unit) else {
+            return Err(format!("Invalid duration unit \"{unit}\" in \"{s}\""));
+        };
+        total = num
+            .replace('_', "")
+            .parse::<i64>()
+            .ok()
+            .and_then(|n| n.checked_mul(*m))
+            .and_then(|n| total.checked_add(n))
+            .ok_or_else(|| format!("Duration \"{s}\" is too large"))?;
+        rest = r;
+    }
+    Ok(total)
+}
+
+/// Formats a duration given in nanoseconds, e.g. 1h30m, 250ms or -5s.
+pub fn format_duration(nanos: i64) -> String {
+    if nanos == 0 {
+        return "0s".to_string();
+    }
+    let mut s = String::from(if nanos < 0 { "-" } else { "" });
+    let mut rest = nanos.unsigned_abs();
+    for (u, m) in DURATION_UNITS.iter() {
+        let m = *m as u64;
+        if rest >= m {
+            s.push_str(&format!("{}{}", rest / m, u));
+            rest %= m;
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_works() {
+        assert_eq!(parse_size("123"), Ok(123));
+        assert_eq!(parse_size("2k"), Ok(2000));
+        assert_eq!(parse_size("2KiB"), Ok(2048));
+        assert_eq!(parse_size("1mb"), Ok(1_000_000));
+        assert_eq!(parse_size("1Gi"), Ok(1 << 30));
+        assert_eq!(parse_size("3TB"), Ok(3_000_000_000_000));
+        assert_eq!(
+            parse_size("1 MB"),
+            Err("Invalid size unit \" MB\" in \"1 MB\"".to_string())
+        );
+        assert_eq!(
+            parse_size("1X"),
+            Err("Invalid size unit \"X\" in \"1X\"".to_string())
+        );
+        assert_eq!(parse_size("MB"), Err("Invalid size \"MB\"".to_string()));
+        assert_eq!(
+            parse_size("20000000TiB"),
+            Err("Size \"20000000TiB\" is too large".to_string())
+        );
+    }
+
+    #[test]
+    fn format_works() {
+        assert_eq!(format_exact(0), "0B");
+        assert_eq!(format_exact(512 << 20), "512MiB");
+        assert_eq!(format_exact(2_000_000_000), "2GB");
+        assert_eq!(format_exact(1_000_001), "1000001B");
+        assert_eq!(format_human(1536 << 20, true), "1.5 GiB");
+        assert_eq!(format_human(1_000_000, false), "1 MB");
+        assert_eq!(format_human(999, false), "999 B");
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90s"), Ok(90_000_000_000));
+        assert_eq!(parse_duration("1h30m"), Ok(5_400_000_000_000));
+        assert_eq!(parse_duration("1_000ms"), Ok(1_000_000_000));
+        assert_eq!(parse_duration("2d3ns"), Ok(172_800_000_000_003));
+        assert_eq!(
+            parse_duration("5min"),
+            Err("Invalid duration unit \"min\" in \"5min\"".to_string())
+        );
+        assert_eq!(
+            parse_duration("h"),
+            Err("Invalid duration \"h\"".to_string())
+        );
+        assert_eq!(
+            parse_duration("200000d"),
+            Err("Duration \"200000d\" is too large".to_string())
+        );
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(90_000_000_000), "1m30s");
+        assert_eq!(format_duration(-250_000_000), "-250ms");
+        assert_eq!(format_duration(86_400_000_001_000), "1d1us");
+    }
+}
@@ -0,0 +1,169 @@
+// Re-evaluation of a konfi file whenever it or one of its imports changes (konfi watch).
+
+use crate::{
+    engine_with, import_base, load, read_input, to_json_string, CliError, CliResult, EngineArgs,
+};
+use konfi::engine::FileResolver;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::Duration;
+
+// Editors often save a file in several steps. After the first change,
+// wait this long for the others before evaluating again.
+const SETTLE_TIME: Duration = Duration::from_millis(50);
+
+// Evaluates `input_file` to JSON. Also returns all files that the
+// evaluation read (or tried to read), i.e. the input and its imports.
+fn evaluate(input_file: &str, opts: &EngineArgs) -> (CliResult<String>, Vec<PathBuf>) {
+    let files = Rc::new(RefCell::new(vec![PathBuf::from(input_file)]));
+    let read = {
+        let files = Rc::clone(&files);
+        move |p: &Path| {
+            files.borrow_mut().push(p.to_path_buf());
+            fs::read_to_string(p).map_err(|e| e.to_string())
+        }
+    };
+    let result = engine_with(
+        opts,
+        FileResolver::with_reader(import_base(input_file), read),
+    )
+    .and_then(|engine| load(&engine, input_file, &read_input(input_file)?))
+    .and_then(|v| to_json_string(&v));
+    let files = files.borrow().clone();
+    (result, files)
+}
+
+// Writes `contents` to `path` by renaming a temporary file over it, so that
+// readers of `path` never see a partially written file.
+fn write_atomically(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+// The absolute path of `file`. Only its directory needs to exist.
+fn absolute(file: &Path) -> Option<PathBuf> {
+    let dir = match file.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    Some(fs::canonicalize(dir).ok()?.join(file.file_name()?))
+}
+
+fn watch_error(e: notify::Error) -> CliError {
+    CliError::Io(format!("Cannot watch files: {}", e))
+}
+
+// Blocks until one of `files` changes.
+fn wait_for_change(
+    rx: &mpsc::Receiver<notify::Result<Event>>,
+    files: &HashSet<PathBuf>,
+) -> CliResult<()> {
+    loop {
+        let event = rx
+            .recv()
+            .map_err(|e| CliError::Io(format!("Cannot watch files: {}", e)))?
+            .map_err(watch_error)?;
+        if !matches!(event.kind, EventKind::Access(_))
+            && event.paths.iter().any(|p| files.contains(p))
+        {
+            break;
+        }
+    }
+    while rx.recv_timeout(SETTLE_TIME).is_ok() {}
+    Ok(())
+}
+
+// Evaluates `input_file` and writes its value to `output` (or stdout) every
+// time it changes. Errors are printed, but do not stop watching.
+pub fn run(input_file: &str, output: Option<&str>, opts: &EngineArgs) -> CliResult<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(watch_error)?;
+    let mut dirs: HashSet<PathBuf> = HashSet::new();
+    let mut last: Option<String> = None;
+    loop {
+        let (result, read) = evaluate(input_file, opts);
+        match result {
+            Ok(json) if last.as_ref() != Some(&json) => {
+                let written = match output {
+                    Some(f) => match write_atomically(Path::new(f), &format!("{}\n", json)) {
+                        Ok(()) => {
+                            eprintln!("Wrote {}", f);
+                            true
+                        }
+                        Err(e) => {
+                            eprintln!("Cannot write {}: {}", f, e);
+                            false
+                        }
+                    },
+                    None => {
+                        println!("{}", json);
+                        true
+                    }
+                };
+                if written {
+                    last = Some(json);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("{}", e.message()),
+        }
+        // Watch directories rather than files: editors often replace a file
+        // instead of writing to it, which would end a watch on the file.
+        let files: HashSet<PathBuf> = read.iter().filter_map(|f| absolute(f)).collect();
+        let wanted: HashSet<PathBuf> = files
+            .iter()
+            .filter_map(|f| f.parent().map(Path::to_path_buf))
+            .collect();
+        for d in dirs.difference(&wanted) {
+            // The directory may be gone already.
+            let _ = watcher.unwatch(d);
+        }
+        for d in wanted.difference(&dirs) {
+            watcher
+                .watch(d, RecursiveMode::NonRecursive)
+                .map_err(watch_error)?;
+        }
+        dirs = wanted;
+        wait_for_change(&rx, &files)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Args, Command};
+    use clap::Parser;
+
+    #[test]
+    fn evaluate_reports_imports() {
+        let dir = std::env::temp_dir().join(format!("konfi-watch-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        let main = dir.join("main.konfi");
+        fs::write(&main, "import \"lib/base.konfi\" as base\nbase.port").unwrap();
+        fs::write(dir.join("lib/base.konfi"), "{\n  port: 80\n}").unwrap();
+        let main = main.display().to_string();
+        let args = Args::parse_from(["konfi", "watch", "--allow-imports", &main]);
+        let Some(Command::Watch { opts, .. }) = args.command else {
+            panic!("Expected watch command");
+        };
+        let (json, files) = evaluate(&main, &opts);
+        assert_eq!(json.unwrap(), "80");
+        assert_eq!(
+            files,
+            vec![PathBuf::from(&main), dir.join("lib/base.konfi")]
+        );
+
+        let out = dir.join("out.json");
+        write_atomically(&out, "80\n").unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "80\n");
+        assert!(!dir.join("out.json.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}